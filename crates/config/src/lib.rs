@@ -1,5 +1,6 @@
 use tracing::info;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
 use std::path::PathBuf;
 use std::sync::LazyLock;
@@ -29,21 +30,211 @@ impl Config {
 
     /// get resources folder path
     pub fn get_resource_path() -> PathBuf {
-        // get root path
-        let mut path = std::env::current_dir().expect("fail to get current dir");
-        loop {
-            let mut p = path.clone();
-            p.push("resources");
-            if p.is_dir() {
-                return p;
-            }
-            if !path.pop() {
-                panic!("fail to get resource path");
+        find_resource_path().expect("fail to get resource path")
+    }
+}
+
+/// The `resources` folder in the current directory or the nearest of its parents, `None` if there is none.
+pub fn find_resource_path() -> Option<PathBuf> {
+    let mut path = std::env::current_dir().ok()?;
+    loop {
+        let mut p = path.clone();
+        p.push("resources");
+        if p.is_dir() {
+            return Some(p);
+        }
+        if !path.pop() {
+            return None;
+        }
+    }
+}
+
+/// Settings of the proxy listeners, loaded from `config/proxy_config_{env}.yaml`.
+///
+/// Every field has a default, so the file and any of its sections may be omitted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    pub handshake: HandshakeConfig,
+    pub udp: UdpConfig,
+    pub tls: TlsConfig,
+    pub http: HttpConfig,
+    pub sni: SniConfig,
+}
+
+impl ProxyConfig {
+    /// load config from resource folder, falls back to the defaults if there is no config file
+    pub fn load() -> Self {
+        let env = env::var("env").unwrap_or("dev".to_string());
+        let Some(mut base_path) = find_resource_path() else {
+            info!("resource folder not found, use default proxy config");
+            return Self::default();
+        };
+        base_path.push(format!("config/proxy_config_{}.yaml", env));
+        match std::fs::read_to_string(&base_path) {
+            Ok(content) => serde_yaml::from_str(&content).expect("parse proxy config file error"),
+            Err(_) => {
+                info!("{:?} not found, use default proxy config", base_path);
+                Self::default()
             }
         }
     }
 }
 
+/// Deadlines from accepting a connection to relaying its first byte, 0 disables a deadline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandshakeConfig {
+    /// greeting, auth sub-negotiation and request of SOCKS4 and SOCKS5 clients
+    pub socks_timeout_secs: u64,
+    /// request head of HTTP clients, including the retry after a 407
+    pub http_timeout_secs: u64,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            socks_timeout_secs: 10,
+            http_timeout_secs: 15,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UdpConfig {
+    /// close an association after this many seconds without any datagram, 0 disables the expiry
+    pub idle_timeout_secs: u64,
+    /// also pin the client source port, not only its ip
+    pub pin_source_port: bool,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 120,
+            pin_source_port: false,
+        }
+    }
+}
+
+/// Forwarding of plain HTTP requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// name of the proxy in the `Via` header
+    pub via_pseudonym: String,
+    /// headers added for users of plans without an entry in `plans`
+    pub forwarded: ForwardedHeaders,
+    /// headers added for the users of a plan, by plan id
+    pub plans: HashMap<u64, ForwardedHeaders>,
+    /// requests with a larger head are answered with 431
+    pub max_head_size: usize,
+    /// requests with more header fields are answered with 431
+    pub max_headers: usize,
+    /// realm of the `Proxy-Authenticate` challenges
+    pub realm: String,
+    /// accept Basic credentials
+    pub basic_auth: bool,
+    /// accept Digest credentials, RFC 7616
+    pub digest_auth: bool,
+    /// a Digest nonce is answered as stale after this many seconds
+    pub digest_nonce_lifetime_secs: u64,
+    /// close the connection after this many 407 in a row
+    pub max_auth_attempts: u32,
+    /// listener ports whose HTTP clients are served by the hyper front end, the others by the built-in one
+    pub hyper_ports: Vec<u16>,
+    /// body of the error responses, with `{code}`, `{status}` and `{message}` replaced, a JSON one if `None`
+    pub error_template: Option<String>,
+    /// hosts the PAC file served at `/proxy.pac` sends direct: names matching their subdomains too, `*` patterns
+    /// or IPv4 networks
+    pub pac_bypass: Vec<String>,
+}
+
+impl HttpConfig {
+    pub fn forwarded_headers(&self, plan_id: u64) -> ForwardedHeaders {
+        self.plans.get(&plan_id).copied().unwrap_or(self.forwarded)
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            via_pseudonym: "rg-proxy".to_string(),
+            forwarded: ForwardedHeaders::default(),
+            plans: HashMap::new(),
+            max_head_size: 64 * 1024,
+            max_headers: 100,
+            realm: "Proxy-Login".to_string(),
+            basic_auth: true,
+            digest_auth: false,
+            digest_nonce_lifetime_secs: 300,
+            max_auth_attempts: 3,
+            hyper_ports: Vec::new(),
+            error_template: None,
+            pac_bypass: vec!["localhost".to_string(), "127.0.0.0/8".to_string()],
+        }
+    }
+}
+
+/// Sniffing the server name of the TLS ClientHello a client opens a CONNECT tunnel with, nothing is decrypted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SniConfig {
+    /// acl check tunnels by the server name too and account them to it
+    pub sniff: bool,
+    /// wait this long for the client to send its ClientHello, what the target sends meanwhile is relayed
+    pub peek_timeout_ms: u64,
+    /// close tunnels whose server name is not the domain asked for, tunnels to an ip are not affected
+    pub block_mismatch: bool,
+}
+
+impl Default for SniConfig {
+    fn default() -> Self {
+        Self {
+            sniff: false,
+            peek_timeout_ms: 500,
+            block_mismatch: false,
+        }
+    }
+}
+
+/// Headers identifying the proxy and the client in forwarded requests, none by default.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForwardedHeaders {
+    pub via: bool,
+    pub x_forwarded_for: bool,
+}
+
+/// TLS termination of inbound connections, ahead of the SOCKS or HTTP handshake.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// listener ports serving TLS, listeners on other ports stay plain
+    pub ports: Vec<u16>,
+    /// PEM certificate chain, leaf first
+    pub cert_file: PathBuf,
+    /// PEM private key, PKCS#8, PKCS#1 or SEC1
+    pub key_file: PathBuf,
+    /// check the files for changes every this many seconds, 0 disables reloading
+    pub reload_interval_secs: u64,
+    /// offer HTTP/2 by ALPN, HTTP/2 clients are served by the hyper front end with a stream per request
+    pub http2: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            ports: Vec::new(),
+            cert_file: PathBuf::from("tls/cert.pem"),
+            key_file: PathBuf::from("tls/key.pem"),
+            reload_interval_secs: 60,
+            http2: false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RedisConfig {
     pub addr: String,
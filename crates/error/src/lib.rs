@@ -50,17 +50,166 @@ pub enum Error {
     #[error("Request body is empty")]
     EmptyRequest,
 
+    /// A request the client should not repeat unchanged, answered with the status.
+    #[error("Invalid request ({0}): {1}")]
+    InvalidRequest(http::StatusCode, String),
+
     #[error("anyhow error {0}")]
     AnyhowError(#[from] anyhow::Error),
 
     #[error("Auth fail: {0}")]
     AuthFailed(String),
 
+    #[error("User disabled: {0}")]
+    UserDisabled(String),
+
     #[error("Forbidden request")]
     ForbiddenRequest,
 
+    #[error("Handshake timeout")]
+    HandshakeTimeout,
+
     #[error("Resolve dns address error {0}")]
     ResolveDnsError(#[from] trust_dns_resolver::error::ResolveError),
+
+    #[error("Deserialize error {0}")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("{0}")]
+    UrlParseError(#[from] url::ParseError),
+
+    #[error("Uri error {0}")]
+    HttpParseError(#[from] http::Error),
+
+    #[error("Send to server error")]
+    WebsocketSendError,
+
+    #[error("Connect server error")]
+    ConnectServerError,
+
+    #[error("{1}")]
+    Upstream(FailureReason, Box<Error>),
+}
+
+/// Why a request could not be served, as reported to the client and counted in the stats.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FailureReason {
+    /// The target domain does not exist.
+    DnsNotFound,
+    /// The target refused the connection.
+    ConnectionRefused,
+    /// Resolving or connecting to the target took too long.
+    Timeout,
+    /// There is no route to the target.
+    NetworkUnreachable,
+    /// The request is not allowed by the access rules.
+    AclDenied,
+    /// The client did not authenticate.
+    AuthFailed,
+    /// The client authenticated as a user that is disabled.
+    UserDisabled,
+    /// Any other failure.
+    Other,
+}
+
+impl FailureReason {
+    pub const ALL: [FailureReason; 8] = [
+        FailureReason::DnsNotFound,
+        FailureReason::ConnectionRefused,
+        FailureReason::Timeout,
+        FailureReason::NetworkUnreachable,
+        FailureReason::AclDenied,
+        FailureReason::AuthFailed,
+        FailureReason::UserDisabled,
+        FailureReason::Other,
+    ];
+
+    /// The status an HTTP proxy answers with.
+    pub fn http_status(&self) -> http::StatusCode {
+        match self {
+            FailureReason::DnsNotFound | FailureReason::ConnectionRefused | FailureReason::Other => {
+                http::StatusCode::BAD_GATEWAY
+            }
+            FailureReason::Timeout => http::StatusCode::GATEWAY_TIMEOUT,
+            FailureReason::NetworkUnreachable => http::StatusCode::SERVICE_UNAVAILABLE,
+            FailureReason::AclDenied | FailureReason::UserDisabled => http::StatusCode::FORBIDDEN,
+            FailureReason::AuthFailed => http::StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+        }
+    }
+
+    /// The stable code of the reason, as counted in the stats and sent to HTTP clients in `X-Proxy-Error`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureReason::DnsNotFound => "dns_not_found",
+            FailureReason::ConnectionRefused => "connection_refused",
+            FailureReason::Timeout => "timeout",
+            FailureReason::NetworkUnreachable => "network_unreachable",
+            FailureReason::AclDenied => "acl_denied",
+            FailureReason::AuthFailed => "auth_failed",
+            FailureReason::UserDisabled => "user_disabled",
+            FailureReason::Other => "other",
+        }
+    }
+
+    /// A sentence for the people reading an error response.
+    pub fn description(&self) -> &'static str {
+        match self {
+            FailureReason::DnsNotFound => "The target domain does not exist.",
+            FailureReason::ConnectionRefused => "The target refused the connection.",
+            FailureReason::Timeout => "The target did not answer in time.",
+            FailureReason::NetworkUnreachable => "There is no route to the target.",
+            FailureReason::AclDenied => "The target is not allowed by the access rules.",
+            FailureReason::AuthFailed => "Proxy authentication is required.",
+            FailureReason::UserDisabled => "The user is disabled.",
+            FailureReason::Other => "The request could not be served.",
+        }
+    }
+}
+
+impl std::fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Error {
+    /// Wraps an error met while resolving or connecting to a target, classifying it.
+    pub fn upstream(e: impl Into<Error>) -> Self {
+        let e = e.into();
+        if let Error::Upstream(..) = e {
+            return e;
+        }
+        let reason = match &e {
+            Error::ResolveDnsError(e) => match e.kind() {
+                trust_dns_resolver::error::ResolveErrorKind::NoRecordsFound { .. } => FailureReason::DnsNotFound,
+                trust_dns_resolver::error::ResolveErrorKind::Timeout => FailureReason::Timeout,
+                _ => FailureReason::Other,
+            },
+            Error::Io(e) => match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => FailureReason::ConnectionRefused,
+                std::io::ErrorKind::TimedOut => FailureReason::Timeout,
+                std::io::ErrorKind::NetworkUnreachable | std::io::ErrorKind::HostUnreachable => {
+                    FailureReason::NetworkUnreachable
+                }
+                _ => FailureReason::Other,
+            },
+            Error::HandshakeTimeout => FailureReason::Timeout,
+            _ => FailureReason::Other,
+        };
+        Error::Upstream(reason, Box::new(e))
+    }
+
+    /// The reason to report to the client, `None` for errors that are not a failure to serve the request,
+    /// such as a malformed request or a client going away.
+    pub fn failure_reason(&self) -> Option<FailureReason> {
+        match self {
+            Error::Upstream(reason, _) => Some(*reason),
+            Error::AuthFailed(_) => Some(FailureReason::AuthFailed),
+            Error::UserDisabled(_) => Some(FailureReason::UserDisabled),
+            Error::ForbiddenRequest => Some(FailureReason::AclDenied),
+            _ => None,
+        }
+    }
 }
 
 impl From<&str> for Error {
@@ -85,7 +234,7 @@ impl From<Error> for std::io::Error {
     fn from(e: Error) -> Self {
         match e {
             Error::Io(e) => e,
-            _ => std::io::Error::new(std::io::ErrorKind::Other, e),
+            _ => std::io::Error::other(e),
         }
     }
 }
@@ -98,3 +247,39 @@ impl From<tokio::time::error::Elapsed> for Error {
 
 /// The library's `Result` type alias.
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_upstream_reason() {
+        let reason = |e: Error| Error::upstream(e).failure_reason();
+        let io = |kind: ErrorKind| Error::from(std::io::Error::from(kind));
+        assert_eq!(reason(io(ErrorKind::ConnectionRefused)), Some(FailureReason::ConnectionRefused));
+        assert_eq!(reason(io(ErrorKind::TimedOut)), Some(FailureReason::Timeout));
+        assert_eq!(reason(io(ErrorKind::NetworkUnreachable)), Some(FailureReason::NetworkUnreachable));
+        assert_eq!(reason(io(ErrorKind::HostUnreachable)), Some(FailureReason::NetworkUnreachable));
+        assert_eq!(reason(Error::HandshakeTimeout), Some(FailureReason::Timeout));
+        assert_eq!(reason("other".into()), Some(FailureReason::Other));
+        // wrapping twice keeps the first classification
+        let e = Error::upstream(io(ErrorKind::ConnectionRefused));
+        assert_eq!(reason(e), Some(FailureReason::ConnectionRefused));
+
+        assert_eq!(Error::ForbiddenRequest.failure_reason(), Some(FailureReason::AclDenied));
+        assert_eq!(Error::AuthFailed("x".into()).failure_reason(), Some(FailureReason::AuthFailed));
+        assert_eq!(Error::UserDisabled("x".into()).failure_reason(), Some(FailureReason::UserDisabled));
+        assert_eq!(Error::EmptyRequest.failure_reason(), None);
+    }
+
+    #[test]
+    fn test_http_status() {
+        assert_eq!(FailureReason::DnsNotFound.http_status(), 502);
+        assert_eq!(FailureReason::Timeout.http_status(), 504);
+        assert_eq!(FailureReason::NetworkUnreachable.http_status(), 503);
+        assert_eq!(FailureReason::AclDenied.http_status(), 403);
+        assert_eq!(FailureReason::AuthFailed.http_status(), 407);
+        assert_eq!(FailureReason::UserDisabled.http_status(), 403);
+    }
+}
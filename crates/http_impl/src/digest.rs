@@ -0,0 +1,315 @@
+//! Digest access authentication of proxy clients, RFC 7616.
+//!
+//! Nonces are not stored: a nonce carries the time it was issued with a MAC of it under the key of the proxy, so
+//! that any nonce the proxy issued is recognized until it expires.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+const NONCE_MAC_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_uppercase().as_str() {
+            "MD5" => Self::Md5,
+            "MD5-SESS" => Self::Md5Sess,
+            "SHA-256" => Self::Sha256,
+            "SHA-256-SESS" => Self::Sha256Sess,
+            _ => return None,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Md5 => "MD5",
+            Self::Md5Sess => "MD5-sess",
+            Self::Sha256 => "SHA-256",
+            Self::Sha256Sess => "SHA-256-sess",
+        }
+    }
+
+    fn hash(&self, data: &str) -> String {
+        match self {
+            Self::Md5 | Self::Md5Sess => hex(&Md5::digest(data.as_bytes())),
+            Self::Sha256 | Self::Sha256Sess => hex(&Sha256::digest(data.as_bytes())),
+        }
+    }
+
+    fn is_session(&self) -> bool {
+        matches!(self, Self::Md5Sess | Self::Sha256Sess)
+    }
+}
+
+/// The parameters of a `Proxy-Authorization: Digest` header with the method and request-target of its request.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub realm: String,
+    pub nonce: String,
+    pub uri: String,
+    pub response: String,
+    pub algorithm: Algorithm,
+    pub qop: Option<String>,
+    pub nc: Option<String>,
+    pub cnonce: Option<String>,
+    pub method: String,
+    pub target: String,
+}
+
+impl Credentials {
+    /// Parses the parameters following the `Digest` scheme, `None` if a required one is missing.
+    pub fn parse(method: &str, target: &str, params: &str) -> Option<Self> {
+        let params = parse_params(params)?;
+        let param = |name: &str| params.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone());
+        let algorithm = match param("algorithm") {
+            Some(name) => Algorithm::from_name(&name)?,
+            None => Algorithm::Md5,
+        };
+        Some(Self {
+            username: param("username")?,
+            realm: param("realm")?,
+            nonce: param("nonce")?,
+            uri: param("uri")?,
+            response: param("response")?,
+            algorithm,
+            qop: param("qop"),
+            nc: param("nc"),
+            cnonce: param("cnonce"),
+            method: method.to_string(),
+            target: target.to_string(),
+        })
+    }
+}
+
+/// Outcome of checking [`Credentials`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Valid,
+    /// the response matches the password but the nonce expired, the client retries without asking its user
+    Stale,
+    Invalid,
+}
+
+#[derive(Clone)]
+pub struct DigestAuth {
+    realm: String,
+    key: [u8; 32],
+    nonce_lifetime: Duration,
+}
+
+impl DigestAuth {
+    pub fn new(realm: impl Into<String>, key: [u8; 32], nonce_lifetime: Duration) -> Self {
+        Self {
+            realm: realm.into(),
+            key,
+            nonce_lifetime,
+        }
+    }
+
+    /// The `Proxy-Authenticate` values of a 407, SHA-256 first as the preferred algorithm.
+    pub fn challenges(&self, stale: bool) -> Vec<String> {
+        let nonce = self.nonce(unix_time());
+        [Algorithm::Sha256, Algorithm::Md5]
+            .iter()
+            .map(|algorithm| {
+                let mut challenge = format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"",
+                    self.realm,
+                    algorithm.name(),
+                    nonce
+                );
+                if stale {
+                    challenge.push_str(", stale=true");
+                }
+                challenge
+            })
+            .collect()
+    }
+
+    /// Checks `credentials` against the password of their user.
+    pub fn verify(&self, credentials: &Credentials, password: &str) -> Verdict {
+        self.verify_at(credentials, password, unix_time())
+    }
+
+    fn verify_at(&self, credentials: &Credentials, password: &str, now: u64) -> Verdict {
+        let Some(issued) = self.nonce_time(&credentials.nonce) else {
+            return Verdict::Invalid;
+        };
+        // the client has to ask with qop=auth, which is the only one offered
+        let (Some(qop), Some(nc), Some(cnonce)) = (&credentials.qop, &credentials.nc, &credentials.cnonce) else {
+            return Verdict::Invalid;
+        };
+        if credentials.realm != self.realm || credentials.uri != credentials.target || !qop.eq_ignore_ascii_case("auth") {
+            return Verdict::Invalid;
+        }
+
+        let algorithm = credentials.algorithm;
+        let mut ha1 = algorithm.hash(&format!("{}:{}:{}", credentials.username, self.realm, password));
+        if algorithm.is_session() {
+            ha1 = algorithm.hash(&format!("{}:{}:{}", ha1, credentials.nonce, cnonce));
+        }
+        let ha2 = algorithm.hash(&format!("{}:{}", credentials.method, credentials.uri));
+        let expected = algorithm.hash(&format!("{}:{}:{}:{}:{}:{}", ha1, credentials.nonce, nc, cnonce, qop, ha2));
+        if !expected.eq_ignore_ascii_case(&credentials.response) {
+            return Verdict::Invalid;
+        }
+        if now.saturating_sub(issued) > self.nonce_lifetime.as_secs() {
+            return Verdict::Stale;
+        }
+        Verdict::Valid
+    }
+
+    fn nonce(&self, timestamp: u64) -> String {
+        format!("{:016x}{}", timestamp, hex(&self.nonce_mac(timestamp)))
+    }
+
+    /// The time `nonce` was issued at, `None` if the proxy did not issue it.
+    fn nonce_time(&self, nonce: &str) -> Option<u64> {
+        if nonce.len() != 16 + 2 * NONCE_MAC_LEN || !nonce.is_ascii() {
+            return None;
+        }
+        let timestamp = u64::from_str_radix(&nonce[..16], 16).ok()?;
+        let mac = (0..NONCE_MAC_LEN)
+            .map(|i| u8::from_str_radix(&nonce[16 + 2 * i..18 + 2 * i], 16))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .ok()?;
+        (mac == self.nonce_mac(timestamp)).then_some(timestamp)
+    }
+
+    fn nonce_mac(&self, timestamp: u64) -> [u8; NONCE_MAC_LEN] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(&timestamp.to_be_bytes());
+        let mut out = [0; NONCE_MAC_LEN];
+        out.copy_from_slice(&mac.finalize().into_bytes()[..NONCE_MAC_LEN]);
+        out
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Splits `name=value, name="quoted value"` pairs, `None` if they are malformed.
+fn parse_params(s: &str) -> Option<Vec<(String, String)>> {
+    let mut params = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('=')?;
+        let name = name.trim().to_string();
+        let after = after.trim_start();
+        let value;
+        if let Some(quoted) = after.strip_prefix('"') {
+            let mut v = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => v.push(chars.next()?.1),
+                    '"' => {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    c => v.push(c),
+                }
+            }
+            value = v;
+            rest = &quoted[end?..];
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            value = after[..end].trim().to_string();
+            rest = &after[end..];
+        }
+        params.push((name, value));
+        rest = rest.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn auth() -> DigestAuth {
+        DigestAuth::new("Proxy-Login", [7; 32], Duration::from_secs(300))
+    }
+
+    fn credentials(auth: &DigestAuth, algorithm: Algorithm, nonce: &str, password: &str) -> Credentials {
+        let uri = "a.com:443";
+        let (nc, cnonce) = ("00000001", "0a4f113b");
+        let mut ha1 = algorithm.hash(&format!("u:{}:{}", auth.realm, password));
+        if algorithm.is_session() {
+            ha1 = algorithm.hash(&format!("{}:{}:{}", ha1, nonce, cnonce));
+        }
+        let ha2 = algorithm.hash(&format!("CONNECT:{}", uri));
+        let response = algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+        let header = format!(
+            "username=\"u\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\", qop=auth, nc={}, cnonce=\"{}\"",
+            auth.realm,
+            nonce,
+            uri,
+            algorithm.name(),
+            response,
+            nc,
+            cnonce
+        );
+        Credentials::parse("CONNECT", uri, &header).unwrap()
+    }
+
+    #[test]
+    fn test_md5_known_answer() {
+        // H(A1) of the example in RFC 2617 section 3.5
+        let algorithm = Algorithm::Md5;
+        assert_eq!(algorithm.hash("Mufasa:testrealm@host.com:Circle Of Life"), "939e7578ed9e3c518a452acee763bce9");
+    }
+
+    #[test]
+    fn test_verify() {
+        let auth = auth();
+        let now = 1_700_000_000;
+        let nonce = auth.nonce(now);
+        for algorithm in [Algorithm::Md5, Algorithm::Md5Sess, Algorithm::Sha256, Algorithm::Sha256Sess] {
+            let c = credentials(&auth, algorithm, &nonce, "p");
+            assert_eq!(auth.verify_at(&c, "p", now + 10), Verdict::Valid);
+            assert_eq!(auth.verify_at(&c, "q", now + 10), Verdict::Invalid);
+            assert_eq!(auth.verify_at(&c, "p", now + 301), Verdict::Stale);
+            // credentials replayed for another target
+            let replayed = Credentials { target: "b.com:443".to_string(), ..c };
+            assert_eq!(auth.verify_at(&replayed, "p", now + 10), Verdict::Invalid);
+        }
+        // a nonce the proxy did not issue
+        let forged = format!("{:016x}{}", now, "0".repeat(32));
+        let c = credentials(&auth, Algorithm::Sha256, &forged, "p");
+        assert_eq!(auth.verify_at(&c, "p", now), Verdict::Invalid);
+    }
+
+    #[test]
+    fn test_challenges_and_params() {
+        let challenges = auth().challenges(true);
+        assert!(challenges[0].starts_with("Digest realm=\"Proxy-Login\", qop=\"auth\", algorithm=SHA-256, nonce=\""));
+        assert!(challenges[1].contains("algorithm=MD5") && challenges[1].ends_with(", stale=true"));
+
+        let params = parse_params("a=1, b=\"x, \\\"y\\\"\",c = \"\" ").unwrap();
+        assert_eq!(params, vec![
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "x, \"y\"".to_string()),
+            ("c".to_string(), String::new()),
+        ]);
+        assert!(parse_params("a=\"unterminated").is_none());
+        assert!(Credentials::parse("GET", "/", "username=\"u\", realm=\"r\"").is_none());
+    }
+}
@@ -0,0 +1,108 @@
+//! Error responses of the proxy itself, telling clients' automation why a request was not served.
+//!
+//! Every error response carries the reason code in `X-Proxy-Error` and a JSON body rendered from a template, in
+//! which `{code}`, `{status}` and `{message}` are replaced.
+use std::sync::Arc;
+
+use ::http::StatusCode;
+use error::FailureReason;
+
+pub const ERROR_HEADER: &str = "X-Proxy-Error";
+
+/// The code of a request the proxy could not make sense of.
+pub const INVALID_REQUEST: &str = "invalid_request";
+
+pub const DEFAULT_TEMPLATE: &str = r#"{"error":"{code}","status":{status},"message":"{message}"}"#;
+
+#[derive(Clone, Debug)]
+pub struct ErrorPage {
+    template: Arc<str>,
+}
+
+impl Default for ErrorPage {
+    fn default() -> Self {
+        Self::new(DEFAULT_TEMPLATE)
+    }
+}
+
+impl ErrorPage {
+    pub fn new(template: &str) -> Self {
+        Self {
+            template: Arc::from(template),
+        }
+    }
+
+    /// The body of an error response, the values are escaped for a JSON string.
+    pub fn body(&self, code: &str, status: StatusCode, message: &str) -> String {
+        self.template
+            .replace("{code}", &json_escape(code))
+            .replace("{status}", status.as_str())
+            .replace("{message}", &json_escape(message))
+    }
+
+    /// The body of the response to a request failed for `reason`.
+    pub fn failure_body(&self, reason: FailureReason) -> String {
+        self.body(reason.as_str(), reason.http_status(), reason.description())
+    }
+
+    /// A whole HTTP/1.1 error response, `headers` being further header lines ending with CRLF.
+    pub fn response(&self, code: &str, status: StatusCode, message: &str, headers: &str) -> String {
+        let body = self.body(code, status, message);
+        format!(
+            "HTTP/1.1 {} {}\r\n{}: {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status.as_str(),
+            status.canonical_reason().unwrap_or_default(),
+            ERROR_HEADER,
+            code,
+            headers,
+            body.len(),
+            body
+        )
+    }
+}
+
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_response() {
+        let page = ErrorPage::default();
+        assert_eq!(
+            page.failure_body(FailureReason::AclDenied),
+            r#"{"error":"acl_denied","status":403,"message":"The target is not allowed by the access rules."}"#
+        );
+        let body = r#"{"error":"invalid_request","status":400,"message":"bad \"x\"\n"}"#;
+        assert_eq!(
+            page.response(INVALID_REQUEST, StatusCode::BAD_REQUEST, "bad \"x\"\n", "Connection: close\r\n"),
+            format!(
+                "HTTP/1.1 400 Bad Request\r\nX-Proxy-Error: invalid_request\r\nConnection: close\r\n\
+                 Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        );
+
+        let page = ErrorPage::new(r#"{"code":"{code}","docs":"https://example.com/errors#{code}"}"#);
+        assert_eq!(
+            page.failure_body(FailureReason::Timeout),
+            r#"{"code":"timeout","docs":"https://example.com/errors#timeout"}"#
+        );
+    }
+}
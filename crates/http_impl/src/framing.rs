@@ -0,0 +1,445 @@
+//! HTTP/1.1 message framing, RFC 7230 section 3.3.
+//!
+//! Bodies are copied as they are, chunked bodies included, the framing is only followed to find where a
+//! message ends so that the next one can be read from the same connection.
+use std::collections::HashMap;
+
+use bytes::{BufMut, BytesMut};
+use error::{Error, Result};
+use http::StatusCode;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest message head accepted by default.
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Largest chunk size or trailer line accepted in a chunked body.
+const MAX_LINE_SIZE: u64 = 8 * 1024;
+const MAX_RESPONSE_HEADERS: usize = 128;
+
+/// How the end of a message body is found.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BodyLength {
+    /// The message has no body.
+    Empty,
+    /// The body is `Content-Length` bytes long.
+    Fixed(u64),
+    /// The body uses the chunked transfer coding.
+    Chunked,
+    /// The body ends when the connection is closed, only possible for responses.
+    UntilClose,
+}
+
+impl BodyLength {
+    /// The body length given by every `Transfer-Encoding` and `Content-Length` header line of a message.
+    ///
+    /// A request without either has no body, a response reads until the connection closes. A request whose
+    /// framing is ambiguous, with differing lengths or a transfer coding list not ending in a single `chunked`,
+    /// fails with a 400 (RFC 7230 section 3.3.3).
+    pub fn from_headers(transfer_encoding: &[&str], content_length: &[&str], is_request: bool) -> Result<Self> {
+        if !transfer_encoding.is_empty() {
+            let codings = transfer_encoding
+                .iter()
+                .flat_map(|te| te.split(','))
+                .map(str::trim)
+                .filter(|coding| !coding.is_empty())
+                .collect::<Vec<_>>();
+            let is_chunked = |coding: &str| coding.eq_ignore_ascii_case("chunked");
+            let chunked = codings.last().is_some_and(|coding| is_chunked(coding));
+            return match (chunked, is_request) {
+                (true, true) if codings.iter().filter(|coding| is_chunked(coding)).count() > 1 => Err(invalid_framing(
+                    format!("request transfer coding is chunked more than once: {}", transfer_encoding.join(", ")),
+                    true,
+                )),
+                (true, _) => Ok(BodyLength::Chunked),
+                (false, true) => Err(invalid_framing(
+                    format!("request transfer coding is not chunked: {}", transfer_encoding.join(", ")),
+                    true,
+                )),
+                (false, false) => Ok(BodyLength::UntilClose),
+            };
+        }
+        let mut len = None;
+        for value in content_length {
+            let value_len = parse_content_length(value)
+                .ok_or_else(|| invalid_framing(format!("invalid content-length: {}", value), is_request))?;
+            if len.is_some_and(|len| len != value_len) {
+                return Err(invalid_framing(
+                    format!("differing content-length values: {}", content_length.join(", ")),
+                    is_request,
+                ));
+            }
+            len = Some(value_len);
+        }
+        match len {
+            Some(0) => Ok(BodyLength::Empty),
+            Some(len) => Ok(BodyLength::Fixed(len)),
+            None if is_request => Ok(BodyLength::Empty),
+            None => Ok(BodyLength::UntilClose),
+        }
+    }
+}
+
+/// A `Content-Length` value, `None` unless it is ASCII digits only.
+fn parse_content_length(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// A message whose framing headers cannot be followed, a request is answered with 400.
+fn invalid_framing(message: String, is_request: bool) -> Error {
+    if is_request {
+        Error::InvalidRequest(StatusCode::BAD_REQUEST, message)
+    } else {
+        Error::from(message)
+    }
+}
+
+/// Whether the connection stays open after a message with HTTP version `1.minor_version` and the given
+/// `Connection` header.
+pub fn keep_alive(minor_version: u8, connection: Option<&str>) -> bool {
+    let has = |option: &str| {
+        connection.is_some_and(|c| c.split(',').any(|o| o.trim().eq_ignore_ascii_case(option)))
+    };
+    if minor_version == 0 {
+        has("keep-alive")
+    } else {
+        !has("close")
+    }
+}
+
+/// The status line and headers of a response.
+#[derive(Debug)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub minor_version: u8,
+    /// the values of every line of a header by upper case name
+    headers: HashMap<String, Vec<String>>,
+}
+
+impl ResponseHead {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+        let mut res = httparse::Response::new(&mut headers);
+        match res.parse(buf) {
+            Ok(httparse::Status::Complete(_)) => {}
+            Ok(httparse::Status::Partial) => return Err(Error::from("incomplete response head")),
+            Err(e) => return Err(Error::from(format!("parse response error {:?}", e))),
+        }
+        let status = res.code.ok_or_else(|| Error::from("response without status"))?;
+        let minor_version = res.version.unwrap_or(1);
+        let mut headers = HashMap::<_, Vec<_>>::new();
+        for h in res.headers.iter() {
+            headers
+                .entry(h.name.to_uppercase())
+                .or_default()
+                .push(String::from_utf8_lossy(h.value).into_owned());
+        }
+        Ok(Self {
+            status,
+            minor_version,
+            headers,
+        })
+    }
+
+    /// The value of the first line of a header.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_values(name).first().copied()
+    }
+
+    /// The values of every line of a header.
+    pub fn header_values(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .get(&name.to_uppercase())
+            .map(|values| values.iter().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    /// Informational responses precede the final response to a request.
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.status)
+    }
+
+    /// The body length of the response to a request, `head_request` if that request was a HEAD.
+    pub fn body_length(&self, head_request: bool) -> Result<BodyLength> {
+        if head_request || self.is_informational() || self.status == 204 || self.status == 304 {
+            return Ok(BodyLength::Empty);
+        }
+        BodyLength::from_headers(&self.header_values("Transfer-Encoding"), &self.header_values("Content-Length"), false)
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(self.minor_version, self.header("Connection"))
+    }
+}
+
+/// Bounds of a message head.
+#[derive(Clone, Copy, Debug)]
+pub struct HeadLimits {
+    /// bytes up to and including the empty line
+    pub max_size: usize,
+    /// header fields
+    pub max_headers: usize,
+}
+
+impl Default for HeadLimits {
+    fn default() -> Self {
+        Self {
+            max_size: MAX_HEAD_SIZE,
+            max_headers: 100,
+        }
+    }
+}
+
+/// Reads a request head, leaving what follows it in `reader`.
+///
+/// A head that does not parse fails with [`Error::InvalidRequest`], with status 400 if it is malformed and 431
+/// if it exceeds `limits`. Returns `None` if the connection is closed before the head starts.
+pub async fn read_request_head<R>(reader: &mut R, limits: &HeadLimits) -> Result<Option<BytesMut>>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
+    read_head(reader, limits, parse_request)
+        .await
+        .map_err(|e| match e {
+            HeadError::TooLarge => Error::InvalidRequest(
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                format!("request head larger than {} bytes", limits.max_size),
+            ),
+            HeadError::Parse(httparse::Error::TooManyHeaders) => Error::InvalidRequest(
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                format!("more than {} header fields", limits.max_headers),
+            ),
+            HeadError::Parse(e) => Error::InvalidRequest(StatusCode::BAD_REQUEST, format!("malformed request head: {}", e)),
+            HeadError::Other(e) => e,
+        })
+}
+
+/// Reads a response head, leaving what follows it in `reader`.
+///
+/// Returns `None` if the connection is closed before the head starts.
+pub async fn read_response_head<R>(reader: &mut R) -> Result<Option<BytesMut>>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
+    let limits = HeadLimits {
+        max_size: MAX_HEAD_SIZE,
+        max_headers: MAX_RESPONSE_HEADERS,
+    };
+    read_head(reader, &limits, parse_response)
+        .await
+        .map_err(|e| match e {
+            HeadError::TooLarge => Error::from("response head too large"),
+            HeadError::Parse(e) => Error::from(format!("parse response error {:?}", e)),
+            HeadError::Other(e) => e,
+        })
+}
+
+fn parse_request<'b>(buf: &'b [u8], headers: &mut [httparse::Header<'b>]) -> httparse::Result<usize> {
+    httparse::Request::new(headers).parse(buf)
+}
+
+fn parse_response<'b>(buf: &'b [u8], headers: &mut [httparse::Header<'b>]) -> httparse::Result<usize> {
+    httparse::Response::new(headers).parse(buf)
+}
+
+enum HeadError {
+    TooLarge,
+    Parse(httparse::Error),
+    Other(Error),
+}
+
+impl<E: Into<Error>> From<E> for HeadError {
+    fn from(e: E) -> Self {
+        HeadError::Other(e.into())
+    }
+}
+
+/// Reads until `parse` finds a complete head, however the head is split across reads.
+async fn read_head<R, P>(reader: &mut R, limits: &HeadLimits, parse: P) -> std::result::Result<Option<BytesMut>, HeadError>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+    P: for<'b> Fn(&'b [u8], &mut [httparse::Header<'b>]) -> httparse::Result<usize>,
+{
+    let mut head = BytesMut::new();
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(Error::from("connection closed in the middle of a message head").into());
+        }
+        let read = buf.len();
+        head.put_slice(buf);
+        let mut headers = vec![httparse::EMPTY_HEADER; limits.max_headers];
+        match parse(&head, &mut headers).map_err(HeadError::Parse)? {
+            httparse::Status::Complete(end) if end <= limits.max_size => {
+                reader.consume(read - (head.len() - end));
+                head.truncate(end);
+                return Ok(Some(head));
+            }
+            httparse::Status::Complete(_) => return Err(HeadError::TooLarge),
+            httparse::Status::Partial if head.len() > limits.max_size => return Err(HeadError::TooLarge),
+            httparse::Status::Partial => reader.consume(read),
+        }
+    }
+}
+
+/// Copies a body framed as `length` from `reader` to `writer`, returning the number of bytes copied.
+pub async fn copy_body<R, W>(reader: &mut R, writer: &mut W, length: BodyLength) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    match length {
+        BodyLength::Empty => Ok(0),
+        BodyLength::Fixed(len) => copy_exact(reader, writer, len).await,
+        BodyLength::UntilClose => Ok(tokio::io::copy_buf(reader, writer).await?),
+        BodyLength::Chunked => {
+            let mut copied = 0;
+            loop {
+                let line = read_line(reader).await?;
+                writer.write_all(&line).await?;
+                copied += line.len() as u64;
+                let size = std::str::from_utf8(&line)?;
+                let size = size.split(';').next().unwrap_or_default().trim();
+                let size = u64::from_str_radix(size, 16)
+                    .map_err(|e| Error::from(format!("invalid chunk size {:?}: {}", size, e)))?;
+                if size == 0 {
+                    break;
+                }
+                // the chunk data and its CRLF
+                copied += copy_exact(reader, writer, size + 2).await?;
+            }
+            // trailer fields up to the empty line
+            loop {
+                let line = read_line(reader).await?;
+                writer.write_all(&line).await?;
+                copied += line.len() as u64;
+                if line == b"\r\n" || line == b"\n" {
+                    return Ok(copied);
+                }
+            }
+        }
+    }
+}
+
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, len: u64) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let copied = tokio::io::copy_buf(&mut reader.take(len), writer).await?;
+    if copied < len {
+        return Err(Error::from("connection closed in the middle of a message body"));
+    }
+    Ok(copied)
+}
+
+async fn read_line<R>(reader: &mut R) -> Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_LINE_SIZE).read_until(b'\n', &mut line).await?;
+    if !line.ends_with(b"\n") {
+        return Err(Error::from("invalid line in chunked body"));
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_body_length() {
+        let request = |te: &[&str], cl: &[&str]| BodyLength::from_headers(te, cl, true);
+        let response = |te: &[&str], cl: &[&str]| BodyLength::from_headers(te, cl, false);
+        assert_eq!(request(&[], &[]).unwrap(), BodyLength::Empty);
+        assert_eq!(request(&[], &["12"]).unwrap(), BodyLength::Fixed(12));
+        assert_eq!(request(&["gzip, chunked"], &["12"]).unwrap(), BodyLength::Chunked);
+        assert_eq!(request(&["gzip", "chunked"], &[]).unwrap(), BodyLength::Chunked);
+        let status = |res: Result<BodyLength>| match res {
+            Err(Error::InvalidRequest(status, _)) => status.as_u16(),
+            res => panic!("not an invalid request: {:?}", res),
+        };
+        for len in ["x", "+5", "-5", "5, 5", "", "0x5", "99999999999999999999"] {
+            assert_eq!(status(request(&[], &[len])), 400, "{:?}", len);
+        }
+        assert_eq!(request(&[], &[" 007 "]).unwrap(), BodyLength::Fixed(7));
+        assert!(response(&[], &["+5"]).is_err());
+        assert_eq!(response(&[], &[]).unwrap(), BodyLength::UntilClose);
+        assert_eq!(response(&["gzip"], &[]).unwrap(), BodyLength::UntilClose);
+
+        // duplicated lengths must agree, a transfer coding must end in a single chunked
+        assert_eq!(request(&[], &["5", "5"]).unwrap(), BodyLength::Fixed(5));
+        assert_eq!(status(request(&[], &["5", "6"])), 400);
+        assert!(response(&[], &["5", "6"]).is_err());
+        assert_eq!(status(request(&["gzip"], &[])), 400);
+        assert_eq!(status(request(&["chunked", "gzip"], &["5"])), 400);
+        assert_eq!(status(request(&["chunked", "chunked"], &[])), 400);
+        assert_eq!(status(request(&["identity", "identity"], &["5", "5"])), 400);
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        assert!(keep_alive(1, None));
+        assert!(!keep_alive(1, Some("Upgrade, close")));
+        assert!(!keep_alive(0, None));
+        assert!(keep_alive(0, Some("Keep-Alive")));
+    }
+
+    #[test]
+    fn test_response_body_length() {
+        let head = ResponseHead::parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n").unwrap();
+        assert_eq!(head.body_length(false).unwrap(), BodyLength::Fixed(5));
+        assert_eq!(head.body_length(true).unwrap(), BodyLength::Empty);
+        let head = ResponseHead::parse(b"HTTP/1.0 304 Not Modified\r\n\r\n").unwrap();
+        assert_eq!(head.body_length(false).unwrap(), BodyLength::Empty);
+        assert!(!head.keep_alive());
+    }
+
+    #[tokio::test]
+    async fn test_read_messages() {
+        let stream = b"GET / HTTP/1.1\r\n\r\nPOST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;x=1\r\nhello\r\n0\r\nA: b\r\n\r\nrest";
+        // a tiny buffer splits the empty line across reads
+        let limits = HeadLimits::default();
+        let mut reader = tokio::io::BufReader::with_capacity(3, &stream[..]);
+        let head = read_request_head(&mut reader, &limits).await.unwrap().unwrap();
+        assert_eq!(head, &b"GET / HTTP/1.1\r\n\r\n"[..]);
+        let head = read_request_head(&mut reader, &limits).await.unwrap().unwrap();
+        assert!(head.ends_with(b"chunked\r\n\r\n"));
+        let mut body = Vec::new();
+        let copied = copy_body(&mut reader, &mut body, BodyLength::Chunked).await.unwrap();
+        assert_eq!(body, b"5;x=1\r\nhello\r\n0\r\nA: b\r\n\r\n");
+        assert_eq!(copied, body.len() as u64);
+        assert!(read_request_head(&mut reader, &limits).await.is_err());
+
+        let mut reader = tokio::io::BufReader::new(&b"abc"[..]);
+        assert!(copy_body(&mut reader, &mut Vec::new(), BodyLength::Fixed(4)).await.is_err());
+        assert!(read_request_head(&mut reader, &limits).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_request_head_limits() {
+        let status = |res: Result<Option<BytesMut>>| match res {
+            Err(Error::InvalidRequest(status, _)) => status.as_u16(),
+            res => panic!("unexpected {:?}", res),
+        };
+        let limits = HeadLimits {
+            max_size: 64,
+            max_headers: 2,
+        };
+        let mut reader = &b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"[..];
+        assert_eq!(status(read_request_head(&mut reader, &limits).await), 431);
+        let mut reader = &b"GET / HTTP/1.1\r\nA: 0123456789012345678901234567890123456789012345678901234567890123456789"[..];
+        assert_eq!(status(read_request_head(&mut reader, &limits).await), 431);
+        let mut reader = &b"GET / HTTP/1.1\r\nA 1\r\n\r\n"[..];
+        assert_eq!(status(read_request_head(&mut reader, &limits).await), 400);
+        let mut reader = &b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\n\r\n"[..];
+        assert!(read_request_head(&mut reader, &limits).await.unwrap().is_some());
+    }
+}
@@ -2,20 +2,21 @@ use base64::engine::general_purpose::STANDARD as BASE64;
 use http::Uri;
 use std::{collections::HashMap, str::FromStr};
 
-use anyhow::{anyhow, Context};
+use anyhow::Context;
 use base64::Engine;
 use bytes::Bytes;
 use tracing::error;
-use error::{Error, Result};
-use tokio::net::TcpStream;
+use error::{Error, FailureReason, Result};
 use httparse;
 use tokio::io::AsyncWriteExt;
-use crate::{Protocol, RequestType};
+use crate::digest::Credentials;
+use crate::error_page::ErrorPage;
+use crate::framing::{self, BodyLength};
+use crate::local::LocalRequest;
+use crate::{ClientStream, Protocol, RequestType};
 
 const HTTP_AUTH_HEADER: &str = "PROXY-AUTHORIZATION";
-const SUCCESS: &[u8] = b"HTTP/1.1 200 OK\r\n\r\n";
-const UNAUTHORIZED: &[u8] = b"HTTP/1.1 401 Unauthorized\r\nUnauthorized\r\n\r\n";
-const AUTHENTICATION_REQUIRED: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"Proxy-Login\"\r\n\r\n";
+const SUCCESS: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
 
 #[derive(Debug)]
 pub struct HttpRequest {
@@ -27,13 +28,18 @@ struct BaseRequestInfo {
     pub host: Uri,
     pub method: RequestType,
     pub auth: Option<(String, String)>,
+    pub digest: Option<Credentials>,
+    pub head_request: bool,
+    pub body_length: BodyLength,
+    pub keep_alive: bool,
+    pub local: Option<LocalRequest>,
 }
 
 
 
 impl HttpRequest {
-    pub fn new(buf: Bytes) -> Result<Self> {
-        let mut headers = [httparse::EMPTY_HEADER; 32];
+    pub fn new(buf: Bytes, max_headers: usize) -> Result<Self> {
+        let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
         let mut req = httparse::Request::new(&mut headers);
         req.parse(&buf)
             .map_err(|e| Error::from( format!("parse content error {:?}", e)))?;
@@ -42,23 +48,44 @@ impl HttpRequest {
             .context("do not find method")?
             .to_string()
             .to_uppercase();
-        let path = req.path.context("do not find path").map_err(|e| Error::from(e))?;
+        let path = req.path.context("do not find path").map_err(Error::from)?;
+        let version = req.version.unwrap_or(1);
         let uri = Uri::from_str(path)?;
-        let mut header_map = HashMap::new();
+        // every line of a repeated header is kept, the framing depends on all of them
+        let mut header_map = HashMap::<_, Vec<_>>::new();
 
         for header in headers.into_iter() {
             if header.name.is_empty() {
                 continue;
             }
-            header_map.insert(
-                header.name.to_string().to_uppercase(),
-                String::from_utf8(header.value.to_vec())?,
-            );
+            header_map
+                .entry(header.name.to_string().to_uppercase())
+                .or_default()
+                .push(String::from_utf8(header.value.to_vec())?);
         }
+        let header_values = |name: &str| {
+            header_map
+                .get(name)
+                .map(|values| values.iter().map(String::as_str).collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+        let header = |name: &str| header_values(name).first().copied();
+        let (auth, digest) = match header(HTTP_AUTH_HEADER) {
+            Some(value) => parse_auth_header(value, &method, path),
+            None => (None, None),
+        };
+        let body_length = BodyLength::from_headers(&header_values("TRANSFER-ENCODING"), &header_values("CONTENT-LENGTH"), true)?;
+        let keep_alive = framing::keep_alive(version, header("CONNECTION"));
+        let local = LocalRequest::recognize(&method, &uri);
         let base = BaseRequestInfo {
             method: RequestType::from_str(&method)?,
             host: uri,
-            auth: get_auth_header(&header_map),
+            auth,
+            digest,
+            head_request: method == "HEAD",
+            body_length,
+            keep_alive,
+            local,
         };
         Ok(Self { inner: base })
     }
@@ -70,6 +97,10 @@ impl Protocol for HttpRequest {
         self.inner.auth.clone()
     }
 
+    fn get_digest_credentials(&self) -> Option<&Credentials> {
+        self.inner.digest.as_ref()
+    }
+
     fn get_host(&self) -> Uri {
         self.inner.host.clone()
     }
@@ -78,40 +109,71 @@ impl Protocol for HttpRequest {
         self.inner.method
     }
 
-    async fn respond_auth_result(&mut self, conn: &mut TcpStream, success: bool, _is_white: bool) -> Result<()> {
-        if !success {
-            write_all(conn, UNAUTHORIZED).await?;
-        }
-        Ok(())
+    fn is_head_request(&self) -> bool {
+        self.inner.head_request
+    }
+
+    fn body_length(&self) -> BodyLength {
+        self.inner.body_length
     }
 
-    async fn respond_command_result(&self, conn: &mut TcpStream, success: bool) -> Result<()> {
+    fn keep_alive(&self) -> bool {
+        self.inner.keep_alive
+    }
+
+    fn local_request(&self) -> Option<LocalRequest> {
+        self.inner.local
+    }
+
+    async fn respond_command_result(&self, conn: &mut dyn ClientStream, success: bool) -> Result<()> {
         if success {
             write_all(conn, SUCCESS).await?;
         }
         Ok(())
     }
 
-    async fn respond_authorization_required(&self, conn: &mut TcpStream) -> Result<()> {
-        write_all(conn, AUTHENTICATION_REQUIRED).await?;
-        Ok(())
+    async fn respond_authorization_required(
+        &self,
+        conn: &mut dyn ClientStream,
+        challenges: &[String],
+        close: bool,
+        error_page: &ErrorPage,
+    ) -> Result<()> {
+        let mut headers = String::new();
+        for challenge in challenges {
+            headers.push_str(&format!("Proxy-Authenticate: {}\r\n", challenge));
+        }
+        if close {
+            headers.push_str("Connection: close\r\n");
+        }
+        let reason = FailureReason::AuthFailed;
+        let response = error_page.response(reason.as_str(), reason.http_status(), reason.description(), &headers);
+        write_all(conn, response.as_bytes()).await
     }
 }
 
-async fn write_all(conn: &mut TcpStream, buf: &[u8]) -> Result<()> {
+async fn write_all(conn: &mut dyn ClientStream, buf: &[u8]) -> Result<()> {
     conn.write_all(buf).await?;
     conn.flush().await?;
     Ok(())
 }
 
-fn get_auth_header(headers: &HashMap<String, String>) -> Option<(String, String)> {
-    let basic_auth = headers.get(HTTP_AUTH_HEADER)?;
-    let split = basic_auth.split(' ').collect::<Vec<_>>();
-    if split.len() != 2 {
-        error!("invalid auth header: {}", basic_auth);
-        return None;
+/// The `Proxy-Authenticate` value asking for Basic credentials.
+pub fn basic_challenge(realm: &str) -> String {
+    format!("Basic realm=\"{}\"", realm)
+}
+
+/// Splits a `Proxy-Authorization` value into Basic or Digest credentials of a request.
+pub fn parse_auth_header(value: &str, method: &str, target: &str) -> (Option<(String, String)>, Option<Credentials>) {
+    let (scheme, params) = value.trim().split_once(' ').unwrap_or((value, ""));
+    if scheme.eq_ignore_ascii_case("basic") {
+        (decode_basic_auth(params).ok(), None)
+    } else if scheme.eq_ignore_ascii_case("digest") {
+        (None, Credentials::parse(method, target, params))
+    } else {
+        error!("invalid auth header: {}", value);
+        (None, None)
     }
-    decode_basic_auth(split[1]).ok()
 }
 
 fn decode_basic_auth(auth: &str) -> Result<(String, String)> {
@@ -1,20 +1,28 @@
 pub mod proxy;
 pub mod https;
+pub mod framing;
+pub mod rewrite;
+pub mod digest;
+pub mod error_page;
+pub mod local;
 
 
 use std::str::FromStr;
 
-use ::http::Uri;
-use bytes::{BufMut, Bytes, BytesMut};
+use ::http::{StatusCode, Uri};
+use bytes::{Bytes, BytesMut};
 use tracing::{debug, info};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
-use error::{Error, Result};
-
-const HTTP_FORBIDDEN: &[u8] = b"HTTP/1.1 403 Forbidden\r\n\r\n";
-const BUFF_SIZE: usize = 4096;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
+use error::{Error, FailureReason, Result};
+use error_page::ErrorPage;
+use framing::{BodyLength, HeadLimits};
+
+/// The client side of a connection, plain TCP or TLS, buffered so that a request head is read without
+/// consuming what follows it.
+pub trait ClientStream: AsyncBufRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncBufRead + AsyncWrite + Unpin + Send> ClientStream for T {}
+
 pub struct IncomingRequest {
     pub type_: ProtocolType,
     pub content: Bytes,
@@ -24,31 +32,43 @@ pub struct IncomingRequest {
 impl IncomingRequest {
     pub fn hostname(&self) -> String {
         let host = self.protocol.get_host();
-        let host = host.host().unwrap_or_default();
-        format_hostname(host)
+        format_hostname(host_name(&host))
     }
 }
 
-#[derive(Debug)]
-struct BaseRequestInfo {
-    pub host: Uri,
-    pub method: RequestType,
-    pub auth: Option<(String, String)>,
+/// The host of `uri`, without the brackets of an IPv6 literal.
+pub fn host_name(uri: &Uri) -> &str {
+    let host = uri.host().unwrap_or_default();
+    host.strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
 }
 
+/// Reads the next request head from `conn`.
+///
+/// A request that cannot be served because of its head is answered with a 400 or 431 before failing with
+/// [`Error::InvalidRequest`].
 pub async fn parse_incomming_request(
-    conn: &mut TcpStream,
-    is_white: bool,
+    conn: &mut dyn ClientStream,
+    limits: &HeadLimits,
+    error_page: &ErrorPage,
 ) -> Result<IncomingRequest> {
-    let request = read_content(conn).await?;
-    let bytes = request.clone();
-    let (type_, protocol_request) = {
-        let mut p = https::HttpRequest::new(bytes.freeze())?;
-        if !is_white && p.get_user_password().is_none() {
-            p.respond_authorization_required(conn).await?;
-            let request = read_content(conn).await?;
-            p = https::HttpRequest::new(request.freeze())?;
+    match read_request(conn, limits).await {
+        Err(Error::InvalidRequest(status, reason)) => {
+            info!("invalid request ({}): {}", status, reason);
+            let response = error_page.response(error_page::INVALID_REQUEST, status, &reason, "Connection: close\r\n");
+            write_all(conn, response.as_bytes()).await?;
+            conn.shutdown().await?;
+            Err(Error::InvalidRequest(status, reason))
         }
+        res => res,
+    }
+}
+
+async fn read_request(conn: &mut dyn ClientStream, limits: &HeadLimits) -> Result<IncomingRequest> {
+    let request = read_content(conn, limits).await?.freeze();
+    let (type_, protocol_request) = {
+        let p = parse_head(request.clone(), limits)?;
         (
             ProtocolType::Http,
             Box::new(p) as Box<dyn Protocol + Send + Sync>,
@@ -56,7 +76,7 @@ pub async fn parse_incomming_request(
     };
     Ok(IncomingRequest {
         type_,
-        content: request.freeze(),
+        content: request,
         protocol: protocol_request,
     })
 }
@@ -64,14 +84,49 @@ pub async fn parse_incomming_request(
 #[async_trait::async_trait]
 pub trait Protocol {
     fn get_user_password(&self) -> Option<(String, String)>;
+    fn get_digest_credentials(&self) -> Option<&digest::Credentials>;
     fn get_host(&self) -> Uri;
     fn get_method(&self) -> RequestType;
-    async fn respond_auth_result(&mut self, conn: &mut TcpStream, success: bool, is_white: bool) -> Result<()>;
-    async fn respond_command_result(&self, conn: &mut TcpStream, success: bool) -> Result<()>;
-    async fn respond_authorization_required(&self, conn: &mut TcpStream) -> Result<()>;
-    async fn respond_forbidden(&self, conn: &mut TcpStream) -> Result<()> {
-        write_all(conn, HTTP_FORBIDDEN).await?;
-        Ok(())
+    /// Whether the request is a HEAD, whose response has no body.
+    fn is_head_request(&self) -> bool;
+    fn body_length(&self) -> BodyLength;
+    /// Whether the client keeps the connection open for another request.
+    fn keep_alive(&self) -> bool;
+    /// The endpoint of the proxy itself the request is for, `None` if it is to be proxied.
+    fn local_request(&self) -> Option<local::LocalRequest>;
+    /// Answers a request for an endpoint of the proxy itself.
+    async fn respond_local(&self, conn: &mut dyn ClientStream, response: &local::LocalResponse, close: bool) -> Result<()> {
+        write_all(conn, response.to_http1(self.is_head_request(), close).as_bytes()).await
+    }
+    async fn respond_command_result(&self, conn: &mut dyn ClientStream, success: bool) -> Result<()>;
+    /// Answers with a 407 offering `challenges`, the connection is kept open for another attempt unless `close`.
+    async fn respond_authorization_required(
+        &self,
+        conn: &mut dyn ClientStream,
+        challenges: &[String],
+        close: bool,
+        error_page: &ErrorPage,
+    ) -> Result<()>;
+    /// Answers a request that could not be served with the status and the code of `reason`, a failed
+    /// authentication being challenged for Basic credentials of `realm`. The connection is closed after it.
+    async fn respond_failure(
+        &self,
+        conn: &mut dyn ClientStream,
+        reason: FailureReason,
+        realm: &str,
+        error_page: &ErrorPage,
+    ) -> Result<()> {
+        match reason {
+            FailureReason::AuthFailed => {
+                let challenges = [https::basic_challenge(realm)];
+                self.respond_authorization_required(conn, &challenges, true, error_page).await
+            }
+            _ => {
+                let response =
+                    error_page.response(reason.as_str(), reason.http_status(), reason.description(), "Connection: close\r\n");
+                write_all(conn, response.as_bytes()).await
+            }
+        }
     }
 }
 
@@ -108,38 +163,37 @@ impl FromStr for RequestType {
     }
 }
 
-async fn read_content(conn: &mut TcpStream) -> Result<BytesMut> {
-    let mut buf = [0; BUFF_SIZE];
-    let mut request = BytesMut::new();
+fn parse_head(request: Bytes, limits: &HeadLimits) -> Result<https::HttpRequest> {
+    https::HttpRequest::new(request, limits.max_headers)
+        .map_err(|e| Error::InvalidRequest(StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Reads the head of the next request, the body stays in `conn`.
+async fn read_content(conn: &mut dyn ClientStream, limits: &HeadLimits) -> Result<BytesMut> {
     let timeout = tokio::time::Duration::from_secs(10);
-    loop {
-        let n = tokio::time::timeout(timeout, conn.read(&mut buf)).await??;
-        info!("read {} bytes", n);
-        if n == 0 {
-            return Err(Error::EmptyRequest);
-        }
-        request.put_slice(&buf[..n]);
-        // if !request.is_ascii() {
-        //     return Err(RgError::InvalidRequest);
-        // }
-        if request.ends_with(b"\r\n\r\n") {
-            break;
-        }
-        if n < BUFF_SIZE {
-            break;
-        }
-    }
+    let request = tokio::time::timeout(timeout, framing::read_request_head(conn, limits))
+        .await??
+        .ok_or(Error::EmptyRequest)?;
+    info!("read {} bytes", request.len());
     debug!("request: {:?}", String::from_utf8_lossy(&request));
     Ok(request)
 }
 
-fn format_hostname(host: &str) -> String {
+/// Strips the port and keeps at most the last three labels of a host, as reported in traffic statistics.
+pub fn format_hostname(host: &str) -> String {
+    // IPv6 literals are kept whole, bracketed or not
+    if let Some(ip) = host.strip_prefix('[').and_then(|h| h.split(']').next()) {
+        return ip.to_string();
+    }
+    if host.parse::<std::net::Ipv6Addr>().is_ok() {
+        return host.to_string();
+    }
     let host = host.split(':').next().unwrap_or_default();
     let host = host.rsplit('.').take(3).collect::<Vec<_>>();
     host.into_iter().rev().collect::<Vec<&str>>().join(".")
 }
 
-async fn write_all(conn: &mut TcpStream, buf: &[u8]) -> Result<()> {
+async fn write_all(conn: &mut dyn ClientStream, buf: &[u8]) -> Result<()> {
     conn.write_all(buf).await?;
     conn.flush().await?;
     Ok(())
@@ -147,7 +201,10 @@ async fn write_all(conn: &mut TcpStream, buf: &[u8]) -> Result<()> {
 
 #[cfg(test)]
 mod test {
-    use crate::format_hostname;
+    use error::{Error, FailureReason};
+    use http::StatusCode;
+
+    use crate::{error_page::ErrorPage, format_hostname, framing::HeadLimits, Protocol};
 
     #[test]
     fn test_parse_hostname() {
@@ -161,5 +218,47 @@ mod test {
         test_fn("www.google.com:443", "www.google.com");
         test_fn("a.b.c.google.com:443", "c.google.com");
         test_fn("", "");
+        test_fn("[2001:db8::1]:443", "2001:db8::1");
+        test_fn("2001:db8::1", "2001:db8::1");
+    }
+
+    #[test]
+    fn test_host_name() {
+        let uri = "[2001:db8::1]:443".parse().unwrap();
+        assert_eq!(crate::host_name(&uri), "2001:db8::1");
+        assert_eq!(uri.port_u16(), Some(443));
+        let uri = "http://a.com/x".parse().unwrap();
+        assert_eq!(crate::host_name(&uri), "a.com");
+    }
+    #[tokio::test]
+    async fn test_respond_failure() {
+        async fn respond(reason: FailureReason) -> String {
+            let request = crate::https::HttpRequest::new(b"GET http://a.com/ HTTP/1.1\r\n\r\n"[..].into(), 16).unwrap();
+            let mut conn = tokio::io::join(&b""[..], Vec::new());
+            request.respond_failure(&mut conn, reason, "Custom", &ErrorPage::default()).await.unwrap();
+            String::from_utf8(conn.into_inner().1).unwrap()
+        }
+        let response = respond(FailureReason::AuthFailed).await;
+        assert!(response.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"));
+        assert!(response.contains("\r\nProxy-Authenticate: Basic realm=\"Custom\"\r\n"));
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        let response = respond(FailureReason::AclDenied).await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\nX-Proxy-Error: acl_denied\r\nConnection: close\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_framing() {
+        let heads: [&[u8]; 2] = [
+            b"POST http://a.com/ HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            b"POST http://a.com/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: identity\r\n\
+              Content-Length: 5\r\nContent-Length: 5\r\n\r\n",
+        ];
+        for head in heads {
+            let mut conn = tokio::io::join(head, Vec::new());
+            let res = crate::parse_incomming_request(&mut conn, &HeadLimits::default(), &ErrorPage::default()).await;
+            assert!(matches!(res, Err(Error::InvalidRequest(StatusCode::BAD_REQUEST, _))));
+            let response = String::from_utf8(conn.into_inner().1).unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        }
     }
 }
@@ -0,0 +1,202 @@
+//! Requests to the proxy itself rather than through it, answered on the proxy port.
+//!
+//! A client sending an origin-form `GET` or `HEAD` of one of these paths talks to the proxy as to a web server:
+//! `/proxy.pac` is the proxy auto-config file pointing browsers at the listener, `/healthz` tells whether the
+//! proxy is connected to its control plane.
+use std::{net::SocketAddr, sync::Arc};
+
+use ::http::{StatusCode, Uri};
+
+use crate::error_page::json_escape;
+
+pub const PAC_PATH: &str = "/proxy.pac";
+pub const HEALTH_PATH: &str = "/healthz";
+
+const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// An endpoint of the proxy itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalRequest {
+    ProxyPac,
+    Health,
+}
+
+impl LocalRequest {
+    /// The endpoint asked for by a request, `None` for a request to be proxied.
+    ///
+    /// Only a request target without scheme and authority is for the proxy itself, an absolute URI naming the
+    /// same path is proxied as usual.
+    pub fn recognize(method: &str, uri: &Uri) -> Option<Self> {
+        if !matches!(method, "GET" | "HEAD") || uri.scheme().is_some() || uri.authority().is_some() {
+            return None;
+        }
+        match uri.path() {
+            PAC_PATH => Some(Self::ProxyPac),
+            HEALTH_PATH => Some(Self::Health),
+            _ => None,
+        }
+    }
+}
+
+/// The response of a local endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalResponse {
+    pub status: StatusCode,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl LocalResponse {
+    /// The whole HTTP/1.1 response, without its body for a `HEAD`.
+    pub fn to_http1(&self, head_request: bool, close: bool) -> String {
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n{}\r\n{}",
+            self.status.as_str(),
+            self.status.canonical_reason().unwrap_or_default(),
+            self.content_type,
+            self.body.len(),
+            if close { "Connection: close\r\n" } else { "" },
+            if head_request { "" } else { self.body.as_str() }
+        )
+    }
+}
+
+/// The listener a client connected to, which the PAC file points it at.
+#[derive(Debug, Clone, Copy)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    /// the listener terminates TLS, browsers reach it as an `HTTPS` proxy
+    pub tls: bool,
+}
+
+/// Answers the local endpoints.
+#[derive(Debug, Clone, Default)]
+pub struct LocalEndpoints {
+    /// hosts the PAC file sends direct
+    bypass: Arc<[String]>,
+}
+
+impl LocalEndpoints {
+    /// `bypass` holds host names, which match their subdomains too, `*` and `?` patterns or IPv4 networks.
+    pub fn new(bypass: &[String]) -> Self {
+        Self {
+            bypass: Arc::from(bypass),
+        }
+    }
+
+    /// Answers `request` on `listener`, `backend_up` being whether the proxy is connected to its control plane.
+    pub fn respond(&self, request: LocalRequest, listener: &Listener, backend_up: bool) -> LocalResponse {
+        match request {
+            LocalRequest::ProxyPac => LocalResponse {
+                status: StatusCode::OK,
+                content_type: PAC_CONTENT_TYPE,
+                body: self.pac_file(listener),
+            },
+            LocalRequest::Health => LocalResponse {
+                status: if backend_up { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE },
+                content_type: JSON_CONTENT_TYPE,
+                body: format!(
+                    r#"{{"status":"{}","backend":"{}"}}"#,
+                    if backend_up { "ok" } else { "unavailable" },
+                    if backend_up { "connected" } else { "disconnected" }
+                ),
+            },
+        }
+    }
+
+    /// The proxy auto-config file sending every host but the bypassed ones through `listener`.
+    pub fn pac_file(&self, listener: &Listener) -> String {
+        let proxy = format!("{} {}", if listener.tls { "HTTPS" } else { "PROXY" }, listener.addr);
+        let conditions = self.bypass.iter().filter_map(|host| bypass_condition(host)).collect::<Vec<_>>();
+        let mut pac = String::from("function FindProxyForURL(url, host) {\n");
+        if !conditions.is_empty() {
+            pac.push_str(&format!("    if ({}) {{\n        return \"DIRECT\";\n    }}\n", conditions.join(" ||\n        ")));
+        }
+        pac.push_str(&format!("    return \"{}\";\n}}\n", proxy));
+        pac
+    }
+}
+
+/// The PAC expression matching the hosts of a bypass entry, `None` for an empty one.
+fn bypass_condition(entry: &str) -> Option<String> {
+    let entry = entry.trim().trim_start_matches('.').to_ascii_lowercase();
+    if entry.is_empty() {
+        return None;
+    }
+    if let Some((ip, prefix)) = entry.split_once('/')
+        && let (Ok(ip), Ok(prefix @ 0..=32)) = (ip.parse::<std::net::Ipv4Addr>(), prefix.parse::<u32>())
+    {
+        let mask = std::net::Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix).unwrap_or(0));
+        return Some(format!("isInNet(host, \"{}\", \"{}\")", ip, mask));
+    }
+    let entry = json_escape(&entry);
+    if entry.contains(['*', '?']) {
+        return Some(format!("shExpMatch(host, \"{}\")", entry));
+    }
+    Some(format!("host == \"{0}\" || dnsDomainIs(host, \".{0}\")", entry))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recognize() {
+        let uri = |s: &str| s.parse::<Uri>().unwrap();
+        assert_eq!(LocalRequest::recognize("GET", &uri("/proxy.pac")), Some(LocalRequest::ProxyPac));
+        assert_eq!(LocalRequest::recognize("HEAD", &uri("/healthz?full=1")), Some(LocalRequest::Health));
+        assert_eq!(LocalRequest::recognize("POST", &uri("/healthz")), None);
+        assert_eq!(LocalRequest::recognize("GET", &uri("/other")), None);
+        assert_eq!(LocalRequest::recognize("GET", &uri("http://a.com/proxy.pac")), None);
+        assert_eq!(LocalRequest::recognize("CONNECT", &uri("a.com:443")), None);
+    }
+
+    #[test]
+    fn test_pac_file() {
+        let listener = Listener {
+            addr: "10.0.0.1:8080".parse().unwrap(),
+            tls: false,
+        };
+        let endpoints = LocalEndpoints::new(&[
+            "Localhost".to_string(),
+            "*.corp.example.com".to_string(),
+            "192.168.0.0/16".to_string(),
+            " ".to_string(),
+        ]);
+        assert_eq!(
+            endpoints.pac_file(&listener),
+            "function FindProxyForURL(url, host) {\n    \
+             if (host == \"localhost\" || dnsDomainIs(host, \".localhost\") ||\n        \
+             shExpMatch(host, \"*.corp.example.com\") ||\n        \
+             isInNet(host, \"192.168.0.0\", \"255.255.0.0\")) {\n        \
+             return \"DIRECT\";\n    }\n    \
+             return \"PROXY 10.0.0.1:8080\";\n}\n"
+        );
+        let listener = Listener {
+            addr: "[2001:db8::1]:443".parse().unwrap(),
+            tls: true,
+        };
+        assert_eq!(
+            LocalEndpoints::default().pac_file(&listener),
+            "function FindProxyForURL(url, host) {\n    return \"HTTPS [2001:db8::1]:443\";\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_health() {
+        let listener = Listener {
+            addr: "127.0.0.1:1080".parse().unwrap(),
+            tls: false,
+        };
+        let res = LocalEndpoints::default().respond(LocalRequest::Health, &listener, false);
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            res.to_http1(true, true),
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Type: application/json\r\nContent-Length: 49\r\n\
+             Cache-Control: no-store\r\nConnection: close\r\n\r\n"
+        );
+        let res = LocalEndpoints::default().respond(LocalRequest::Health, &listener, true);
+        assert_eq!(res.body, r#"{"status":"ok","backend":"connected"}"#);
+    }
+}
@@ -1,96 +1,456 @@
-// use axum::body::Body;
-// use axum::extract::Request;
-// use axum::response::{IntoResponse, Response};
-// use axum::Router;
-// use axum::routing::get;
-// use http::{Method, StatusCode};
-// use hyper::body::Incoming;
-// use hyper::upgrade::Upgraded;
-// use hyper_util::rt::TokioIo;
-// use tokio::net::TcpStream;
-// use tokio::sync::OnceCell;
-// use tower::{Service, ServiceExt};
-// use hyper::service;
-//
-//
-//
-// pub async fn init() {
-//     HYPER_SERVICE_ONCE.get_or_init(|| async move{
-//         let router_svc = Router::new().route("/", get(|| async { "Hello, World!" }));
-//
-//         let tower_service = tower::service_fn(move |req: Request<_>| {
-//             let router_svc = router_svc.clone();
-//             let req = req.map(Body::new);
-//             async move {
-//                 if req.method() == Method::CONNECT {
-//                     proxy(req).await
-//                 } else {
-//                     router_svc.oneshot(req).await.map_err(|err| match err {})
-//                 }
-//             }
-//         });
-//
-//         let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
-//             tower_service.clone().call(request)
-//         });
-//         hyper_service
-//     })
-// }
-//
-//
-// pub async fn handle(stream: TcpStream) {
-//     let io = TokioIo::new(stream);
-//     let hyper_service = hyper_service.clone();
-//     tokio::task::spawn(async move {
-//         if let Err(err) = http1::Builder::new()
-//             .preserve_header_case(true)
-//             .title_case_headers(true)
-//             .serve_connection(io, hyper_service)
-//             .with_upgrades()
-//             .await
-//         {
-//             println!("Failed to serve connection: {:?}", err);
-//         }
-//     });
-// }
-//
-// pub async fn proxy(req: Request) -> Result<Response, hyper::Error> {
-//     tracing::trace!(?req);
-//     if let Some(host_addr) = req.uri().authority().map(|auth| auth.to_string()) {
-//         tokio::task::spawn(async move {
-//             match hyper::upgrade::on(req).await {
-//                 Ok(upgraded) => {
-//                     if let Err(e) = tunnel(upgraded, host_addr).await {
-//                         tracing::warn!("server io error: {}", e);
-//                     };
-//                 }
-//                 Err(e) => tracing::warn!("upgrade error: {}", e),
-//             }
-//         });
-//
-//         Ok(Response::new(Body::empty()))
-//     } else {
-//         tracing::warn!("CONNECT host is not socket addr: {:?}", req.uri());
-//         Ok((
-//             StatusCode::BAD_REQUEST,
-//             "CONNECT must be to a socket address",
-//         )
-//             .into_response())
-//     }
-// }
-//
-// async fn tunnel(upgraded: Upgraded, addr: String) -> std::io::Result<()> {
-//     let mut server = TcpStream::connect(addr).await?;
-//     let mut upgraded = TokioIo::new(upgraded);
-//
-//     let (from_client, from_server) =
-//         tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
-//
-//     tracing::debug!(
-//         "client wrote {} bytes and received {} bytes",
-//         from_client,
-//         from_server
-//     );
-//
-//     Ok(())
-// }
\ No newline at end of file
+//! HTTP proxy front end on hyper, an alternative to the request handling on top of [`crate::framing`].
+//!
+//! hyper reads and frames the messages, a [`ProxyHandler`] authenticates the users, decides where they may go,
+//! connects to the targets and accounts the traffic. Over HTTP/2 every request is a stream of its own, a CONNECT
+//! stream becomes a tunnel (RFC 9113 section 8.5).
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use error::{Error, FailureReason, Result};
+use http::{header, HeaderValue, Method, Request, Response, StatusCode, Version};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::client::conn::http1::SendRequest;
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+use crate::digest::Credentials;
+use crate::error_page::{self, ErrorPage};
+use crate::framing::HeadLimits;
+use crate::local::{LocalRequest, LocalResponse};
+use crate::rewrite::{self, Forwarding};
+use crate::{format_hostname, host_name, https, RequestType};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body of the requests and responses passed on by the proxy.
+pub type ProxyBody = BoxBody<Bytes, BoxError>;
+
+/// Outcome of authenticating a request.
+pub enum Authenticated<U> {
+    User(U),
+    /// no valid credentials, answered with a 407 offering these `Proxy-Authenticate` challenges
+    Challenge(Vec<String>),
+}
+
+/// What the hyper front end leaves to the proxy.
+#[async_trait::async_trait]
+pub trait ProxyHandler: Send + Sync + 'static {
+    type User: Clone + Send + Sync + 'static;
+    type Upstream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Authenticates the user of a request by the credentials of its `Proxy-Authorization`.
+    async fn authenticate(
+        &self,
+        basic: Option<(String, String)>,
+        digest: Option<&Credentials>,
+    ) -> Result<Authenticated<Self::User>>;
+
+    /// Whether `user` may reach `host`.
+    async fn allow(&self, user: &Self::User, host: &str) -> bool;
+
+    /// Connects to the target of a request.
+    async fn connect(&self, host: &str, port: u16) -> Result<Self::Upstream>;
+
+    /// Relays a CONNECT tunnel to `host` until one side closes.
+    async fn tunnel(
+        &self,
+        user: Self::User,
+        host: String,
+        client: TokioIo<Upgraded>,
+        upstream: Self::Upstream,
+    ) -> Result<()>;
+
+    /// Relays a connection whose target answered 101 Switching Protocols until one side closes, like
+    /// [`tunnel`](Self::tunnel).
+    async fn relay_switched(
+        &self,
+        user: Self::User,
+        hostname: String,
+        client: TokioIo<Upgraded>,
+        upstream: TokioIo<Upgraded>,
+    ) -> Result<()>;
+
+    /// The headers added to the forwarded requests of `user`.
+    fn forwarding(&self, user: &Self::User) -> Forwarding;
+
+    /// Accounts `bytes` of a forwarded request or response body to `user`.
+    fn account(&self, user: &Self::User, hostname: &str, bytes: u64, is_upload: bool);
+
+    /// Counts an authenticated request.
+    fn count_request(&self, method: RequestType);
+
+    /// Counts a request that could not be served.
+    fn count_failure(&self, reason: FailureReason);
+
+    /// Answers a request for an endpoint of the proxy itself, no credentials are asked for.
+    fn local(&self, request: LocalRequest) -> LocalResponse;
+
+    /// Registers an exchange of `user` in the kill list, the exchange is aborted once the receiver gets a message.
+    fn kill_signal(&self, user: &Self::User) -> broadcast::Receiver<()>;
+}
+
+/// Limits of the connections served by [`serve_connection`].
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    pub limits: HeadLimits,
+    /// the client has to send each request head within this, `None` if there is no deadline
+    pub header_read_timeout: Option<Duration>,
+    /// close an HTTP/1 connection after this many 407 in a row, 0 disables the limit
+    pub max_auth_attempts: u32,
+    /// the body of the error responses
+    pub error_page: ErrorPage,
+}
+
+/// Serves the requests of an HTTP/1 client connection until it closes or turns into a CONNECT tunnel.
+///
+/// Tunnels run on their own task once the 200 is sent.
+pub async fn serve_connection<I, H>(io: I, handler: Arc<H>, options: &ServeOptions) -> Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    H: ProxyHandler,
+{
+    hyper::server::conn::http1::Builder::new()
+        .preserve_header_case(true)
+        .title_case_headers(true)
+        .timer(TokioTimer::new())
+        .header_read_timeout(options.header_read_timeout)
+        // hyper refuses buffers below 8K
+        .max_buf_size(options.limits.max_size.max(8 * 1024))
+        .max_headers(options.limits.max_headers)
+        .serve_connection(TokioIo::new(io), Service::service_fn(handler, options.max_auth_attempts, options.error_page.clone()))
+        .with_upgrades()
+        .await
+        .map_err(hyper_error)
+}
+
+/// Serves the streams of an HTTP/2 client connection until it closes.
+///
+/// The 407 of a stream never closes the connection, other streams may be authenticated.
+pub async fn serve_http2_connection<I, H>(io: I, handler: Arc<H>, options: &ServeOptions) -> Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    H: ProxyHandler,
+{
+    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+        .timer(TokioTimer::new())
+        .max_header_list_size(options.limits.max_size.try_into().unwrap_or(u32::MAX))
+        .serve_connection(TokioIo::new(io), Service::service_fn(handler, 0, options.error_page.clone()))
+        .await
+        .map_err(hyper_error)
+}
+
+struct Service<H: ProxyHandler> {
+    handler: Arc<H>,
+    max_auth_attempts: u32,
+    error_page: ErrorPage,
+    /// 407 answered in a row
+    failed_auth: AtomicU32,
+    /// the target of the previous request with the connection to it
+    upstream: Mutex<Option<(Target, SendRequest<ProxyBody>)>>,
+}
+
+/// Host and port of a target.
+type Target = (String, u16);
+
+impl<H: ProxyHandler> Service<H> {
+    /// The hyper service answering the requests of one client connection.
+    fn service_fn(
+        handler: Arc<H>,
+        max_auth_attempts: u32,
+        error_page: ErrorPage,
+    ) -> impl hyper::service::Service<
+        Request<Incoming>,
+        Response = Response<ProxyBody>,
+        Error = Infallible,
+        Future = impl Future<Output = std::result::Result<Response<ProxyBody>, Infallible>> + Send,
+    > + Clone {
+        let service = Arc::new(Service {
+            handler,
+            max_auth_attempts,
+            error_page,
+            failed_auth: AtomicU32::new(0),
+            upstream: Mutex::new(None),
+        });
+        hyper::service::service_fn(move |req| {
+            let service = service.clone();
+            async move { Ok::<_, Infallible>(service.handle(req).await) }
+        })
+    }
+
+    async fn handle(&self, req: Request<Incoming>) -> Response<ProxyBody> {
+        match self.proxy(req).await {
+            Ok(res) => res,
+            Err(Error::InvalidRequest(status, reason)) => {
+                info!("invalid request ({}): {}", status, reason);
+                self.error_response(error_page::INVALID_REQUEST, status, &reason)
+            }
+            Err(e) => {
+                info!("http request failed: {}", e);
+                let reason = e.failure_reason().unwrap_or(FailureReason::Other);
+                self.handler.count_failure(reason);
+                self.failure_response(reason)
+            }
+        }
+    }
+
+    async fn proxy(&self, mut req: Request<Incoming>) -> Result<Response<ProxyBody>> {
+        if let Some(local) = LocalRequest::recognize(req.method().as_str(), req.uri()) {
+            return Ok(local_response(self.handler.local(local), req.method() == Method::HEAD));
+        }
+        let target = req.uri().to_string();
+        let (basic, digest) = match req.headers().get(header::PROXY_AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+            Some(value) => https::parse_auth_header(value, req.method().as_str(), &target),
+            None => (None, None),
+        };
+        let user = match self.handler.authenticate(basic, digest.as_ref()).await? {
+            Authenticated::User(user) => user,
+            Authenticated::Challenge(challenges) => return Ok(self.challenge(&challenges)),
+        };
+        self.failed_auth.store(0, Ordering::Relaxed);
+
+        let method = RequestType::from_str(req.method().as_str())?;
+        self.handler.count_request(method);
+        let host = host_name(req.uri()).to_owned();
+        if host.is_empty() {
+            return Err(Error::InvalidRequest(StatusCode::BAD_REQUEST, "request target without host".to_string()));
+        }
+        let port = req.uri().port_u16().unwrap_or(method.default_port());
+        if !self.handler.allow(&user, &host).await {
+            info!("forbidden request to host: {}", host);
+            self.handler.count_failure(FailureReason::AclDenied);
+            return Ok(self.failure_response(FailureReason::AclDenied));
+        }
+
+        // a CONNECT is answered by the proxy, the target only gets what follows the request head
+        if req.method() == Method::CONNECT {
+            let upstream = self.handler.connect(&host, port).await.map_err(Error::upstream)?;
+            let handler = self.handler.clone();
+            tokio::spawn(async move {
+                match hyper::upgrade::on(req).await {
+                    Ok(client) => {
+                        if let Err(e) = handler.tunnel(user, host, TokioIo::new(client), upstream).await {
+                            warn!("tunnel error: {}", e);
+                            if let Some(reason) = e.failure_reason() {
+                                handler.count_failure(reason);
+                            }
+                        }
+                    }
+                    Err(e) => warn!("upgrade error: {}", e),
+                }
+            });
+            return Ok(Response::new(empty()));
+        }
+
+        // an HTTP/1.1 client may ask the target to switch protocols, the connection is relayed as is after a 101
+        let client_upgrade = (req.version() == Version::HTTP_11 && rewrite::upgrade_protocols(req.headers()).is_some())
+            .then(|| hyper::upgrade::on(&mut req));
+        let hostname = format_hostname(&host);
+        let (mut parts, body) = req.into_parts();
+        let version = parts.version;
+        rewrite::rewrite_request(&mut parts, &self.handler.forwarding(&user))?;
+        // targets are spoken to in HTTP/1.1 whatever the client speaks
+        if version == Version::HTTP_2 {
+            parts.version = Version::HTTP_11;
+        }
+        let body = self.metered(body, &user, &hostname, true);
+        let mut sender = self.sender(&host, port).await?;
+        let mut res = tokio::select! {
+            res = sender.send_request(Request::from_parts(parts, body)) => res.map_err(|e| Error::upstream(hyper_error(e)))?,
+            _ = killed(self.handler.kill_signal(&user)) => return Err(Error::from("user killed")),
+        };
+        if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+            let client_upgrade = client_upgrade.ok_or_else(|| Error::from("target switched protocols unasked"))?;
+            return Ok(self.switch_protocols(user, hostname, client_upgrade, &mut res));
+        }
+        *self.upstream.lock().unwrap() = Some(((host, port), sender));
+
+        let (mut parts, body) = res.into_parts();
+        rewrite::strip_hop_by_hop(&mut parts.headers);
+        parts.version = version;
+        Ok(Response::from_parts(parts, self.metered(body, &user, &hostname, false)))
+    }
+
+    /// Passes the 101 of the target on and relays both connections once they are handed over.
+    fn switch_protocols(
+        &self,
+        user: H::User,
+        hostname: String,
+        client_upgrade: OnUpgrade,
+        res: &mut Response<Incoming>,
+    ) -> Response<ProxyBody> {
+        let upstream_upgrade = hyper::upgrade::on(&mut *res);
+        let handler = self.handler.clone();
+        tokio::spawn(async move {
+            match tokio::try_join!(client_upgrade, upstream_upgrade) {
+                Ok((client, upstream)) => {
+                    if let Err(e) = handler.relay_switched(user, hostname, TokioIo::new(client), TokioIo::new(upstream)).await {
+                        warn!("switched protocols relay error: {}", e);
+                    }
+                }
+                Err(e) => warn!("upgrade error: {}", e),
+            }
+        });
+
+        let mut switched = status_response(StatusCode::SWITCHING_PROTOCOLS);
+        let protocols = rewrite::upgrade_protocols(res.headers()).cloned();
+        *switched.headers_mut() = std::mem::take(res.headers_mut());
+        rewrite::strip_hop_by_hop(switched.headers_mut());
+        if let Some(protocols) = protocols {
+            switched.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            switched.headers_mut().insert(header::UPGRADE, protocols);
+        }
+        switched
+    }
+
+    /// A 407 offering `challenges`, closing the connection after too many attempts.
+    fn challenge(&self, challenges: &[String]) -> Response<ProxyBody> {
+        let mut res = self.failure_response(FailureReason::AuthFailed);
+        for challenge in challenges {
+            if let Ok(value) = HeaderValue::from_str(challenge) {
+                res.headers_mut().append(header::PROXY_AUTHENTICATE, value);
+            }
+        }
+        let failed = self.failed_auth.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.max_auth_attempts != 0 && failed >= self.max_auth_attempts {
+            self.handler.count_failure(FailureReason::AuthFailed);
+            res.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+        }
+        res
+    }
+
+    /// An error response carrying `code` in `X-Proxy-Error` and the body of the error page.
+    fn error_response(&self, code: &str, status: StatusCode, message: &str) -> Response<ProxyBody> {
+        let body = self.error_page.body(code, status, message);
+        let mut res = Response::new(Full::new(Bytes::from(body)).map_err(|never| match never {}).boxed());
+        *res.status_mut() = status;
+        if let Ok(code) = HeaderValue::from_str(code) {
+            res.headers_mut().insert(error_page::ERROR_HEADER, code);
+        }
+        res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        res
+    }
+
+    fn failure_response(&self, reason: FailureReason) -> Response<ProxyBody> {
+        self.error_response(reason.as_str(), reason.http_status(), reason.description())
+    }
+
+    /// The connection of the previous request if it went to the same target and is still open, a new one otherwise.
+    async fn sender(&self, host: &str, port: u16) -> Result<SendRequest<ProxyBody>> {
+        let previous = self.upstream.lock().unwrap().take();
+        if let Some((target, mut sender)) = previous
+            && target.0 == host
+            && target.1 == port
+            && sender.ready().await.is_ok()
+        {
+            return Ok(sender);
+        }
+        let upstream = self.handler.connect(host, port).await.map_err(Error::upstream)?;
+        let (sender, conn) = hyper::client::conn::http1::Builder::new()
+            .preserve_header_case(true)
+            .title_case_headers(true)
+            .handshake(TokioIo::new(upstream))
+            .await
+            .map_err(|e| Error::upstream(hyper_error(e)))?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.with_upgrades().await {
+                info!("upstream connection closed: {}", e);
+            }
+        });
+        Ok(sender)
+    }
+
+    fn metered(&self, body: Incoming, user: &H::User, hostname: &str, is_upload: bool) -> ProxyBody {
+        let killed = Box::pin(killed(self.handler.kill_signal(user)));
+        let (handler, user, hostname) = (self.handler.clone(), user.clone(), hostname.to_owned());
+        Metered {
+            inner: body,
+            meter: Box::new(move |bytes| handler.account(&user, &hostname, bytes, is_upload)),
+            killed,
+        }
+        .boxed()
+    }
+}
+
+/// Resolves once the user of an exchange gets killed.
+async fn killed(mut kill: broadcast::Receiver<()>) {
+    if let Err(RecvError::Closed) = kill.recv().await {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// A body reporting the size of every data frame passing through it, failing once its user gets killed.
+struct Metered<B> {
+    inner: B,
+    meter: Box<dyn Fn(u64) + Send + Sync>,
+    killed: Pin<Box<dyn Future<Output = ()> + Send + Sync>>,
+}
+
+impl<B> Body for Metered<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<std::result::Result<Frame<Bytes>, BoxError>>> {
+        if self.killed.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Some(Err("user killed".into())));
+        }
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx)).map(|f| f.map_err(Into::into));
+        if let Some(data) = frame.as_ref().and_then(|f| f.as_ref().ok()).and_then(Frame::data_ref) {
+            (self.meter)(data.len() as u64);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn empty() -> ProxyBody {
+    Empty::new().map_err(|never| match never {}).boxed()
+}
+
+/// The response of a local endpoint, with an empty body for a `HEAD`.
+fn local_response(local: LocalResponse, head_request: bool) -> Response<ProxyBody> {
+    let length = local.body.len();
+    let body = if head_request { empty() } else { Full::new(Bytes::from(local.body)).map_err(|never| match never {}).boxed() };
+    let mut res = Response::new(body);
+    *res.status_mut() = local.status;
+    let headers = res.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(local.content_type));
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    res
+}
+
+fn status_response(status: StatusCode) -> Response<ProxyBody> {
+    let mut res = Response::new(empty());
+    *res.status_mut() = status;
+    res
+}
+
+fn hyper_error(e: hyper::Error) -> Error {
+    Error::from(std::io::Error::other(e))
+}
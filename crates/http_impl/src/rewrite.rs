@@ -0,0 +1,304 @@
+//! Rewriting of a request head forwarded to the origin server, RFC 7230 sections 5.3, 5.4, 5.7, 6.1 and 6.7.
+use std::net::IpAddr;
+
+use ::http::{header, request, HeaderMap, HeaderValue, Uri, Version};
+use bytes::{BufMut, BytesMut};
+use error::{Error, Result};
+
+/// Headers that only apply to the connection between the client and the proxy.
+///
+/// `Transfer-Encoding` is hop-by-hop as well but stays, bodies are forwarded with the coding they arrived with.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Headers a proxy adds to the requests it forwards.
+#[derive(Clone, Debug, Default)]
+pub struct Forwarding {
+    /// the pseudonym of the proxy in an added `Via`, none if `None`
+    pub via: Option<String>,
+    /// the client address appended to `X-Forwarded-For`, none if `None`
+    pub forwarded_for: Option<IpAddr>,
+}
+
+/// Rewrites the head of a request to forward it to the origin server.
+///
+/// An absolute-form request-target becomes origin-form with `Host` taken from it, hop-by-hop headers and the
+/// headers listed in `Connection` are dropped and the headers of `forwarding` are added. The `Upgrade` of an
+/// HTTP/1.1 request listing it in `Connection` is passed on, the target may switch protocols. A `Content-Length`
+/// next to a `Transfer-Encoding` is dropped, the body is framed by the transfer coding (RFC 7230 section 3.3.3).
+pub fn rewrite_request_head(head: &[u8], forwarding: &Forwarding) -> Result<BytesMut> {
+    // one header field per line at most
+    let lines = head.iter().filter(|&&b| b == b'\n').count();
+    let mut headers = vec![httparse::EMPTY_HEADER; lines];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => return Err(Error::from("incomplete request head")),
+        Err(e) => return Err(Error::from(format!("parse content error {:?}", e))),
+    }
+    let method = req.method.ok_or_else(|| Error::from("do not find method"))?;
+    let path = req.path.ok_or_else(|| Error::from("do not find path"))?;
+    let version = req.version.unwrap_or(1);
+
+    let (target, host) = match path.parse::<Uri>() {
+        Ok(uri) if uri.scheme().is_some() => {
+            let target = uri.path_and_query().map_or("/", |p| p.as_str()).to_owned();
+            (target, uri.authority().map(|a| a.as_str().to_owned()))
+        }
+        _ => (path.to_owned(), None),
+    };
+
+    // Connection lists further headers that are not forwarded
+    let listed = req
+        .headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("connection"))
+        .flat_map(|h| String::from_utf8_lossy(h.value).split(',').map(|o| o.trim().to_ascii_lowercase()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let upgrade = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("upgrade"))
+        .filter(|_| version == 1 && listed.iter().any(|o| o == "upgrade"))
+        .map(|h| h.value);
+    let transfer_coded = req.headers.iter().any(|h| h.name.eq_ignore_ascii_case("transfer-encoding"));
+    let forwarded = |name: &str| {
+        let name = name.to_ascii_lowercase();
+        let framing = transfer_coded && name == "content-length";
+        !(HOP_BY_HOP.contains(&name.as_str()) || listed.contains(&name) || framing)
+    };
+
+    let mut out = BytesMut::with_capacity(head.len() + 64);
+    out.put_slice(format!("{} {} HTTP/1.{}\r\n", method, target, version).as_bytes());
+    if let Some(host) = &host {
+        put_header(&mut out, "Host", host.as_bytes());
+    }
+    let mut forwarded_for = Vec::new();
+    for h in req.headers.iter() {
+        if !forwarded(h.name) || (host.is_some() && h.name.eq_ignore_ascii_case("host")) {
+            continue;
+        }
+        if forwarding.forwarded_for.is_some() && h.name.eq_ignore_ascii_case("x-forwarded-for") {
+            forwarded_for.push(String::from_utf8_lossy(h.value));
+            continue;
+        }
+        put_header(&mut out, h.name, h.value);
+    }
+    if let Some(protocols) = upgrade {
+        put_header(&mut out, "Connection", b"upgrade");
+        put_header(&mut out, "Upgrade", protocols);
+    }
+    if let Some(via) = &forwarding.via {
+        put_header(&mut out, "Via", format!("1.{} {}", version, via).as_bytes());
+    }
+    if let Some(ip) = forwarding.forwarded_for {
+        forwarded_for.push(ip.to_string().into());
+        put_header(&mut out, "X-Forwarded-For", forwarded_for.join(", ").as_bytes());
+    }
+    out.put_slice(b"\r\n");
+    Ok(out)
+}
+
+/// Rewrites a request parsed by hyper to forward it to the origin server, like [`rewrite_request_head`].
+pub fn rewrite_request(parts: &mut request::Parts, forwarding: &Forwarding) -> Result<()> {
+    let upgrade = upgrade_protocols(&parts.headers).filter(|_| parts.version == Version::HTTP_11).cloned();
+    let forwarded_for = forwarding.forwarded_for.map(|ip| {
+        let previous = parts.headers.get_all("x-forwarded-for").iter().filter_map(|v| v.to_str().ok()).collect::<Vec<_>>();
+        previous.into_iter().map(str::to_owned).chain([ip.to_string()]).collect::<Vec<_>>().join(", ")
+    });
+    strip_hop_by_hop(&mut parts.headers);
+    if parts.headers.contains_key(header::TRANSFER_ENCODING) {
+        parts.headers.remove(header::CONTENT_LENGTH);
+    }
+    if parts.uri.scheme().is_some() {
+        if let Some(authority) = parts.uri.authority() {
+            parts.headers.insert(header::HOST, header_value(authority.as_str())?);
+        }
+        let target = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+        parts.uri = target.parse()?;
+    }
+    if let Some(protocols) = upgrade {
+        parts.headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        parts.headers.insert(header::UPGRADE, protocols);
+    }
+    if let Some(via) = &forwarding.via {
+        let version = if parts.version == Version::HTTP_10 { 0 } else { 1 };
+        parts.headers.append(header::VIA, header_value(&format!("1.{} {}", version, via))?);
+    }
+    if let Some(forwarded_for) = forwarded_for {
+        parts.headers.insert("x-forwarded-for", header_value(&forwarded_for)?);
+    }
+    Ok(())
+}
+
+/// Drops the hop-by-hop headers and the headers listed in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = connection_options(headers);
+    for name in HOP_BY_HOP.iter().copied().chain(listed.iter().map(String::as_str)) {
+        headers.remove(name);
+    }
+}
+
+/// The `Upgrade` of a message listing it in `Connection`, the protocols its sender wants to switch to.
+pub fn upgrade_protocols(headers: &HeaderMap) -> Option<&HeaderValue> {
+    headers.get(header::UPGRADE).filter(|_| connection_options(headers).iter().any(|o| o == "upgrade"))
+}
+
+/// The lowercased options listed in `Connection`.
+fn connection_options(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .flat_map(|v| String::from_utf8_lossy(v.as_bytes()).split(',').map(|o| o.trim().to_ascii_lowercase()).collect::<Vec<_>>())
+        .collect()
+}
+
+fn header_value(value: &str) -> Result<HeaderValue> {
+    HeaderValue::from_str(value).map_err(|e| Error::from(format!("invalid header value {:?}: {}", value, e)))
+}
+
+fn put_header(out: &mut BytesMut, name: &str, value: &[u8]) {
+    out.put_slice(name.as_bytes());
+    out.put_slice(b": ");
+    out.put_slice(value);
+    out.put_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rewrite(head: &str, forwarding: &Forwarding) -> String {
+        String::from_utf8(rewrite_request_head(head.as_bytes(), forwarding).unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_origin_form_and_hop_by_hop() {
+        let head = "GET http://a.com:8080/p?q=1 HTTP/1.1\r\nHost: b.com\r\nProxy-Authorization: Basic dTpw\r\n\
+                    Connection: keep-alive, X-Secret\r\nX-Secret: 1\r\nProxy-Connection: keep-alive\r\n\
+                    Transfer-Encoding: chunked\r\nX-Proxy-Id: 7\r\n\r\n";
+        assert_eq!(
+            rewrite(head, &Forwarding::default()),
+            "GET /p?q=1 HTTP/1.1\r\nHost: a.com:8080\r\nTransfer-Encoding: chunked\r\nX-Proxy-Id: 7\r\n\r\n"
+        );
+        assert_eq!(
+            rewrite("OPTIONS http://a.com HTTP/1.0\r\n\r\n", &Forwarding::default()),
+            "OPTIONS / HTTP/1.0\r\nHost: a.com\r\n\r\n"
+        );
+        // origin-form is kept with its Host
+        assert_eq!(
+            rewrite("GET /x HTTP/1.1\r\nHost: a.com\r\n\r\n", &Forwarding::default()),
+            "GET /x HTTP/1.1\r\nHost: a.com\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_forwarding_headers() {
+        let forwarding = Forwarding {
+            via: Some("rg".to_owned()),
+            forwarded_for: Some("10.0.0.2".parse().unwrap()),
+        };
+        assert_eq!(
+            rewrite("GET http://a.com/ HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n", &forwarding),
+            "GET / HTTP/1.1\r\nHost: a.com\r\nVia: 1.1 rg\r\nX-Forwarded-For: 10.0.0.1, 10.0.0.2\r\n\r\n"
+        );
+        // every earlier hop is kept
+        assert_eq!(
+            rewrite(
+                "GET http://a.com/ HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1, 10.0.0.3\r\nX-Forwarded-For: 10.0.0.4\r\n\r\n",
+                &forwarding
+            ),
+            "GET / HTTP/1.1\r\nHost: a.com\r\nVia: 1.1 rg\r\nX-Forwarded-For: 10.0.0.1, 10.0.0.3, 10.0.0.4, 10.0.0.2\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_content_length_with_transfer_encoding() {
+        let head = "POST http://a.com/ HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\
+                    Content-Length: 5\r\n\r\n";
+        assert_eq!(
+            rewrite(head, &Forwarding::default()),
+            "POST / HTTP/1.1\r\nHost: a.com\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+        assert_eq!(
+            rewrite("POST http://a.com/ HTTP/1.1\r\nContent-Length: 5\r\n\r\n", &Forwarding::default()),
+            "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Length: 5\r\n\r\n"
+        );
+
+        let (mut parts, _) = ::http::Request::post("http://a.com/")
+            .header("content-length", "5")
+            .header("transfer-encoding", "chunked")
+            .body(())
+            .unwrap()
+            .into_parts();
+        rewrite_request(&mut parts, &Forwarding::default()).unwrap();
+        assert!(!parts.headers.contains_key("content-length"));
+        assert_eq!(parts.headers["transfer-encoding"], "chunked");
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let head = "GET http://a.com/chat HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        assert_eq!(
+            rewrite(head, &Forwarding::default()),
+            "GET /chat HTTP/1.1\r\nHost: a.com\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Connection: upgrade\r\nUpgrade: websocket\r\n\r\n"
+        );
+        // not listed in Connection, or from an HTTP/1.0 client
+        assert_eq!(
+            rewrite("GET / HTTP/1.1\r\nHost: a.com\r\nUpgrade: websocket\r\n\r\n", &Forwarding::default()),
+            "GET / HTTP/1.1\r\nHost: a.com\r\n\r\n"
+        );
+        assert_eq!(
+            rewrite("GET / HTTP/1.0\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n", &Forwarding::default()),
+            "GET / HTTP/1.0\r\n\r\n"
+        );
+
+        let (mut parts, _) = ::http::Request::get("http://a.com/chat")
+            .header("connection", "Upgrade")
+            .header("upgrade", "websocket")
+            .body(())
+            .unwrap()
+            .into_parts();
+        rewrite_request(&mut parts, &Forwarding::default()).unwrap();
+        assert_eq!(parts.headers["connection"], "upgrade");
+        assert_eq!(parts.headers["upgrade"], "websocket");
+    }
+
+    #[test]
+    fn test_rewrite_parsed_request() {
+        let (mut parts, _) = ::http::Request::get("http://a.com:8080/p?q=1")
+            .header("host", "b.com")
+            .header("connection", "X-Secret")
+            .header("x-secret", "1")
+            .header("proxy-authorization", "Basic dTpw")
+            .header("x-forwarded-for", "10.0.0.1")
+            .header("x-proxy-id", "7")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let forwarding = Forwarding {
+            via: Some("rg".to_owned()),
+            forwarded_for: Some("10.0.0.2".parse().unwrap()),
+        };
+        rewrite_request(&mut parts, &forwarding).unwrap();
+        assert_eq!(parts.uri, "/p?q=1");
+        let mut headers = parts.headers.iter().map(|(k, v)| format!("{}: {}", k, v.to_str().unwrap())).collect::<Vec<_>>();
+        headers.sort();
+        assert_eq!(headers, [
+            "host: a.com:8080",
+            "via: 1.1 rg",
+            "x-forwarded-for: 10.0.0.1, 10.0.0.2",
+            "x-proxy-id: 7",
+        ]);
+    }
+}
@@ -1,5 +1,7 @@
 mod black_list;
 
+pub use black_list::BlackListAclRule;
+
 use rg_common::user_auth::UserInfo;
 
 pub trait AclRule {
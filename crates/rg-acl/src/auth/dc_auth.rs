@@ -16,6 +16,9 @@ pub struct DcAuthenticator {
     // whitelist: ip-username-password => UserId, if default user, user_id = 0
     user_white_list: DashMap<String, UserId>,
 
+    // whitelist: ip-username => (password, UserId)
+    white_list_passwords: DashMap<String, Vec<(String, UserId)>>,
+
     // user_id -> user_info
     pub user_map: DashMap<UserId, UserInfo>,
 
@@ -51,6 +54,16 @@ impl Authenticator for DcAuthenticator {
                     return (true, UserInfo::clone_id(&user_info));
                 }
             }
+        } else if !username.is_empty() {
+            // the lookup the HMAC and Digest methods share, which have no password to compare
+            return match self
+                .password_users(username, ip)
+                .into_iter()
+                .find(|(user_password, _)| user_password == password)
+            {
+                Some((_, user_info)) => (true, user_info),
+                None => (false, UserInfo::default()),
+            };
         } else if let Some(user_id) =
             self.check_white_list(&format!("{}-{}-{}", ip, username, password))
         {
@@ -119,6 +132,7 @@ impl Authenticator for DcAuthenticator {
 
     fn update_white_list(&mut self, white_list: Vec<WhiteListData>) {
         let user_white_list = DashMap::new();
+        let white_list_passwords = DashMap::<_, Vec<_>>::new();
         let mut in_stock = HashSet::new();
         for white in white_list {
             let key = if white.username.is_empty() && white.password.is_empty() {
@@ -127,9 +141,16 @@ impl Authenticator for DcAuthenticator {
                 format!("{}-{}-{}", white.ip, white.username, white.password)
             };
             user_white_list.insert(key, white.user_id);
+            if !white.username.is_empty() {
+                white_list_passwords
+                    .entry(format!("{}-{}", white.ip, white.username))
+                    .or_default()
+                    .push((white.password.clone(), white.user_id));
+            }
             in_stock.insert(white.ip.clone());
         }
         self.user_white_list = user_white_list;
+        self.white_list_passwords = white_list_passwords;
         self.in_stock = in_stock;
     }
 
@@ -144,6 +165,45 @@ impl Authenticator for DcAuthenticator {
             None
         }
     }
+
+    fn password_users(&self, username: &str, ip: &str) -> Vec<(String, UserInfo)> {
+        if username.is_empty() {
+            return Vec::new();
+        }
+        let key = format!("{}-{}", ip, username);
+        let mut users = Vec::new();
+        // white listed ip-username-password first
+        if let Some(white) = self.white_list_passwords.get(&key) {
+            for (password, user_id) in white.iter() {
+                if *user_id == 0 {
+                    users.push((password.clone(), UserInfo::default()));
+                } else if let Some(user_info) = self.user_map.get(user_id) {
+                    if user_info.available && user_info.auth_type == PASSWORD {
+                        users.push((password.clone(), UserInfo::clone_id(&user_info)));
+                    }
+                }
+            }
+        }
+        // then the password of the user
+        if let Some(user_id) = self.ip_map.get(&key) {
+            if let Some(user_info) = self.user_map.get(&user_id) {
+                if user_info.available {
+                    users.push((user_info.password.clone(), UserInfo::clone_id(&user_info)));
+                }
+            }
+        }
+        users
+    }
+
+    fn is_disabled(&self, username: &str, ip: &str) -> bool {
+        if username.is_empty() {
+            return false;
+        }
+        self.ip_map
+            .get(&format!("{}-{}", ip, username))
+            .and_then(|user_id| self.user_map.get(user_id.value()).map(|user_info| !user_info.available))
+            .unwrap_or(false)
+    }
 }
 
 impl DcAuthenticator {
@@ -46,6 +46,17 @@ pub trait Authenticator {
     fn in_stock(&self, ip: &str) -> bool;
 
     fn user_map_get(&self, remote_ip: &str) -> Option<UserInfo>;
+
+    /// the users `username` may authenticate as by password on `ip`, each with the password it is checked
+    /// against, in the order `check_auth` tries them
+    fn password_users(&self, _username: &str, _ip: &str) -> Vec<(String, UserInfo)> {
+        Vec::new()
+    }
+
+    /// whether `username` is a user of `ip` that exists but is disabled
+    fn is_disabled(&self, _username: &str, _ip: &str) -> bool {
+        false
+    }
 }
 
 pub struct DefaultAuthenticator;
@@ -1,3 +1,9 @@
+use std::sync::atomic::AtomicBool;
+
+/// backend status, if backend is running or not
+/// used for reconnect if backend is down, and reported by the health endpoint of the proxy
+pub static BACKEND_STATUS: AtomicBool = AtomicBool::new(false);
+
 #[derive(Debug, Clone)]
 pub enum ProxyBackend {
     DcProxy,
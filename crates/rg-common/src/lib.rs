@@ -1,14 +1,12 @@
 use std::path::PathBuf;
 
-use error::RgError;
 use serde::{Deserialize, Serialize};
 
 pub mod backend;
-pub mod error;
 pub mod stat;
 pub mod user_auth;
 
-pub type Result<T> = std::result::Result<T, RgError>;
+pub use error::Result;
 
 pub type UserId = u64;
 pub type UserPlanId = u64;
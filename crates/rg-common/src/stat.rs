@@ -52,6 +52,9 @@ pub struct StatData {
 pub struct ConnectionStatSnapshot {
     pub alive_in_connection: i64,
     // pub alive_out_connection: i64,
+    // absent in snapshots from older nodes
+    #[serde(default)]
+    pub handshake_timeout: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -59,7 +62,26 @@ pub struct RequestStatSnapshot {
     pub total_request: u64,
     pub http_request: u64,
     pub https_request: u64,
+    // absent in snapshots from older nodes
+    #[serde(default)]
+    pub socks4_request: u64,
     pub socks5_request: u64,
+    #[serde(default)]
+    pub failures: FailureStatSnapshot,
+}
+
+/// Requests that could not be served, by reason.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FailureStatSnapshot {
+    pub dns_not_found: u64,
+    pub connection_refused: u64,
+    pub timeout: u64,
+    pub network_unreachable: u64,
+    pub acl_denied: u64,
+    pub auth_failed: u64,
+    pub user_disabled: u64,
+    pub other: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1,45 +1,48 @@
-use crate::socks5_server::auth::UserKeyAuth;
-use crate::socks5_server::handle_conn::handle;
+use crate::socks5_server::connection::bind::{self, Bind};
+use crate::socks5_server::connection::connect::{self, Connect};
+use crate::socks5_server::handle_conn::handle_s5_upd_associate;
 use crate::socks5_server::server_auth::ServerAuth;
-use crate::socks5_server::{handle_conn, AuthAdaptor, IncomingConnection};
-use crate::{backend::io_copy, get_traffic_fn, resolver::resolve_host, util::remove_headers, FilterFn};
+use crate::socks5_server::{ClientConnection, IncomingConnection, Resolve};
+use crate::resolver::{resolve_host, resolve_ip, resolve_ptr};
+use crate::tls::TlsAcceptor;
 use async_channel::Sender;
-use error::{Error, Result};
-use http_impl::parse_incomming_request;
-use rg_acl::acl::DefaultAclRule;
-use rg_acl::auth::dc_auth::DcAuthenticator;
-use rg_common::UserId;
-use rg_stat::StatEvent;
-use socks5_http::{Sock5Http, Sock5OrHttp};
+use config::ProxyConfig;
+use error::{Error, FailureReason, Result};
+use http_impl::framing::{copy_body, read_response_head, BodyLength, HeadLimits, ResponseHead};
+use http_impl::rewrite::rewrite_request_head;
+use http_impl::local::Listener;
+use http_impl::{host_name, parse_incomming_request, IncomingRequest};
+use rg_acl::{AclCenter, AuthCenter};
+use rg_common::{user_auth::UserInfo, UserId};
+use rg_stat::{RequestType, StatEvent};
+use socks5_http::{ProxyStream, Sock5Http, Sock5OrHttp};
+use socks5_protocol::{socks4, Address, AsyncStreamOperation, Command, Reply};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::{Deref, DerefMut};
 use std::sync::{Arc, LazyLock};
-use std::{
-    net::{IpAddr, SocketAddr},
-    ops::{Deref, DerefMut},
-    time::Duration,
-};
+use std::time::Duration;
 use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::{OnceCell, RwLock};
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
 use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpSocket, TcpStream},
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpSocket, TcpStream},
 };
-use tracing::{debug, error, info};
+use tracing::{error, info, warn};
 
-use super::{check_is_white, get_stat_request_type, http_check_user_auth, CommonBackend, ServerBackend};
+use super::{address_host, check_is_white, get_stat_request_type, hyper_http, CommonBackend, ConnInfo, ServerBackend};
 
 const RETRY_LIMIT: u32 = 3;
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub static DC_SERVER_BACKEND_ONCE: OnceCell<Arc<DcServerBackend>> = OnceCell::const_new();
 pub static DC_SERVER_BACKEND: LazyLock<Arc<DcServerBackend>> =
     LazyLock::new(|| DC_SERVER_BACKEND_ONCE.get().expect("DcServerBackend not initialized").clone());
 
-pub async fn init(stat_sender: UnboundedSender<StatEvent>) {
+pub async fn init(stat_sender: UnboundedSender<StatEvent>, auth_center: AuthCenter, acl_center: AclCenter, config: ProxyConfig) {
     DC_SERVER_BACKEND_ONCE
         .get_or_init(|| async move {
-            let acl_center = Arc::new(RwLock::new(DefaultAclRule {}));
-            let auth_center = Arc::new(RwLock::new(DcAuthenticator::default()));
-
-            let dc_backend = DcServerBackend::new(CommonBackend::new(auth_center.clone(), acl_center.clone(), stat_sender));
+            let dc_backend = DcServerBackend::new(CommonBackend::new(auth_center, acl_center, stat_sender, config));
             Arc::new(dc_backend)
         })
         .await;
@@ -69,228 +72,528 @@ impl DerefMut for DcServerBackend {
     }
 }
 
+impl DcServerBackend {
+    async fn handle_socks5(&self, conn: ProxyStream, conn_info: &ConnInfo) -> Result<()> {
+        let auth = ServerAuth::new(
+            self.auth.clone(),
+            conn_info.is_white,
+            conn_info.local_ip.clone(),
+            conn_info.remote_ip.clone(),
+        );
+        let incoming = IncomingConnection::new(conn, Arc::new(auth.into_chain()));
+        let (conn, user_info) = conn_info.handshake(incoming.authenticate()).await?;
+        let user_info = user_info?;
+
+        match conn_info.handshake(conn.wait_request()).await? {
+            ClientConnection::Connect(connect, addr) => self.handle_socks5_connect(connect, addr, &user_info, conn_info).await,
+            ClientConnection::Bind(bind, addr) => self.handle_socks5_bind(bind, addr, &user_info, conn_info).await,
+            ClientConnection::UdpAssociate(associate, addr) => {
+                handle_s5_upd_associate(self, associate, addr, &user_info, conn_info).await
+            }
+            ClientConnection::Resolve(resolve, addr) => {
+                self.handle_socks5_resolve(resolve, addr, Command::Resolve, &user_info, conn_info).await
+            }
+            ClientConnection::ResolvePtr(resolve, addr) => {
+                self.handle_socks5_resolve(resolve, addr, Command::ResolvePtr, &user_info, conn_info).await
+            }
+        }
+    }
+
+    async fn handle_socks5_connect(
+        &self,
+        connect: Connect<connect::NeedReply>,
+        addr: Address,
+        user_info: &UserInfo,
+        conn_info: &ConnInfo,
+    ) -> Result<()> {
+        let (host, hostname, port) = address_host(&addr);
+        info!("socks5 connect, host: {}, port: {}", host, port);
+        self.request_stat(RequestType::Socks5);
+
+        // check acl
+        if !self.acl.read().await.check(user_info, &host, &conn_info.local_ip) {
+            let mut conn = connect.reply(Reply::from(FailureReason::AclDenied), Address::unspecified()).await?;
+            conn.shutdown().await?;
+            error!("forbidden request from user: {:?}, host: {}", user_info, host);
+            return Err(Error::ForbiddenRequest);
+        }
+
+        // resolve dns hostname and connect to target website
+        let out_conn = match conn_info.handshake(connect_address(addr, conn_info.local_addr)).await {
+            Ok(out_conn) => out_conn,
+            Err(e) => {
+                let e = Error::upstream(e);
+                let mut conn = connect.reply(failure_reply(&e), Address::unspecified()).await?;
+                conn.shutdown().await?;
+                return Err(e);
+            }
+        };
+        let _ = out_conn.set_zero_linger();
+
+        let conn = connect.reply(Reply::Succeeded, Address::from(out_conn.local_addr()?)).await?;
+        self.tunnel(conn, out_conn, user_info, &host, hostname, conn_info).await
+    }
+
+    /// Serves a BIND request: listen on the egress ip, report the bound address to the client,
+    /// wait for the expected peer to connect, report the peer address and relay.
+    async fn handle_socks5_bind(
+        &self,
+        bind: Bind<bind::NeedFirstReply>,
+        addr: Address,
+        user_info: &UserInfo,
+        conn_info: &ConnInfo,
+    ) -> Result<()> {
+        let (host, hostname, port) = address_host(&addr);
+        info!("socks5 bind, expected peer host: {}, port: {}", host, port);
+        self.request_stat(RequestType::Socks5);
+
+        // check acl
+        if !self.acl.read().await.check(user_info, &host, &conn_info.local_ip) {
+            let mut conn = bind.reply(Reply::from(FailureReason::AclDenied), Address::unspecified()).await?;
+            conn.shutdown().await?;
+            error!("forbidden request from user: {:?}, host: {}", user_info, host);
+            return Err(Error::ForbiddenRequest);
+        }
+
+        // the peer is only checked by ip, clients usually do not know the port it will connect from
+        let expected_ip = match addr {
+            Address::SocketAddress(addr) => Some(addr.ip()),
+            Address::DomainAddress(domain, port) => match conn_info.handshake(resolve_host(&domain, port)).await {
+                Ok(addr) => Some(addr.ip()),
+                Err(e) => {
+                    let e = Error::upstream(e);
+                    let mut conn = bind.reply(failure_reply(&e), Address::unspecified()).await?;
+                    conn.shutdown().await?;
+                    return Err(e);
+                }
+            },
+        }
+        .filter(|ip| !ip.is_unspecified());
+
+        let listener = match bind_listener(conn_info.local_addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                let mut conn = bind.reply(Reply::from(FailureReason::Other), Address::unspecified()).await?;
+                conn.shutdown().await?;
+                return Err(e);
+            }
+        };
+        let bind = bind.reply(Reply::Succeeded, Address::from(listener.local_addr()?)).await?;
+
+        let accepted = tokio::time::timeout(BIND_ACCEPT_TIMEOUT, async {
+            loop {
+                let (peer, peer_addr) = listener.accept().await?;
+                match expected_ip {
+                    Some(ip) if ip != peer_addr.ip() => {
+                        info!("socks5 bind, drop unexpected peer: {}", peer_addr);
+                    }
+                    _ => return Ok::<_, Error>((peer, peer_addr)),
+                }
+            }
+        })
+        .await;
+        let (peer, peer_addr) = match accepted {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                let reply = bind.reply(Reply::from(FailureReason::Other), Address::unspecified()).await;
+                if let Ok(mut conn) = reply {
+                    conn.shutdown().await?;
+                }
+                return Err(e);
+            }
+            Err(e) => {
+                let e = Error::upstream(e);
+                let reply = bind.reply(failure_reply(&e), Address::unspecified()).await;
+                if let Ok(mut conn) = reply {
+                    conn.shutdown().await?;
+                }
+                return Err(e);
+            }
+        };
+        drop(listener);
+        let _ = peer.set_zero_linger();
+
+        let conn = bind.reply(Reply::Succeeded, Address::from(peer_addr)).await.map_err(|(e, _)| e)?;
+        self.relay(conn, peer, user_info, hostname, conn_info, None).await
+    }
+
+    /// Serves the Tor `RESOLVE` and `RESOLVE_PTR` extensions with the egress node's view of DNS.
+    ///
+    /// The answer is reported as the bound address: an ip for `RESOLVE`, a hostname for `RESOLVE_PTR`.
+    async fn handle_socks5_resolve(
+        &self,
+        resolve: Resolve,
+        addr: Address,
+        command: Command,
+        user_info: &UserInfo,
+        conn_info: &ConnInfo,
+    ) -> Result<()> {
+        let (host, _, _) = address_host(&addr);
+        info!("socks5 {:?}, host: {}", command, host);
+        self.request_stat(RequestType::Socks5);
+
+        // check acl
+        if !self.acl.read().await.check(user_info, &host, &conn_info.local_ip) {
+            resolve.reply(Reply::from(FailureReason::AclDenied), Address::unspecified()).await?;
+            error!("forbidden request from user: {:?}, host: {}", user_info, host);
+            return Err(Error::ForbiddenRequest);
+        }
+
+        let answer = match (command, addr) {
+            (Command::Resolve, Address::DomainAddress(domain, _)) => conn_info
+                .handshake(resolve_ip(&domain))
+                .await
+                .map(|ip| Address::from(SocketAddr::new(ip, 0))),
+            // nothing to resolve
+            (Command::Resolve, Address::SocketAddress(addr)) => Ok(Address::from(SocketAddr::new(addr.ip(), 0))),
+            (_, Address::SocketAddress(addr)) => conn_info
+                .handshake(resolve_ptr(addr.ip()))
+                .await
+                .map(|name| Address::DomainAddress(name, 0)),
+            (_, Address::DomainAddress(domain, _)) => Err(Error::from(format!("RESOLVE_PTR of a hostname: {}", domain))),
+        };
+        match answer {
+            Ok(addr) => Ok(resolve.reply(Reply::Succeeded, addr).await?),
+            Err(e) => {
+                let e = Error::upstream(e);
+                resolve.reply(failure_reply(&e), Address::unspecified()).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Serves a SOCKS4 or SOCKS4a request, only CONNECT is supported.
+    ///
+    /// The USERID is authenticated like SOCKS5 credentials, routing, acl and accounting are the same as for
+    /// a SOCKS5 CONNECT.
+    async fn handle_socks4(&self, mut conn: ProxyStream, conn_info: &ConnInfo) -> Result<()> {
+        let req = conn_info.handshake(socks4::Request::retrieve_from_async_stream(&mut conn)).await?;
+        let auth = ServerAuth::new(
+            self.auth.clone(),
+            conn_info.is_white,
+            conn_info.local_ip.clone(),
+            conn_info.remote_ip.clone(),
+        );
+        let user_info = match auth.check_socks4(&req.user_id).await {
+            Ok(user_info) => user_info,
+            Err(e) => {
+                socks4::Response::rejected().write_to_async_stream(&mut conn).await?;
+                conn.shutdown().await?;
+                return Err(e);
+            }
+        };
+
+        let (host, hostname, port) = address_host(&req.address);
+        info!("socks4 {:?}, host: {}, port: {}", req.command, host, port);
+        self.request_stat(RequestType::Socks4);
+
+        if req.command != socks4::Command::Connect {
+            socks4::Response::rejected().write_to_async_stream(&mut conn).await?;
+            conn.shutdown().await?;
+            return Err(Error::from(format!("unsupported socks4 command: {:?}", req.command)));
+        }
+
+        // check acl
+        if !self.acl.read().await.check(&user_info, &host, &conn_info.local_ip) {
+            socks4::Response::rejected().write_to_async_stream(&mut conn).await?;
+            conn.shutdown().await?;
+            error!("forbidden request from user: {:?}, host: {}", user_info, host);
+            return Err(Error::ForbiddenRequest);
+        }
+
+        let out_conn = match conn_info.handshake(connect_address(req.address, conn_info.local_addr)).await {
+            Ok(out_conn) => out_conn,
+            Err(e) => {
+                socks4::Response::rejected().write_to_async_stream(&mut conn).await?;
+                conn.shutdown().await?;
+                return Err(Error::upstream(e));
+            }
+        };
+        let _ = out_conn.set_zero_linger();
+
+        socks4::Response::from_socket_addr(socks4::Reply::Granted, out_conn.local_addr()?)
+            .write_to_async_stream(&mut conn)
+            .await?;
+        self.tunnel(conn, out_conn, &user_info, &host, hostname, conn_info).await
+    }
+
+    /// Serves HTTP proxy requests on a persistent client connection.
+    ///
+    /// Every request is authenticated, acl checked and accounted on its own and sent to its own target, the
+    /// connection to the target is reused as long as consecutive requests go to the same one. A CONNECT, or a
+    /// response switching protocols, turns the rest of the connection into a tunnel.
+    async fn handle_http(&self, conn: ProxyStream, conn_info: &ConnInfo) -> Result<()> {
+        let mut conn = BufReader::new(conn);
+        let mut conn_info = conn_info.clone();
+        let limits = HeadLimits {
+            max_size: self.config.http.max_head_size,
+            max_headers: self.config.http.max_headers,
+        };
+        let mut req = conn_info.handshake(parse_incomming_request(&mut conn, &limits, &self.error_page)).await?;
+        // the target of the previous request with the connection to it
+        let mut upstream: Option<((String, u16), BufReader<TcpStream>)> = None;
+        let mut failed_auth = 0;
+        loop {
+            // the proxy itself is asked, without credentials
+            if let Some(local) = req.protocol.local_request() {
+                let close = !req.protocol.keep_alive() || req.protocol.body_length() != BodyLength::Empty;
+                req.protocol.respond_local(&mut conn, &self.local_response(local, &conn_info), close).await?;
+                if close {
+                    return Ok(());
+                }
+                match self.next_http_request(&mut conn, &conn_info, &limits).await? {
+                    Some(next) => req = next,
+                    None => return Ok(()),
+                }
+                continue;
+            }
+            // the client may answer a 407 on the same connection, unless the body of the request was left unread
+            failed_auth += 1;
+            let close = !req.protocol.keep_alive()
+                || req.protocol.body_length() != BodyLength::Empty
+                || failed_auth >= self.config.http.max_auth_attempts;
+            let user_info = match self.http_check_user_auth(&mut conn, &req, &conn_info, close).await {
+                Ok(user_info) => user_info,
+                Err(Error::AuthFailed(e)) if !close => {
+                    info!("http auth failed, waiting for another attempt: {}", e);
+                    match self.next_http_request(&mut conn, &conn_info, &limits).await? {
+                        Some(next) => req = next,
+                        None => return Ok(()),
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            failed_auth = 0;
+            let method = req.protocol.get_method();
+            let target_host = req.protocol.get_host();
+            let host = host_name(&target_host);
+            info!("method: {:?}, host: {}", method, host);
+            self.request_stat(get_stat_request_type(&req.type_, &method));
+
+            // check acl
+            if !self.acl.read().await.check(&user_info, host, &conn_info.local_ip) {
+                req.protocol.respond_failure(&mut conn, FailureReason::AclDenied, &self.config.http.realm, &self.error_page).await?;
+                error!("forbidden request from user: {:?}, host: {}", user_info, host);
+                return Err(Error::ForbiddenRequest);
+            }
+
+            let port = if let Some(p) = target_host.port() {
+                p.as_u16()
+            } else {
+                method.default_port()
+            };
+            let target = (host.to_owned(), port);
+            // a tunnel gets a connection of its own, a kept-alive one may carry what is left of an exchange
+            let mut out_conn = match upstream.take() {
+                Some((previous, out_conn)) if previous == target && method != http_impl::RequestType::Connect => out_conn,
+                _ => {
+                    // resolve dns hostname and connect to target website
+                    let target_addr = Address::DomainAddress(target.0.clone(), port);
+                    match conn_info.handshake(connect_address(target_addr, conn_info.local_addr)).await {
+                        Ok(out_conn) => {
+                            let _ = out_conn.set_zero_linger();
+                            BufReader::new(out_conn)
+                        }
+                        Err(e) => {
+                            let e = Error::upstream(e);
+                            let reason = e.failure_reason().unwrap_or(FailureReason::Other);
+                            req.protocol.respond_failure(&mut conn, reason, &self.config.http.realm, &self.error_page).await?;
+                            return Err(e);
+                        }
+                    }
+                }
+            };
+
+            // a CONNECT is answered by the proxy, the target only gets what follows the request head
+            if method == http_impl::RequestType::Connect {
+                req.protocol.respond_command_result(&mut conn, true).await?;
+                return self.tunnel(conn, out_conn, &user_info, host, req.hostname(), &conn_info).await;
+            }
+
+            match self.forward_http(&mut conn, &mut out_conn, &req, &user_info, &conn_info).await? {
+                Forwarded::KeepAlive => {}
+                Forwarded::Close => return Ok(()),
+                Forwarded::SwitchedProtocols => {
+                    return self.relay(conn, out_conn, &user_info, req.hostname(), &conn_info, None).await;
+                }
+            }
+            upstream = Some((target, out_conn));
+
+            // only the first request is bounded by the handshake deadline
+            conn_info.handshake_deadline = None;
+            match self.next_http_request(&mut conn, &conn_info, &limits).await? {
+                Some(next) => req = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Reads the next request of a persistent connection, `None` once the client closed it or stayed idle.
+    async fn next_http_request(
+        &self,
+        conn: &mut BufReader<ProxyStream>,
+        conn_info: &ConnInfo,
+        limits: &HeadLimits,
+    ) -> Result<Option<IncomingRequest>> {
+        match conn_info.handshake(parse_incomming_request(conn, limits, &self.error_page)).await {
+            Ok(req) => Ok(Some(req)),
+            Err(Error::EmptyRequest) => Ok(None),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
+                info!("idle http connection closed, remote_ip: {}", conn_info.remote_ip);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends one request with its body to the target and the response with its body back to the client.
+    ///
+    /// The exchange is registered in the kill list, killing the user closes the client connection.
+    async fn forward_http(
+        &self,
+        conn: &mut BufReader<ProxyStream>,
+        out_conn: &mut BufReader<TcpStream>,
+        req: &IncomingRequest,
+        user_info: &UserInfo,
+        conn_info: &ConnInfo,
+    ) -> Result<Forwarded> {
+        let (shutdown_tx, mut shutdown_rx) = tokio::sync::broadcast::channel::<()>(1);
+        let id = &shutdown_tx as *const _ as usize;
+        self.conn_set.add(user_info.user_id, id, shutdown_tx.clone());
+        let traffic = self.traffic_fn(user_info, req.hostname(), conn_info);
+        let forwarding = self.http_forwarding(user_info, conn_info);
+
+        let exchange = async move {
+            let head = rewrite_request_head(&req.content, &forwarding)?;
+            out_conn.write_all(&head).await?;
+            let body = copy_body(conn, out_conn, req.protocol.body_length()).await?;
+            out_conn.flush().await?;
+            traffic(head.len() as u64 + body, true);
+
+            loop {
+                let head = read_response_head(out_conn)
+                    .await
+                    .and_then(|head| head.ok_or_else(|| Error::from("target closed before responding")));
+                // nothing of a final response was sent yet, the client is told why there is none
+                let head = match head {
+                    Ok(head) => head,
+                    Err(e) => {
+                        let e = Error::upstream(e);
+                        let reason = e.failure_reason().unwrap_or(FailureReason::Other);
+                        req.protocol.respond_failure(conn, reason, &self.config.http.realm, &self.error_page).await?;
+                        return Err(e);
+                    }
+                };
+                let res = ResponseHead::parse(&head)?;
+                conn.write_all(&head).await?;
+                if res.status == 101 {
+                    conn.flush().await?;
+                    traffic(head.len() as u64, false);
+                    return Ok(Forwarded::SwitchedProtocols);
+                }
+                if res.is_informational() {
+                    conn.flush().await?;
+                    traffic(head.len() as u64, false);
+                    continue;
+                }
+                let length = res.body_length(req.protocol.is_head_request())?;
+                let body = copy_body(out_conn, conn, length).await?;
+                conn.flush().await?;
+                traffic(head.len() as u64 + body, false);
+                return Ok(if req.protocol.keep_alive() && res.keep_alive() && length != BodyLength::UntilClose {
+                    Forwarded::KeepAlive
+                } else {
+                    Forwarded::Close
+                });
+            }
+        };
+        let res = tokio::select! {
+            res = exchange => res,
+            _ = shutdown_rx.recv() => {
+                info!("get shutdown signal, release the connection...");
+                Ok(Forwarded::Close)
+            }
+        };
+        self.conn_set.remove(user_info.user_id, id);
+        res
+    }
+}
+
+/// How the client connection continues after a forwarded request.
+enum Forwarded {
+    /// The next request follows on the same connection.
+    KeepAlive,
+    Close,
+    /// The target switched protocols, the rest of the connection is relayed as is.
+    SwitchedProtocols,
+}
+
 #[async_trait::async_trait]
 impl ServerBackend for DcServerBackend {
-    async fn handle_connection(&self, conn: TcpStream, remote_addr: SocketAddr) -> Result<()> {
+    async fn handle_connection(&self, conn: TcpStream, remote_addr: SocketAddr, tls: Option<TlsAcceptor>) -> Result<()> {
+        let accepted = Instant::now();
         let remote_ip = remote_addr.ip().to_string();
         info!("remote_ip: {:?}", remote_ip);
         let is_white = check_is_white(&self.auth, &remote_ip).await;
         info!("is white: {}", is_white);
-        let local_ip_addr = conn.local_addr()?.ip();
-        let local_ip = local_ip_addr.to_string();
-
-        let mut conn = Sock5Http::new(conn);
-        match conn.socks5_or_http().await.unwrap() {
-            Sock5OrHttp::Sock5 => {
-                let auth = ServerAuth::new(true, local_ip, remote_ip);
-                let auth = Arc::new(auth);
-                if let Err(e) = handle(auth, conn.stream.into_inner()).await {
-                    tracing::error!("handle connection error: {}", e);
-                }
-            }
-            Sock5OrHttp::Http => {
-                let mut conn = conn.stream.into_inner();
-                let mut req = parse_incomming_request(&mut conn, is_white).await?;
-                let user_info = http_check_user_auth(&mut conn, &mut req, &self.auth, &local_ip, &remote_ip, is_white).await?;
-                let method = req.protocol.get_method();
-                let target_host = req.protocol.get_host();
-                let host = target_host.host().unwrap_or_default();
-                info!("method: {:?}, host: {}", method, host);
-                self.request_stat(get_stat_request_type(&req.type_, &method));
-
-                // check acl
-                if !self.acl.read().await.check(&user_info, host, &local_ip) {
-                    req.protocol.respond_forbidden(&mut conn).await?;
-                    error!("forbidden request from user: {:?}, host: {}", user_info, host);
-                    return Err(Error::ForbiddenRequest);
-                }
+        let local = conn.local_addr()?;
+        let local_addr = local.ip();
+        let handshake = &self.config.handshake;
+        let mut conn_info = ConnInfo {
+            remote_addr,
+            remote_ip,
+            local_addr,
+            local_ip: local_addr.to_string(),
+            listener: Listener {
+                addr: local,
+                tls: tls.is_some(),
+            },
+            is_white,
+            // the protocol is not known yet, waiting for the first byte is bounded by the longer deadline
+            handshake_deadline: handshake_deadline(
+                accepted,
+                handshake.socks_timeout_secs.max(handshake.http_timeout_secs),
+            )
+            .filter(|_| handshake.socks_timeout_secs != 0 && handshake.http_timeout_secs != 0),
+        };
 
-                // resolve dns hostname
-                let port = if let Some(p) = target_host.port() {
-                    p.as_u16()
+        let res = match detect_protocol(conn, tls, &conn_info).await {
+            Ok((Sock5OrHttp::Sock4, conn)) => {
+                conn_info.handshake_deadline = handshake_deadline(accepted, handshake.socks_timeout_secs);
+                self.handle_socks4(conn, &conn_info).await
+            }
+            Ok((Sock5OrHttp::Sock5, conn)) => {
+                conn_info.handshake_deadline = handshake_deadline(accepted, handshake.socks_timeout_secs);
+                self.handle_socks5(conn, &conn_info).await
+            }
+            Ok((Sock5OrHttp::Http, conn)) => {
+                conn_info.handshake_deadline = handshake_deadline(accepted, handshake.http_timeout_secs);
+                if self.config.http.hyper_ports.contains(&local.port()) {
+                    hyper_http::serve(self, conn, &conn_info).await
                 } else {
-                    method.default_port()
-                };
-                let target_addr = resolve_host(host, port).await?;
-                let mut size = 0;
-                // connect to target website
-                let mut out_conn = connect_target(target_addr, local_ip_addr).await?;
-                let _ = out_conn.set_linger(Some(Duration::from_secs(0)));
-
-                let traffic_fn = get_traffic_fn(
-                    self.stat_sender.clone(),
-                    &user_info,
-                    req.hostname(),
-                    local_ip.clone(),
-                    remote_addr.ip().to_string(),
-                );
-                let mut header_filter = None;
-
-                // handle http request
-                let content = &req.content;
-                let new_content = remove_headers(content, "PROXY");
-                out_conn.write_all(&new_content).await?;
-                size += new_content.len();
-                header_filter = Some(Box::new(|content: &[u8]| remove_headers(content, "PROXY")) as FilterFn);
-
-                traffic_fn(size as u64, true);
-                let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel::<()>(10);
-                let id = &shutdown_tx as *const _ as usize;
-                self.conn_set.add(user_info.user_id, id, shutdown_tx.clone());
-
-                let (mut src_read, mut src_write) = conn.split();
-                let (mut dst_read, mut dst_write) = out_conn.split();
-
-                let c_traffic_fn = get_traffic_fn(
-                    self.stat_sender.clone(),
-                    &user_info,
-                    req.hostname(),
-                    local_ip.clone(),
-                    remote_addr.ip().to_string(),
-                );
-                let c_shutdown = shutdown_tx.subscribe();
-                let t1 = io_copy(&mut src_read, &mut dst_write, header_filter, c_traffic_fn, shutdown_rx, true);
-                let t2 = io_copy(&mut dst_read, &mut src_write, None, traffic_fn, c_shutdown, false);
-                let res = tokio::select! {
-                    res = t1 => {
-                        info!("io copy from src to dst finished");
-                        res
-                    }
-                    res = t2 => {
-                        info!("io copy from dst to src finished");
-                        res
-                    }
-                };
-                // let res = tokio::join!(t1, t2);
-                debug!("io copy finish: {:?}", res);
-                let _ = shutdown_tx.send(());
-                info!("remove shutdown tx from kill list...");
-                self.conn_set.remove(user_info.user_id, id);
+                    self.handle_http(conn, &conn_info).await
+                }
+            }
+            Ok((Sock5OrHttp::Http2, conn)) => {
+                conn_info.handshake_deadline = handshake_deadline(accepted, handshake.http_timeout_secs);
+                hyper_http::serve_http2(self, conn, &conn_info).await
             }
+            Err(e) => Err(e),
+        };
+        let timed_out = match &res {
+            Err(Error::HandshakeTimeout) => true,
+            Err(Error::Upstream(_, e)) => matches!(**e, Error::HandshakeTimeout),
+            _ => false,
+        };
+        if timed_out {
+            warn!("handshake timeout, remote_ip: {}", conn_info.remote_ip);
+            self.handshake_timeout_stat();
         }
-
-        // if !self.acl.read().await.check(&user_info, host, &local_ip) {
-        //     req.protocol.respond_forbidden(&mut conn).await?;
-        //     error!(
-        //         "forbidden request from user: {:?}, host: {}",
-        //         user_info, host
-        //     );
-        //     return Err(RgError::ForbiddenRequest);
-        // }
-        //
-        // info!("is white: {}", is_white);
-        // let server_auth = Arc::new(ServerAuth::new(true));
-        // if let Err(e) = handle(server_auth, conn).await {
-        //     tracing::error!("handle connection error: {}", e);
-        // }
-        // let mut req = parse_incomming_request(&mut conn, is_white).await?;
-        // info!("new request from: {:?}", remote_addr);
-        // // get requested local ip address
-        // let local_ip_addr = conn.local_addr()?.ip();
-        // let local_ip = local_ip_addr.to_string();
-        // info!("local ip: {}", local_ip);
-        // let user_info = check_user_auth(
-        //     &mut conn, &mut req, &self.auth, &local_ip, &remote_ip, is_white,
-        // )
-        // .await?;
-        //
-        // let method = req.protocol.get_method();
-        // let target_host = req.protocol.get_host();
-        // let host = target_host.host().unwrap_or_default();
-        // info!("method: {:?}, host: {}", method, host);
-        // self.request_stat(get_stat_request_type(&req.type_, &method));
-        //
-        // // check acl
-        // if !self.acl.read().await.check(&user_info, host, &local_ip) {
-        //     req.protocol.respond_forbidden(&mut conn).await?;
-        //     error!(
-        //         "forbidden request from user: {:?}, host: {}",
-        //         user_info, host
-        //     );
-        //     return Err(RgError::ForbiddenRequest);
-        // }
-        //
-        // // resolve dns hostname
-        // let port = if let Some(p) = target_host.port() {
-        //     p.as_u16()
-        // } else {
-        //     method.default_port()
-        // };
-        // let target_addr = resolve_host(host, port).await?;
-        // let mut size = 0;
-        // // connect to target website
-        // let mut out_conn = connect_target(target_addr, local_ip_addr).await?;
-        // let _ = out_conn.set_linger(Some(Duration::from_secs(0)));
-        //
-        // let traffic_fn = get_traffic_fn(
-        //     self.stat_sender.clone(),
-        //     &user_info,
-        //     req.hostname(),
-        //     local_ip.clone(),
-        //     remote_addr.ip().to_string(),
-        // );
-        // let mut header_filter = None;
-        //
-        // if method == RequestType::Connect || req.type_ == ProtocolType::Socks5 {
-        //     req.protocol.respond_command_result(&mut conn, true).await?;
-        // } else {
-        //     // handle http request
-        //     let content = &req.content;
-        //     let new_content = remove_headers(content, "PROXY");
-        //     out_conn.write_all(&new_content).await?;
-        //     size += new_content.len();
-        //     header_filter =
-        //         Some(Box::new(|content: &[u8]| remove_headers(content, "PROXY")) as FilterFn);
-        // }
-        // traffic_fn(size as u64, true);
-        // let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel::<()>(10);
-        // let id = &shutdown_tx as *const _ as usize;
-        // self.conn_set
-        //     .add(user_info.user_id, id, shutdown_tx.clone());
-        //
-        // let (mut src_read, mut src_write) = conn.split();
-        // let (mut dst_read, mut dst_write) = out_conn.split();
-        //
-        // let c_traffic_fn = get_traffic_fn(
-        //     self.stat_sender.clone(),
-        //     &user_info,
-        //     req.hostname(),
-        //     local_ip.clone(),
-        //     remote_addr.ip().to_string(),
-        // );
-        // let c_shutdown = shutdown_tx.subscribe();
-        // let t1 = io_copy(
-        //     &mut src_read,
-        //     &mut dst_write,
-        //     header_filter,
-        //     c_traffic_fn,
-        //     shutdown_rx,
-        //     true,
-        // );
-        // let t2 = io_copy(
-        //     &mut dst_read,
-        //     &mut src_write,
-        //     None,
-        //     traffic_fn,
-        //     c_shutdown,
-        //     false,
-        // );
-        // let res = tokio::select! {
-        //     res = t1 => {
-        //         info!("io copy from src to dst finished");
-        //         res
-        //     }
-        //     res = t2 => {
-        //         info!("io copy from dst to src finished");
-        //         res
-        //     }
-        // };
-        // // let res = tokio::join!(t1, t2);
-        // debug!("io copy finish: {:?}", res);
-        // let _ = shutdown_tx.send(());
-        // info!("remove shutdown tx from kill list...");
-        // self.conn_set.remove(user_info.user_id, id);
-        Ok(())
+        if let Some(reason) = res.as_ref().err().and_then(Error::failure_reason) {
+            self.failure_stat(reason);
+        }
+        res
     }
 
     async fn init_kill_user_connection(&self) -> Sender<UserId> {
@@ -306,6 +609,45 @@ impl ServerBackend for DcServerBackend {
         tx
     }
 }
+/// Terminates TLS if the listener serves it, then detects the protocol from the first plaintext byte.
+async fn detect_protocol(conn: TcpStream, tls: Option<TlsAcceptor>, conn_info: &ConnInfo) -> Result<(Sock5OrHttp, ProxyStream)> {
+    let conn = match tls {
+        Some(tls) => ProxyStream::from(conn_info.handshake(tls.accept(conn)).await?),
+        None => ProxyStream::from(conn),
+    };
+    let mut conn = Sock5Http::new(conn);
+    let protocol = conn_info.handshake(conn.socks5_or_http()).await?;
+    Ok((protocol, conn.stream.into_inner()))
+}
+
+/// The SOCKS5 reply for a failed request.
+fn failure_reply(e: &Error) -> Reply {
+    e.failure_reason().map_or(Reply::GeneralFailure, Reply::from)
+}
+
+/// The deadline `secs` after `accepted`, `None` if `secs` is 0.
+fn handshake_deadline(accepted: Instant, secs: u64) -> Option<Instant> {
+    (secs != 0).then(|| accepted + Duration::from_secs(secs))
+}
+
+fn bind_listener(local_ip: IpAddr) -> Result<TcpListener> {
+    let socket = match local_ip {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(local_ip, 0))?;
+    Ok(socket.listen(1)?)
+}
+
+/// Resolves `addr` if it is a domain and connects to it from the egress ip.
+pub(super) async fn connect_address(addr: Address, local_ip: IpAddr) -> Result<TcpStream> {
+    let target_addr = match addr {
+        Address::SocketAddress(addr) => addr,
+        Address::DomainAddress(domain, port) => resolve_host(&domain, port).await?,
+    };
+    connect_target(target_addr, local_ip).await
+}
+
 async fn connect_target(addr: SocketAddr, local_ip: IpAddr) -> Result<TcpStream> {
     let mut retry = 0;
     loop {
@@ -323,7 +665,18 @@ async fn connect_target(addr: SocketAddr, local_ip: IpAddr) -> Result<TcpStream>
 }
 
 async fn _connect_target(addr: SocketAddr, local_ip: IpAddr) -> Result<TcpStream> {
-    let socket = TcpSocket::new_v4()?;
+    let socket = match (addr, local_ip) {
+        (SocketAddr::V4(_), IpAddr::V4(_)) => TcpSocket::new_v4()?,
+        (SocketAddr::V6(_), IpAddr::V6(_)) => TcpSocket::new_v6()?,
+        // the egress ip cannot reach the other address family
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NetworkUnreachable,
+                format!("no route from {} to {}", local_ip, addr),
+            )
+            .into())
+        }
+    };
     let mut sock_addr = socket.local_addr()?;
     sock_addr.set_ip(local_ip);
     sock_addr.set_port(0);
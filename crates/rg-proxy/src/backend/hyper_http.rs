@@ -0,0 +1,180 @@
+//! The hyper HTTP front end, serving the HTTP/1 clients of the listeners in `http.hyper_ports` and every HTTP/2
+//! client.
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use error::{Error, FailureReason, Result};
+use http_impl::{
+    digest::Credentials,
+    format_hostname,
+    framing::HeadLimits,
+    local::{LocalRequest, LocalResponse},
+    proxy::{self, Authenticated, ProxyHandler, ServeOptions},
+    rewrite::Forwarding,
+    ProtocolType, RequestType,
+};
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use rg_common::{user_auth::UserInfo, UserId};
+use socks5_http::ProxyStream;
+use socks5_protocol::Address;
+use tokio::{
+    net::TcpStream,
+    sync::{broadcast, Notify},
+};
+use tracing::info;
+
+use super::{dc_server::connect_address, get_stat_request_type, CommonBackend, ConnInfo};
+
+/// Serves one client connection with the auth and acl centers, egress binding, accounting and kill list of
+/// the backend.
+///
+/// Killing a user aborts its exchanges and tunnels on the connection, the exchanges of other users go on.
+struct HyperHandler {
+    backend: CommonBackend,
+    conn_info: ConnInfo,
+    /// the kill signal of every user of the connection, boxed for a stable id in the kill list
+    kills: Mutex<HashMap<UserId, Box<broadcast::Sender<()>>>>,
+    /// notified once a request is authenticated, which ends the handshake
+    authenticated: Notify,
+}
+
+/// Serves the requests of an HTTP/1 client until it closes the connection.
+pub(super) async fn serve(backend: &CommonBackend, conn: ProxyStream, conn_info: &ConnInfo) -> Result<()> {
+    let handler = Arc::new(HyperHandler::new(backend, conn_info));
+    let res = handler.serve(proxy::serve_connection(conn, handler.clone(), &serve_options(backend))).await;
+    handler.unregister();
+    res
+}
+
+/// Serves the streams of an HTTP/2 client until it closes the connection.
+pub(super) async fn serve_http2(backend: &CommonBackend, conn: ProxyStream, conn_info: &ConnInfo) -> Result<()> {
+    info!("http2 connection, remote_ip: {}", conn_info.remote_ip);
+    let handler = Arc::new(HyperHandler::new(backend, conn_info));
+    let res = handler.serve(proxy::serve_http2_connection(conn, handler.clone(), &serve_options(backend))).await;
+    handler.unregister();
+    res
+}
+
+impl HyperHandler {
+    fn new(backend: &CommonBackend, conn_info: &ConnInfo) -> Self {
+        Self {
+            backend: backend.clone(),
+            conn_info: conn_info.clone(),
+            kills: Mutex::new(HashMap::new()),
+            authenticated: Notify::new(),
+        }
+    }
+
+    /// Drives a connection, failing with [`Error::HandshakeTimeout`] if no request is authenticated before the
+    /// handshake deadline.
+    async fn serve(&self, conn: impl Future<Output = Result<()>>) -> Result<()> {
+        tokio::pin!(conn);
+        let handshake = async {
+            tokio::select! {
+                res = &mut conn => Ok::<_, Error>(Some(res)),
+                _ = self.authenticated.notified() => Ok(None),
+            }
+        };
+        match self.conn_info.handshake(handshake).await? {
+            Some(res) => res,
+            None => conn.await,
+        }
+    }
+
+    /// Removes the connection from the kill list of every user it served.
+    fn unregister(&self) {
+        for (user_id, kill) in self.kills.lock().unwrap().drain() {
+            self.backend.conn_set.remove(user_id, &*kill as *const _ as usize);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ProxyHandler for HyperHandler {
+    type User = UserInfo;
+    type Upstream = TcpStream;
+
+    async fn authenticate(
+        &self,
+        basic: Option<(String, String)>,
+        digest: Option<&Credentials>,
+    ) -> Result<Authenticated<UserInfo>> {
+        let res = self.backend.http_authenticate(basic, digest, &self.conn_info).await;
+        if let Ok(Authenticated::User(_)) = res {
+            self.authenticated.notify_one();
+        }
+        res
+    }
+
+    async fn allow(&self, user: &UserInfo, host: &str) -> bool {
+        self.backend.acl.read().await.check(user, host, &self.conn_info.local_ip)
+    }
+
+    async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let conn = connect_address(Address::DomainAddress(host.to_owned(), port), self.conn_info.local_addr).await?;
+        let _ = conn.set_zero_linger();
+        Ok(conn)
+    }
+
+    async fn tunnel(&self, user: UserInfo, host: String, client: TokioIo<Upgraded>, upstream: TcpStream) -> Result<()> {
+        let hostname = format_hostname(&host);
+        self.backend.tunnel(client, upstream, &user, &host, hostname, &self.conn_info).await
+    }
+
+    async fn relay_switched(
+        &self,
+        user: UserInfo,
+        hostname: String,
+        client: TokioIo<Upgraded>,
+        upstream: TokioIo<Upgraded>,
+    ) -> Result<()> {
+        self.backend.relay(client, upstream, &user, hostname, &self.conn_info, None).await
+    }
+
+    fn forwarding(&self, user: &UserInfo) -> Forwarding {
+        self.backend.http_forwarding(user, &self.conn_info)
+    }
+
+    fn account(&self, user: &UserInfo, hostname: &str, bytes: u64, is_upload: bool) {
+        self.backend.traffic_stat(user, hostname, &self.conn_info, bytes, is_upload);
+    }
+
+    fn count_request(&self, method: RequestType) {
+        self.backend.request_stat(get_stat_request_type(&ProtocolType::Http, &method));
+    }
+
+    fn count_failure(&self, reason: FailureReason) {
+        self.backend.failure_stat(reason);
+    }
+
+    fn local(&self, request: LocalRequest) -> LocalResponse {
+        self.backend.local_response(request, &self.conn_info)
+    }
+
+    fn kill_signal(&self, user: &UserInfo) -> broadcast::Receiver<()> {
+        let mut kills = self.kills.lock().unwrap();
+        let kill = kills.entry(user.user_id).or_insert_with(|| Box::new(broadcast::channel(1).0));
+        // added again every time, killing the user removes it from the list
+        self.backend.conn_set.add(user.user_id, &**kill as *const _ as usize, (**kill).clone());
+        kill.subscribe()
+    }
+}
+
+fn serve_options(backend: &CommonBackend) -> ServeOptions {
+    let config = &backend.config;
+    ServeOptions {
+        limits: HeadLimits {
+            max_size: config.http.max_head_size,
+            max_headers: config.http.max_headers,
+        },
+        header_read_timeout: (config.handshake.http_timeout_secs != 0)
+            .then(|| Duration::from_secs(config.handshake.http_timeout_secs)),
+        max_auth_attempts: config.http.max_auth_attempts,
+        error_page: backend.error_page.clone(),
+    }
+}
@@ -1,37 +1,102 @@
 pub mod dc_server;
+mod hyper_http;
+#[cfg(test)]
+mod test;
 
-use crate::{conn_set::ConnStat, FilterFn, TrafficFn};
+use crate::{
+    conn_set::ConnStat,
+    get_traffic_fn,
+    sni::{self, ClientHello},
+    tls::TlsAcceptor,
+    FilterFn, TrafficFn,
+};
 use async_channel::Sender;
-use tracing::{error, info};
+use config::ProxyConfig;
+use tracing::{debug, error, info};
 use rg_acl::auth::dc_auth::IP;
 use rg_acl::{AclCenter, AuthCenter};
-use rg_common::{error::RgError, user_auth::UserInfo, UserId};
+use rg_common::{backend::BACKEND_STATUS, user_auth::UserInfo, TrafficInfo, UserId};
 use rg_stat::{RequestType, StatEvent};
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
     sync::{broadcast::Receiver, mpsc::UnboundedSender},
+    time::Instant,
+};
+use error::{Error, FailureReason, Result};
+use http_impl::{
+    digest::{Credentials, DigestAuth, Verdict},
+    error_page::ErrorPage,
+    local::{Listener, LocalEndpoints, LocalRequest, LocalResponse},
+    format_hostname, https,
+    proxy::Authenticated,
+    rewrite::Forwarding,
+    ClientStream, IncomingRequest, ProtocolType,
 };
-use error::{Error, Result};
-use http_impl::{IncomingRequest, ProtocolType};
+use socks5_protocol::Address;
 
 const DEFAULT_USERNAME: &str = "iPOasIsAdmInT0ken";
 const DEFAULT_PASSOWRD: &str = "W0rstPassw0rdEveR";
 
 #[async_trait::async_trait]
 pub trait ServerBackend {
-    async fn handle_connection(&self, conn: TcpStream, remote_addr: SocketAddr) -> Result<()>;
+    /// Serves an accepted connection, terminating TLS with `tls` first if given.
+    async fn handle_connection(&self, conn: TcpStream, remote_addr: SocketAddr, tls: Option<TlsAcceptor>) -> Result<()>;
 
     async fn init_kill_user_connection(&self) -> Sender<UserId>;
 }
 
+/// Addresses of an accepted client connection, shared by every protocol handler.
+#[derive(Debug, Clone)]
+pub struct ConnInfo {
+    pub remote_addr: SocketAddr,
+    pub remote_ip: String,
+    /// The local address the client connected to, outgoing connections are bound to it.
+    pub local_addr: IpAddr,
+    pub local_ip: String,
+    /// The listener the client connected to.
+    pub listener: Listener,
+    pub is_white: bool,
+    /// The client has to finish its handshake before this instant, `None` if there is no deadline.
+    pub handshake_deadline: Option<Instant>,
+}
+
+impl ConnInfo {
+    /// Runs a handshake step, failing with [`Error::HandshakeTimeout`] once the handshake deadline has passed.
+    pub(crate) async fn handshake<F, T, E>(&self, step: F) -> Result<T>
+    where
+        F: Future<Output = std::result::Result<T, E>>,
+        Error: From<E>,
+    {
+        match self.handshake_deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, step).await {
+                Ok(res) => res.map_err(Error::from),
+                Err(_) => Err(Error::HandshakeTimeout),
+            },
+            None => step.await.map_err(Error::from),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct CommonBackend {
     pub auth: AuthCenter,
-    acl: AclCenter,
-    conn_set: Arc<ConnStat<tokio::sync::broadcast::Sender<()>>>,
+    pub(crate) acl: AclCenter,
+    pub(crate) conn_set: Arc<ConnStat<tokio::sync::broadcast::Sender<()>>>,
     stat_sender: UnboundedSender<StatEvent>,
+    pub config: Arc<ProxyConfig>,
+    /// issues and checks the nonces of Digest proxy authentication, `None` if it is disabled
+    digest: Option<DigestAuth>,
+    /// renders the body of the HTTP error responses
+    pub(crate) error_page: ErrorPage,
+    /// answers the requests for the proxy itself
+    local_endpoints: LocalEndpoints,
 }
 
 impl CommonBackend {
@@ -39,15 +104,33 @@ impl CommonBackend {
         auth: AuthCenter,
         acl: AclCenter,
         stat_sender: UnboundedSender<StatEvent>,
+        config: ProxyConfig,
     ) -> CommonBackend {
+        let digest = config.http.digest_auth.then(|| {
+            let lifetime = std::time::Duration::from_secs(config.http.digest_nonce_lifetime_secs);
+            DigestAuth::new(config.http.realm.clone(), rand::random(), lifetime)
+        });
+        let error_page = config.http.error_template.as_deref().map(ErrorPage::new).unwrap_or_default();
+        let local_endpoints = LocalEndpoints::new(&config.http.pac_bypass);
         CommonBackend {
             auth,
             acl,
             stat_sender,
             conn_set: Arc::new(ConnStat::new()),
+            config: Arc::new(config),
+            digest,
+            error_page,
+            local_endpoints,
         }
     }
 
+    /// Answers a request for the proxy itself on the listener of `conn_info`.
+    pub(crate) fn local_response(&self, request: LocalRequest, conn_info: &ConnInfo) -> LocalResponse {
+        info!("local request {:?}, remote_ip: {}", request, conn_info.remote_ip);
+        let backend_up = BACKEND_STATUS.load(Ordering::SeqCst);
+        self.local_endpoints.respond(request, &conn_info.listener, backend_up)
+    }
+
     pub fn request_stat(&self, r_type: RequestType) {
         if let Err(e) = self.stat_sender.send(StatEvent::Request(r_type)) {
             error!("send request stat error: {}", e);
@@ -59,6 +142,321 @@ impl CommonBackend {
             error!("send connection stat error: {}", e);
         }
     }
+
+    pub fn failure_stat(&self, reason: FailureReason) {
+        if let Err(e) = self.stat_sender.send(StatEvent::Failure(reason)) {
+            error!("send failure stat error: {}", e);
+        }
+    }
+
+    pub fn handshake_timeout_stat(&self) {
+        if let Err(e) = self.stat_sender.send(StatEvent::HandshakeTimeout) {
+            error!("send handshake timeout stat error: {}", e);
+        }
+    }
+
+    /// Relay data between the client and the target until one side closes or the user gets killed.
+    ///
+    /// The connection is registered in the kill list for the whole relay and all traffic is accounted to `user_info`.
+    pub(crate) async fn relay<C, T>(
+        &self,
+        client: C,
+        target: T,
+        user_info: &UserInfo,
+        hostname: String,
+        conn_info: &ConnInfo,
+        upload_filter: Option<FilterFn>,
+    ) -> Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send,
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel::<()>(10);
+        let id = &shutdown_tx as *const _ as usize;
+        self.conn_set.add(user_info.user_id, id, shutdown_tx.clone());
+
+        let (mut src_read, mut src_write) = tokio::io::split(client);
+        let (mut dst_read, mut dst_write) = tokio::io::split(target);
+
+        let upload_traffic_fn = self.traffic_fn(user_info, hostname.clone(), conn_info);
+        let download_traffic_fn = self.traffic_fn(user_info, hostname, conn_info);
+        let c_shutdown = shutdown_tx.subscribe();
+        let t1 = io_copy(&mut src_read, &mut dst_write, upload_filter, upload_traffic_fn, shutdown_rx, true);
+        let t2 = io_copy(&mut dst_read, &mut src_write, None, download_traffic_fn, c_shutdown, false);
+        let res = tokio::select! {
+            res = t1 => {
+                info!("io copy from src to dst finished");
+                res
+            }
+            res = t2 => {
+                info!("io copy from dst to src finished");
+                res
+            }
+        };
+        debug!("io copy finish: {:?}", res);
+        let _ = shutdown_tx.send(());
+        info!("remove shutdown tx from kill list...");
+        self.conn_set.remove(user_info.user_id, id);
+        res
+    }
+
+    /// Relays a CONNECT tunnel to `host` like [`relay`](Self::relay).
+    ///
+    /// If sniffing is enabled, the server name of the TLS ClientHello the client opens the tunnel with is acl
+    /// checked and replaces `hostname` in the traffic statistics.
+    pub(crate) async fn tunnel<C, T>(
+        &self,
+        mut client: C,
+        mut target: T,
+        user_info: &UserInfo,
+        host: &str,
+        mut hostname: String,
+        conn_info: &ConnInfo,
+    ) -> Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send,
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let config = &self.config.sni;
+        if config.sniff {
+            let (hello, server_name) = self
+                .sniff_relaying_target(&mut client, &mut target, user_info, &hostname, conn_info)
+                .await?;
+            if let Some(server_name) = server_name {
+                info!("tunnel to {} for server name {}", host, server_name);
+                if !self.acl.read().await.check(user_info, &server_name, &conn_info.local_ip) {
+                    error!("forbidden server name from user: {:?}, host: {}", user_info, server_name);
+                    return Err(Error::ForbiddenRequest);
+                }
+                let is_ip = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok();
+                if config.block_mismatch && !is_ip && !server_name.eq_ignore_ascii_case(host) {
+                    error!("server name {} does not match host {}, user: {:?}", server_name, host, user_info);
+                    return Err(Error::ForbiddenRequest);
+                }
+                hostname = format_hostname(&server_name);
+            }
+            if !hello.is_empty() {
+                target.write_all(&hello).await?;
+                self.traffic_stat(user_info, &hostname, conn_info, hello.len() as u64, true);
+            }
+        }
+        self.relay(client, target, user_info, hostname, conn_info, None).await
+    }
+
+    /// Sniffs the server name of the tunnel with [`sniff_server_name`], relaying what the target sends meanwhile so
+    /// a server speaking first is not held back.
+    async fn sniff_relaying_target<C, T>(
+        &self,
+        client: &mut C,
+        target: &mut T,
+        user_info: &UserInfo,
+        hostname: &str,
+        conn_info: &ConnInfo,
+    ) -> Result<(Vec<u8>, Option<String>)>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send,
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let timeout = Duration::from_millis(self.config.sni.peek_timeout_ms);
+        let sniff = sniff_server_name(&mut client_read, timeout);
+        tokio::pin!(sniff);
+        let mut buf = [0u8; 4096];
+        let mut target_open = true;
+        loop {
+            // only the read is raced with the sniff, a chunk read is always written out whole
+            tokio::select! {
+                res = &mut sniff => return res,
+                n = target.read(&mut buf), if target_open => {
+                    let n = n?;
+                    if n == 0 {
+                        target_open = false;
+                        continue;
+                    }
+                    client_write.write_all(&buf[..n]).await?;
+                    client_write.flush().await?;
+                    self.traffic_stat(user_info, hostname, conn_info, n as u64, false);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn traffic_stat(&self, user_info: &UserInfo, hostname: &str, conn_info: &ConnInfo, traffic: u64, is_upload: bool) {
+        let msg = TrafficInfo::new(
+            user_info.user_id,
+            user_info.user_plan_id,
+            hostname,
+            traffic,
+            is_upload,
+            &conn_info.remote_ip,
+            &conn_info.local_ip,
+        );
+        if let Err(e) = self.stat_sender.send(StatEvent::Traffic(msg)) {
+            error!("send traffic stat error: {}", e);
+        }
+    }
+
+    pub(crate) fn traffic_fn(&self, user_info: &UserInfo, hostname: String, conn_info: &ConnInfo) -> TrafficFn {
+        get_traffic_fn(
+            self.stat_sender.clone(),
+            user_info,
+            hostname,
+            conn_info.local_ip.clone(),
+            conn_info.remote_ip.clone(),
+        )
+    }
+
+    /// Authenticates the user of an HTTP request by its Basic or Digest credentials.
+    ///
+    /// A request without valid credentials is answered with a 407 offering every enabled scheme, which closes the
+    /// connection if `close`, and fails with [`Error::AuthFailed`]. The request of a disabled user is answered with
+    /// a 403 and fails with [`Error::UserDisabled`].
+    pub(crate) async fn http_check_user_auth(
+        &self,
+        conn: &mut dyn ClientStream,
+        req: &IncomingRequest,
+        conn_info: &ConnInfo,
+        close: bool,
+    ) -> Result<UserInfo> {
+        let basic = req.protocol.get_user_password();
+        let digest = req.protocol.get_digest_credentials();
+        let username = digest.map(|c| c.username.clone()).or_else(|| basic.as_ref().map(|(u, _)| u.clone()));
+        let authenticated = match self.http_authenticate(basic, digest, conn_info).await {
+            Err(e @ Error::UserDisabled(_)) => {
+                req.protocol.respond_failure(conn, FailureReason::UserDisabled, &self.config.http.realm, &self.error_page).await?;
+                return Err(e);
+            }
+            res => res?,
+        };
+        match authenticated {
+            Authenticated::User(user_info) => Ok(user_info),
+            Authenticated::Challenge(challenges) => {
+                req.protocol.respond_authorization_required(conn, &challenges, close, &self.error_page).await?;
+                Err(Error::AuthFailed(format!(
+                    "ip: {}, username: {}",
+                    conn_info.local_ip,
+                    username.unwrap_or_default()
+                )))
+            }
+        }
+    }
+
+    /// Checks the Basic or Digest credentials of an HTTP request, failing with the challenges of every enabled
+    /// scheme, or with [`Error::UserDisabled`] if they name a disabled user.
+    pub(crate) async fn http_authenticate(
+        &self,
+        basic: Option<(String, String)>,
+        digest: Option<&Credentials>,
+        conn_info: &ConnInfo,
+    ) -> Result<Authenticated<UserInfo>> {
+        let http = &self.config.http;
+        let username = digest
+            .filter(|_| self.digest.is_some())
+            .map(|c| c.username.clone())
+            .or_else(|| basic.as_ref().filter(|_| http.basic_auth).map(|(u, _)| u.clone()));
+        let mut stale = false;
+        let user = match (&self.digest, digest) {
+            (Some(digest), Some(credentials)) if !conn_info.is_white => {
+                let mut user = None;
+                for (password, user_info) in hmac_user_secrets(&self.auth, &conn_info.local_ip, &credentials.username).await {
+                    match digest.verify(credentials, &password) {
+                        Verdict::Valid => {
+                            user = Some(user_info);
+                            break;
+                        }
+                        Verdict::Stale => stale = true,
+                        Verdict::Invalid => {}
+                    }
+                }
+                user
+            }
+            _ => {
+                // clients on the white list authenticate by their ip
+                let credentials = if conn_info.is_white {
+                    Some((String::new(), String::new()))
+                } else if http.basic_auth {
+                    basic
+                } else {
+                    None
+                };
+                match credentials {
+                    Some((username, password)) => {
+                        let (valid, user_info) = check_user_auth(
+                            &self.auth,
+                            &conn_info.local_ip,
+                            &conn_info.remote_ip,
+                            conn_info.is_white,
+                            &username,
+                            &password,
+                        )
+                        .await?;
+                        valid.then_some(user_info)
+                    }
+                    None => None,
+                }
+            }
+        };
+        info!("valid: {:?}", user.is_some());
+        if let Some(user_info) = user {
+            return Ok(Authenticated::User(user_info));
+        }
+        if let Some(username) = username {
+            if self.auth.read().await.is_disabled(&username, &conn_info.local_ip) {
+                return Err(Error::UserDisabled(format!("ip: {}, username: {}", conn_info.local_ip, username)));
+            }
+        }
+
+        let mut challenges = Vec::new();
+        if let Some(digest) = &self.digest {
+            challenges.extend(digest.challenges(stale));
+        }
+        if http.basic_auth {
+            challenges.push(https::basic_challenge(&http.realm));
+        }
+        Ok(Authenticated::Challenge(challenges))
+    }
+
+    /// The headers added to the forwarded HTTP requests of `user_info`.
+    pub(crate) fn http_forwarding(&self, user_info: &UserInfo, conn_info: &ConnInfo) -> Forwarding {
+        let headers = self.config.http.forwarded_headers(user_info.user_plan_id);
+        Forwarding {
+            via: headers.via.then(|| self.config.http.via_pseudonym.clone()),
+            forwarded_for: headers.x_forwarded_for.then_some(conn_info.remote_addr.ip()),
+        }
+    }
+}
+
+/// Splits a socks5 address into the host checked by the acl, the hostname reported in traffic stats and the port.
+pub(crate) fn address_host(addr: &Address) -> (String, String, u16) {
+    match addr {
+        Address::DomainAddress(domain, port) => (domain.clone(), format_hostname(domain), *port),
+        Address::SocketAddress(addr) => (addr.ip().to_string(), addr.ip().to_string(), addr.port()),
+    }
+}
+
+/// Reads what the client sends first until it makes a whole ClientHello, is not one or `timeout` passes.
+///
+/// Returns the bytes read, which the target still has to get, with the server name found.
+async fn sniff_server_name<C: AsyncRead + Unpin>(client: &mut C, timeout: Duration) -> Result<(Vec<u8>, Option<String>)> {
+    let mut data = Vec::new();
+    let sniff = async {
+        let mut buf = [0u8; 4096];
+        loop {
+            match sni::parse(&data) {
+                ClientHello::Complete(server_name) => return Ok::<_, Error>(server_name),
+                ClientHello::NotTls => return Ok(None),
+                ClientHello::Incomplete if data.len() >= sni::MAX_HELLO_SIZE => return Ok(None),
+                ClientHello::Incomplete => {}
+            }
+            let n = client.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+    };
+    let server_name = tokio::time::timeout(timeout, sniff).await.unwrap_or(Ok(None))?;
+    Ok((data, server_name))
 }
 
 async fn check_is_white(auth_center: &AuthCenter, remote_ip: &str) -> bool {
@@ -87,7 +485,7 @@ pub async fn check_user_auth(
         return Ok((true, UserInfo::default()));
     }
     // check auth
-    let (valid, user_info) = auth.check_auth(&username, &password, addr, remote_ip, is_white);
+    let (valid, user_info) = auth.check_auth(username, password, addr, remote_ip, is_white);
     // info!("valid: {:?}", valid);
     // if !valid {
     //     req.protocol.respond_auth_result(conn, false, is_white).await?;
@@ -99,42 +497,16 @@ pub async fn check_user_auth(
     Ok((valid, user_info))
 }
 
-async fn http_check_user_auth(
-    conn: &mut TcpStream,
-    req: &mut IncomingRequest,
-    auth_center: &AuthCenter,
-    addr: &str,
-    remote_ip: &str,
-    is_white: bool,
-) -> Result<UserInfo> {
-    info!("addr: {:?}", addr);
-    let (username, password) = if is_white {
-        (String::new(), String::new())
-    } else {
-        match req.protocol.get_user_password() {
-            Some((u, p)) => (u, p),
-            None => {
-                (String::new(), String::new())
-                // req.protocol.respond_authorization_required(conn).await?;
-                // return Err(RgError::NoAuthFound);
-            }
-        }
-    };
+/// Looks up the secrets `username` may sign HMAC tokens with, which are its passwords, each with the user it
+/// authenticates as.
+///
+/// This resolves to the same users as [`check_user_auth`] does for password authentication.
+pub async fn hmac_user_secrets(auth_center: &AuthCenter, addr: &str, username: &str) -> Vec<(String, UserInfo)> {
     let auth = auth_center.read().await;
-    if !auth.in_stock(addr) && DEFAULT_USERNAME.eq(&username) && DEFAULT_PASSOWRD.eq(&password) {
-        return Ok(UserInfo::default());
-    }
-    // check auth
-    let (valid, user_info) = auth.check_auth(&username, &password, addr, remote_ip, is_white);
-    info!("valid: {:?}", valid);
-    if !valid {
-        req.protocol.respond_auth_result(conn, false, is_white).await?;
-        return Err(Error::AuthFailed(format!(
-            "ip: {}, username: {}, password: {}",
-            addr, username, password
-        )));
+    if !auth.in_stock(addr) && DEFAULT_USERNAME.eq(username) {
+        return vec![(DEFAULT_PASSOWRD.to_string(), UserInfo::default())];
     }
-    Ok(user_info)
+    auth.password_users(username, addr)
 }
 
 // TODO: may need to spawn two thread to handle upload and download, donot use select
@@ -0,0 +1,604 @@
+//! Runs the backend on a loopback listener and drives it the way clients do.
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use config::ProxyConfig;
+use error::FailureReason;
+use rg_acl::{acl::AclRule, auth::Authenticator};
+use rg_common::{
+    user_auth::{UserInfo, WhiteListData},
+    UserId,
+};
+use rg_stat::{RequestType, StatEvent};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::UnboundedReceiver, RwLock},
+};
+use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
+
+use super::{dc_server::DcServerBackend, CommonBackend, ServerBackend};
+
+/// Denies every host in the list.
+struct DenyHosts(Vec<&'static str>);
+
+impl AclRule for DenyHosts {
+    fn check(&self, _user_info: &UserInfo, host: &str, _ip: &str) -> bool {
+        !self.0.contains(&host)
+    }
+
+    fn update(&mut self, _data: &str) {}
+}
+
+/// Accepts any credentials, the user id being the username when it is a number and 0 otherwise.
+struct NumberedUsers;
+
+impl Authenticator for NumberedUsers {
+    fn check_auth(&self, username: &str, _password: &str, _ip: &str, _remote_ip: &str, _is_white: bool) -> (bool, UserInfo) {
+        let user_info = UserInfo {
+            user_id: username.parse().unwrap_or_default(),
+            ..Default::default()
+        };
+        (true, user_info)
+    }
+
+    fn disable_user(&self, _user_id: UserId) {}
+
+    fn enable_user(&self, _user_id: UserId) {}
+
+    fn update_user_info(&self, _user_info: UserInfo) {}
+
+    fn update_white_list(&mut self, _white_list: Vec<WhiteListData>) {}
+
+    fn update_all(&mut self, _user_info: Vec<UserInfo>) {}
+
+    fn in_stock(&self, _ip: &str) -> bool {
+        false
+    }
+
+    fn user_map_get(&self, _remote_ip: &str) -> Option<UserInfo> {
+        None
+    }
+}
+
+struct Proxy {
+    backend: Arc<DcServerBackend>,
+    addr: SocketAddr,
+    stats: UnboundedReceiver<StatEvent>,
+}
+
+impl Proxy {
+    /// A backend listening on `ip`, accepting any credentials and denying `denied` hosts.
+    async fn start(ip: &str, config: ProxyConfig, denied: Vec<&'static str>) -> Self {
+        Self::start_on(TcpListener::bind((ip, 0)).await.unwrap(), config, denied, None)
+    }
+
+    /// Like [`start`](Self::start) with the HTTP clients served by the hyper front end.
+    async fn start_hyper(mut config: ProxyConfig, denied: Vec<&'static str>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        config.http.hyper_ports = vec![listener.local_addr().unwrap().port()];
+        Self::start_on(listener, config, denied, None)
+    }
+
+    /// Like [`start`](Self::start) with the connections terminated by `tls`.
+    async fn start_tls(config: ProxyConfig, tls: TlsAcceptor) -> Self {
+        Self::start_on(TcpListener::bind("127.0.0.1:0").await.unwrap(), config, vec![], Some(tls))
+    }
+
+    fn start_on(listener: TcpListener, config: ProxyConfig, denied: Vec<&'static str>, tls: Option<TlsAcceptor>) -> Self {
+        let (stat_sender, stats) = tokio::sync::mpsc::unbounded_channel();
+        let backend = Arc::new(DcServerBackend::new(CommonBackend::new(
+            Arc::new(RwLock::new(NumberedUsers)),
+            Arc::new(RwLock::new(DenyHosts(denied))),
+            stat_sender,
+            config,
+        )));
+        let addr = listener.local_addr().unwrap();
+        let server = backend.clone();
+        tokio::spawn(async move {
+            loop {
+                let (conn, remote_addr) = listener.accept().await.unwrap();
+                let (server, tls) = (server.clone(), tls.clone());
+                tokio::spawn(async move {
+                    let _ = server.handle_connection(conn, remote_addr, tls).await;
+                });
+            }
+        });
+        Self { backend, addr, stats }
+    }
+
+    /// The stat events sent so far.
+    async fn stats(&mut self) -> Vec<StatEvent> {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut events = Vec::new();
+        while let Ok(event) = self.stats.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+}
+
+/// An echo server, each connection is answered with the peer address it came from and then echoed.
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, peer) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let peer = peer.ip().to_string();
+                conn.write_all(&[peer.len() as u8]).await?;
+                conn.write_all(peer.as_bytes()).await?;
+                let (mut r, mut w) = conn.split();
+                tokio::io::copy(&mut r, &mut w).await
+            });
+        }
+    });
+    addr
+}
+
+/// Reads the peer address an [`echo_server`] connection starts with.
+async fn echo_peer(conn: &mut TcpStream) -> String {
+    let len = conn.read_u8().await.unwrap();
+    let mut peer = vec![0; len as usize];
+    conn.read_exact(&mut peer).await.unwrap();
+    String::from_utf8(peer).unwrap()
+}
+
+/// Authenticates with username and password and sends a request of `command` for an IPv4 `target`, returning
+/// the reply code and address.
+async fn socks5_request(conn: &mut TcpStream, command: u8, target: SocketAddr) -> (u8, SocketAddr) {
+    conn.write_all(&[5, 1, 2]).await.unwrap();
+    let mut buf = [0; 2];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [5, 2]);
+    conn.write_all(&[1, 1, b'u', 1, b'p']).await.unwrap();
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [1, 0]);
+
+    let SocketAddr::V4(target) = target else {
+        panic!("not an ipv4 target")
+    };
+    let mut request = vec![5, command, 0, 1];
+    request.extend_from_slice(&target.ip().octets());
+    request.extend_from_slice(&target.port().to_be_bytes());
+    conn.write_all(&request).await.unwrap();
+    socks5_reply(conn).await
+}
+
+/// Reads a reply with an IPv4 address.
+async fn socks5_reply(conn: &mut TcpStream) -> (u8, SocketAddr) {
+    let mut reply = [0; 10];
+    conn.read_exact(&mut reply).await.unwrap();
+    let ip = std::net::Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+    (reply[1], SocketAddr::from((ip, u16::from_be_bytes([reply[8], reply[9]]))))
+}
+
+async fn assert_echo(conn: &mut TcpStream, data: &[u8]) {
+    conn.write_all(data).await.unwrap();
+    let mut buf = vec![0; data.len()];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, data);
+}
+
+#[tokio::test]
+async fn test_socks5_connect() {
+    let target = echo_server().await;
+    // the proxy listens on another loopback address, which the connection to the target is bound to
+    let mut proxy = Proxy::start("127.0.0.2", ProxyConfig::default(), vec!["127.0.0.3"]).await;
+
+    let mut conn = TcpStream::connect(proxy.addr).await.unwrap();
+    let (reply, _) = socks5_request(&mut conn, 1, "127.0.0.3:80".parse().unwrap()).await;
+    assert_eq!(reply, 0x02);
+    let stats = proxy.stats().await;
+    assert!(stats.iter().any(|e| matches!(e, StatEvent::Request(RequestType::Socks5))));
+    assert!(stats.iter().any(|e| matches!(e, StatEvent::Failure(FailureReason::AclDenied))));
+
+    let mut conn = TcpStream::connect(proxy.addr).await.unwrap();
+    let (reply, _) = socks5_request(&mut conn, 1, target).await;
+    assert_eq!(reply, 0);
+    assert_eq!(echo_peer(&mut conn).await, "127.0.0.2");
+    assert_echo(&mut conn, b"hello").await;
+    let upload: u64 = proxy
+        .stats()
+        .await
+        .iter()
+        .filter_map(|e| match e {
+            StatEvent::Traffic(t) if t.host == "127.0.0.1" && t.local_ip == "127.0.0.2" => Some(t.upload),
+            _ => None,
+        })
+        .sum();
+    assert_eq!(upload, 5);
+
+    // killing the user closes the tunnel
+    proxy.backend.conn_set.kill_user(UserInfo::default().user_id);
+    let n = tokio::time::timeout(Duration::from_secs(2), conn.read(&mut [0; 1])).await.unwrap();
+    assert!(matches!(n, Ok(0) | Err(_)));
+}
+
+/// Connects to `addr` from the loopback address `ip`.
+async fn connect_from(ip: &str, addr: SocketAddr) -> TcpStream {
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::new(ip.parse().unwrap(), 0)).unwrap();
+    socket.connect(addr).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_socks5_bind() {
+    let proxy = Proxy::start("127.0.0.1", ProxyConfig::default(), vec![]).await;
+    let mut conn = TcpStream::connect(proxy.addr).await.unwrap();
+    let (reply, bound) = socks5_request(&mut conn, 2, "127.0.0.4:0".parse().unwrap()).await;
+    assert_eq!(reply, 0);
+    assert_eq!(bound.ip().to_string(), "127.0.0.1");
+
+    // a peer from another ip is dropped, the bind keeps waiting
+    let mut stranger = connect_from("127.0.0.5", bound).await;
+    let n = tokio::time::timeout(Duration::from_secs(2), stranger.read(&mut [0; 1]))
+        .await
+        .unwrap();
+    assert!(matches!(n, Ok(0) | Err(_)));
+
+    let mut peer = connect_from("127.0.0.4", bound).await;
+    let (reply, peer_addr) = socks5_reply(&mut conn).await;
+    assert_eq!(reply, 0);
+    assert_eq!(peer_addr, peer.local_addr().unwrap());
+
+    peer.write_all(b"from peer").await.unwrap();
+    let mut buf = [0; 9];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"from peer");
+    conn.write_all(b"to peer").await.unwrap();
+    let mut buf = [0; 7];
+    peer.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"to peer");
+}
+
+/// Prepends the socks5 UDP header for an IPv4 `addr`.
+fn udp_datagram(addr: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let SocketAddr::V4(addr) = addr else {
+        panic!("not an ipv4 address")
+    };
+    let mut datagram = vec![0, 0, 0, 1];
+    datagram.extend_from_slice(&addr.ip().octets());
+    datagram.extend_from_slice(&addr.port().to_be_bytes());
+    datagram.extend_from_slice(data);
+    datagram
+}
+
+#[tokio::test]
+async fn test_udp_associate_drops_unsolicited() {
+    let mut proxy = Proxy::start("127.0.0.1", ProxyConfig::default(), vec![]).await;
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut conn = TcpStream::connect(proxy.addr).await.unwrap();
+    let (reply, relay) = socks5_request(&mut conn, 3, client.local_addr().unwrap()).await;
+    assert_eq!(reply, 0);
+
+    let remote = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote_addr = remote.local_addr().unwrap();
+    client.send_to(&udp_datagram(remote_addr, b"ping"), relay).await.unwrap();
+    let mut buf = [0; 64];
+    let (_, outbound) = remote.recv_from(&mut buf).await.unwrap();
+
+    // a peer the client never sent to finds the outbound socket, its datagram is dropped and not accounted
+    let stranger = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    stranger.send_to(b"unsolicited", outbound).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    remote.send_to(b"pong", outbound).await.unwrap();
+    let n = client.recv(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], udp_datagram(remote_addr, b"pong"));
+
+    let download: u64 = proxy
+        .stats()
+        .await
+        .iter()
+        .filter_map(|e| match e {
+            StatEvent::Traffic(t) => Some(t.download),
+            _ => None,
+        })
+        .sum();
+    assert_eq!(download, 4);
+}
+
+fn sniffing(peek_timeout_ms: u64) -> ProxyConfig {
+    let mut config = ProxyConfig::default();
+    config.sni.sniff = true;
+    config.sni.peek_timeout_ms = peek_timeout_ms;
+    config
+}
+
+#[tokio::test]
+async fn test_sni_sniff_relays_server_greeting() {
+    let target = echo_server().await;
+    let proxy = Proxy::start("127.0.0.1", sniffing(60_000), vec![]).await;
+    let mut conn = TcpStream::connect(proxy.addr).await.unwrap();
+    let (reply, _) = socks5_request(&mut conn, 1, target).await;
+    assert_eq!(reply, 0);
+
+    // the target speaks first, the client gets it long before the peek timeout
+    let peer = tokio::time::timeout(Duration::from_secs(2), echo_peer(&mut conn)).await.unwrap();
+    assert_eq!(peer, "127.0.0.1");
+    assert_echo(&mut conn, b"not a client hello").await;
+}
+
+#[tokio::test]
+async fn test_sni_sniff_acl_denied() {
+    let target = echo_server().await;
+    let mut proxy = Proxy::start("127.0.0.1", sniffing(60_000), vec!["blocked.example"]).await;
+    let mut conn = TcpStream::connect(proxy.addr).await.unwrap();
+    let (reply, _) = socks5_request(&mut conn, 1, target).await;
+    assert_eq!(reply, 0);
+    echo_peer(&mut conn).await;
+
+    let client = rustls::ClientConfig::builder()
+        .with_root_certificates(rustls::RootCertStore::empty())
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(client));
+    let handshake = connector.connect("blocked.example".try_into().unwrap(), conn);
+    assert!(tokio::time::timeout(Duration::from_secs(2), handshake).await.unwrap().is_err());
+    assert!(proxy.stats().await.iter().any(|e| matches!(e, StatEvent::Failure(FailureReason::AclDenied))));
+}
+
+/// How long the proxy keeps a connection open after `sent`, `None` if it is still open after `limit`.
+async fn closed_after(proxy: &Proxy, sent: &[u8], limit: Duration) -> Option<Duration> {
+    let mut conn = TcpStream::connect(proxy.addr).await.unwrap();
+    conn.write_all(sent).await.unwrap();
+    let start = tokio::time::Instant::now();
+    let mut buf = Vec::new();
+    match tokio::time::timeout(limit, conn.read_to_end(&mut buf)).await {
+        Ok(_) => Some(start.elapsed()),
+        Err(_) => None,
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handshake_deadline() {
+    let mut config = ProxyConfig::default();
+    config.handshake.socks_timeout_secs = 3;
+    config.handshake.http_timeout_secs = 4;
+    let mut proxy = Proxy::start("127.0.0.1", config, vec![]).await;
+
+    // a greeting without its methods, and a request head without its end
+    let elapsed = closed_after(&proxy, &[5, 2], Duration::from_secs(60)).await.unwrap();
+    assert_eq!(elapsed.as_secs(), 3);
+    let elapsed = closed_after(&proxy, b"GET http://a.com/ HTTP/1.1\r\n", Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!(elapsed.as_secs(), 4);
+    let stats = proxy.stats().await;
+    assert_eq!(stats.iter().filter(|e| matches!(e, StatEvent::HandshakeTimeout)).count(), 2);
+
+    // 0 disables the deadline
+    let mut config = ProxyConfig::default();
+    config.handshake.socks_timeout_secs = 0;
+    config.handshake.http_timeout_secs = 0;
+    let mut proxy = Proxy::start("127.0.0.1", config, vec![]).await;
+    assert_eq!(closed_after(&proxy, &[5, 2], Duration::from_secs(600)).await, None);
+    assert!(!proxy.stats().await.iter().any(|e| matches!(e, StatEvent::HandshakeTimeout)));
+}
+
+/// An HTTP origin answering every request with the number of the connection it came on.
+async fn origin_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        for n in 1u8.. {
+            let (conn, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut conn = tokio::io::BufReader::new(conn);
+                loop {
+                    let mut line = String::new();
+                    while line != "\r\n" {
+                        line.clear();
+                        if conn.read_line(&mut line).await? == 0 {
+                            return Ok::<_, std::io::Error>(());
+                        }
+                    }
+                    conn.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n{}", n).as_bytes())
+                        .await?;
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Reads a response with a body of one byte, returning the byte.
+async fn read_response(conn: &mut tokio::io::BufReader<TcpStream>) -> String {
+    let mut response = String::new();
+    while !response.ends_with("\r\n\r\n") {
+        assert_ne!(conn.read_line(&mut response).await.unwrap(), 0);
+    }
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let mut body = [0; 1];
+    conn.read_exact(&mut body).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn test_http_connect_does_not_reuse_upstream() {
+    let origin = origin_server().await;
+    let proxy = Proxy::start("127.0.0.1", ProxyConfig::default(), vec![]).await;
+    let mut conn = tokio::io::BufReader::new(TcpStream::connect(proxy.addr).await.unwrap());
+    let auth = "Proxy-Authorization: Basic dTpw\r\n";
+
+    for _ in 0..2 {
+        let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n{}\r\n", origin, origin, auth);
+        conn.write_all(request.as_bytes()).await.unwrap();
+        assert_eq!(read_response(&mut conn).await, "1");
+    }
+    // the tunnel to the same target is a new connection
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n{}\r\n", origin, origin, auth);
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let mut established = String::new();
+    while !established.ends_with("\r\n\r\n") {
+        conn.read_line(&mut established).await.unwrap();
+    }
+    assert!(established.starts_with("HTTP/1.1 200"));
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.unwrap();
+    assert_eq!(read_response(&mut conn).await, "2");
+}
+
+/// Reads the head of a response and skips its body, returning the status line or `None` once closed.
+async fn read_status(conn: &mut tokio::io::BufReader<TcpStream>) -> Option<String> {
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        if conn.read_line(&mut head).await.unwrap_or(0) == 0 {
+            return None;
+        }
+    }
+    let length = head
+        .lines()
+        .find_map(|l| {
+            l.to_ascii_lowercase()
+                .strip_prefix("content-length: ")
+                .map(|v| v.parse::<usize>().unwrap())
+        })
+        .unwrap_or(0);
+    conn.read_exact(&mut vec![0; length]).await.ok()?;
+    head.lines().next().map(str::to_owned)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_hyper_handshake_deadline() {
+    let origin = origin_server().await;
+    let mut config = ProxyConfig::default();
+    config.handshake.http_timeout_secs = 4;
+    config.http.max_auth_attempts = 0;
+    let mut proxy = Proxy::start_hyper(config, vec![]).await;
+    let unauthenticated = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+
+    // a client answered with 407 again and again is cut off at the deadline
+    let mut conn = tokio::io::BufReader::new(TcpStream::connect(proxy.addr).await.unwrap());
+    let start = tokio::time::Instant::now();
+    let closed = loop {
+        if conn.write_all(unauthenticated.as_bytes()).await.is_err() {
+            break start.elapsed();
+        }
+        match read_status(&mut conn).await {
+            Some(status) => assert!(status.starts_with("HTTP/1.1 407"), "{}", status),
+            None => break start.elapsed(),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+    assert_eq!(closed.as_secs(), 4);
+    assert!(proxy.stats().await.iter().any(|e| matches!(e, StatEvent::HandshakeTimeout)));
+
+    // once a request is authenticated the connection outlives the deadline
+    let mut conn = tokio::io::BufReader::new(TcpStream::connect(proxy.addr).await.unwrap());
+    let authenticated = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Basic dTpw\r\n\r\n",
+        origin, origin
+    );
+    conn.write_all(authenticated.as_bytes()).await.unwrap();
+    assert_eq!(read_status(&mut conn).await.as_deref(), Some("HTTP/1.1 200 OK"));
+    for _ in 0..8 {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        conn.write_all(unauthenticated.as_bytes()).await.unwrap();
+        assert!(read_status(&mut conn).await.unwrap().starts_with("HTTP/1.1 407"));
+    }
+    assert!(!proxy.stats().await.iter().any(|e| matches!(e, StatEvent::HandshakeTimeout)));
+}
+
+/// A TLS acceptor offering HTTP/2 with a self signed certificate and a connector trusting it.
+fn h2_tls() -> (TlsAcceptor, TlsConnector) {
+    let dir = std::env::temp_dir().join(format!("rg-proxy-h2-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = config::TlsConfig {
+        cert_file: dir.join("cert.pem"),
+        key_file: dir.join("key.pem"),
+        http2: true,
+        ..Default::default()
+    };
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    std::fs::write(&config.cert_file, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&config.key_file, cert.serialize_private_key_pem()).unwrap();
+    let acceptor = crate::tls::TlsTerminator::load(&config).unwrap().acceptor();
+    std::fs::remove_dir_all(dir).unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert.serialize_der().unwrap().into()).unwrap();
+    let mut client = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    client.alpn_protocols = vec![b"h2".to_vec()];
+    (acceptor, TlsConnector::from(Arc::new(client)))
+}
+
+/// Opens an HTTP/2 connection to `addr`.
+async fn h2_handshake(connector: &TlsConnector, addr: SocketAddr) -> h2::client::SendRequest<Bytes> {
+    let conn = TcpStream::connect(addr).await.unwrap();
+    let conn = connector.connect("localhost".try_into().unwrap(), conn).await.unwrap();
+    assert_eq!(conn.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    let (send_request, connection) = h2::client::handshake(conn).await.unwrap();
+    tokio::spawn(connection);
+    send_request
+}
+
+/// Opens a CONNECT stream to `target` as `user`, returning its halves once the tunnel is established.
+async fn h2_connect(
+    client: &mut h2::client::SendRequest<Bytes>,
+    user: &str,
+    target: SocketAddr,
+) -> (h2::SendStream<Bytes>, h2::RecvStream) {
+    // base64 of `<user>:p`
+    let credentials = match user {
+        "1" => "MTpw",
+        "2" => "Mjpw",
+        _ => unreachable!("no credentials of user {}", user),
+    };
+    let request = http::Request::connect(target.to_string())
+        .header("proxy-authorization", format!("Basic {}", credentials))
+        .body(())
+        .unwrap();
+    client.clone().ready().await.unwrap();
+    let (response, send) = client.send_request(request, false).unwrap();
+    let response = response.await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::OK);
+    (send, response.into_body())
+}
+
+/// Reads `len` bytes of a stream, `None` if it ends or is reset before.
+async fn h2_read(recv: &mut h2::RecvStream, len: usize) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    while buf.len() < len {
+        let data = recv.data().await?.ok()?;
+        let _ = recv.flow_control().release_capacity(data.len());
+        buf.extend_from_slice(&data);
+    }
+    Some(buf)
+}
+
+async fn assert_h2_echo(stream: &mut (h2::SendStream<Bytes>, h2::RecvStream), data: &'static [u8]) {
+    stream.0.send_data(Bytes::from_static(data), false).unwrap();
+    assert_eq!(h2_read(&mut stream.1, data.len()).await.as_deref(), Some(data));
+}
+
+#[tokio::test]
+async fn test_h2_connect_streams() {
+    let echo = echo_server().await;
+    let (acceptor, connector) = h2_tls();
+    let proxy = Proxy::start_tls(ProxyConfig::default(), acceptor).await;
+    let mut client = h2_handshake(&connector, proxy.addr).await;
+
+    // two tunnels of different users share the connection, each relaying its own bytes
+    let mut first = h2_connect(&mut client, "1", echo).await;
+    let mut second = h2_connect(&mut client, "2", echo).await;
+    for stream in [&mut first, &mut second] {
+        assert_eq!(h2_read(&mut stream.1, 10).await.unwrap(), b"\x09127.0.0.1");
+    }
+    assert_h2_echo(&mut first, b"first").await;
+    assert_h2_echo(&mut second, b"second").await;
+
+    // killing the first user ends its stream and resets the half the client still has open, the other relays on
+    proxy.backend.conn_set.kill_user(1);
+    assert_eq!(
+        tokio::time::timeout(Duration::from_secs(2), h2_read(&mut first.1, 1))
+            .await
+            .unwrap(),
+        None
+    );
+    let reset = tokio::time::timeout(Duration::from_secs(2), std::future::poll_fn(|cx| first.0.poll_reset(cx)));
+    assert_eq!(reset.await.unwrap().unwrap(), h2::Reason::NO_ERROR);
+    assert_h2_echo(&mut second, b"still open").await;
+    let mut third = h2_connect(&mut client, "2", echo).await;
+    assert_eq!(h2_read(&mut third.1, 10).await.unwrap(), b"\x09127.0.0.1");
+    assert_h2_echo(&mut third, b"third").await;
+}
@@ -2,13 +2,14 @@ pub mod backend;
 mod conn_set;
 pub mod proxy_server;
 mod resolver;
-mod util;
+mod sni;
 pub mod socks5_server;
+pub mod tls;
 
 use bytes::Bytes;
 use rg_common::{user_auth::UserInfo, Result, TrafficInfo};
 use rg_stat::StatEvent;
-use std::{net::SocketAddr, time::Duration};
+use std::net::SocketAddr;
 use tokio::{net::TcpStream, sync::mpsc::UnboundedSender};
 
 type FilterFn = Box<dyn Fn(&[u8]) -> Bytes + Send + 'static>;
@@ -21,7 +22,7 @@ pub trait Server {
     async fn stop(&self) -> Result<()>;
 
     async fn handle_connection(&self, conn: TcpStream, remote_addr: SocketAddr) {
-        let _ = conn.set_linger(Some(Duration::from_secs(0)));
+        let _ = conn.set_zero_linger();
 
         self._handle(conn, remote_addr).await;
     }
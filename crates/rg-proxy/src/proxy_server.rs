@@ -6,6 +6,7 @@ use tokio::net::{TcpListener, TcpStream};
 
 use crate::{
     backend::{CommonBackend, ServerBackend},
+    tls::TlsTerminator,
     Server,
 };
 
@@ -15,6 +16,7 @@ where
 {
     listener: TcpListener,
     inner: Arc<T>,
+    tls: Option<Arc<TlsTerminator>>,
 }
 
 #[async_trait::async_trait]
@@ -45,8 +47,9 @@ where
 
     async fn _handle(&self, conn: TcpStream, remote_addr: SocketAddr) {
         let inner = self.inner.clone();
+        let tls = self.tls.as_ref().map(|tls| tls.acceptor());
         tokio::spawn(async move {
-            if let Err(e) = inner.handle_connection(conn, remote_addr).await {
+            if let Err(e) = inner.handle_connection(conn, remote_addr, tls).await {
                 error!("handle connection error: {}", e);
             }
         });
@@ -58,6 +61,12 @@ where
     T: ServerBackend + Deref<Target = CommonBackend> + Send + Sync,
 {
     pub async fn new(listener: TcpListener, inner: Arc<T>) -> Self {
-        ProxyServer { listener, inner }
+        ProxyServer { listener, inner, tls: None }
+    }
+
+    /// Terminates TLS on every accepted connection before the SOCKS or HTTP handshake.
+    pub fn with_tls(mut self, tls: Arc<TlsTerminator>) -> Self {
+        self.tls = Some(tls);
+        self
     }
 }
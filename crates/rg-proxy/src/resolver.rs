@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use anyhow::Context;
 use error::Result;
@@ -13,7 +13,18 @@ lazy_static::lazy_static! {
 }
 
 pub async fn resolve_host(host: &str, port: u16) -> Result<SocketAddr> {
+    Ok(SocketAddr::new(resolve_ip(host).await?, port))
+}
+
+pub async fn resolve_ip(host: &str) -> Result<IpAddr> {
     let response = RESOLVER.lookup_ip(host).await?;
     let ip = response.iter().next().context("no addresses returned")?;
-    Ok(SocketAddr::new(ip, port))
+    Ok(ip)
+}
+
+/// Looks up the hostname of `ip`, without the trailing dot.
+pub async fn resolve_ptr(ip: IpAddr) -> Result<String> {
+    let response = RESOLVER.reverse_lookup(ip).await?;
+    let name = response.iter().next().context("no names returned")?;
+    Ok(name.to_utf8().trim_end_matches('.').to_string())
 }
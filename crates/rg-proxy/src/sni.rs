@@ -0,0 +1,185 @@
+//! Server name of a TLS ClientHello, RFC 8446 section 4.1.2 and RFC 6066 section 3, read from the first bytes a
+//! client sends through a tunnel without decrypting anything.
+
+/// Largest ClientHello looked at, a client sending more is treated as not asking for a server name.
+pub(crate) const MAX_HELLO_SIZE: usize = 64 * 1024;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+/// largest TLSCiphertext fragment, RFC 8446 section 5.2
+const MAX_RECORD_SIZE: usize = (1 << 14) + 256;
+
+/// What the first bytes of a tunnel tell about the server name it is for.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum ClientHello {
+    /// more bytes are needed
+    Incomplete,
+    /// not a TLS ClientHello
+    NotTls,
+    /// a whole ClientHello, with the host name it asks for if any
+    Complete(Option<String>),
+}
+
+/// Parses the ClientHello at the start of `data`, which may span several records.
+pub(crate) fn parse(data: &[u8]) -> ClientHello {
+    let mut handshake = Vec::new();
+    let mut records = data;
+    loop {
+        if records.len() < 5 {
+            if records.first().is_some_and(|&b| b != CONTENT_TYPE_HANDSHAKE) {
+                return ClientHello::NotTls;
+            }
+            return ClientHello::Incomplete;
+        }
+        let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        if records[0] != CONTENT_TYPE_HANDSHAKE || records[1] != 3 || len == 0 || len > MAX_RECORD_SIZE {
+            return ClientHello::NotTls;
+        }
+        let Some(fragment) = records.get(5..5 + len) else {
+            return ClientHello::Incomplete;
+        };
+        handshake.extend_from_slice(fragment);
+        records = &records[5 + len..];
+
+        if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+            return ClientHello::NotTls;
+        }
+        if handshake.len() >= 4 {
+            let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if let Some(hello) = handshake.get(4..4 + len) {
+                return match server_name(&mut Reader(hello)) {
+                    Some(name) => ClientHello::Complete(name),
+                    None => ClientHello::NotTls,
+                };
+            }
+        }
+    }
+}
+
+/// The host name of a ClientHello body, `None` if it is malformed.
+fn server_name(hello: &mut Reader) -> Option<Option<String>> {
+    // legacy_version and random
+    hello.take(2 + 32)?;
+    // legacy_session_id, cipher_suites and legacy_compression_methods
+    hello.vec8()?;
+    hello.vec16()?;
+    hello.vec8()?;
+    if hello.0.is_empty() {
+        return Some(None);
+    }
+    let mut extensions = Reader(hello.vec16()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader(Reader(data).vec16()?);
+        while !names.0.is_empty() {
+            let name_type = names.take(1)?[0];
+            let name = names.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).ok().filter(|n| !n.is_empty() && n.is_ascii())?;
+                return Some(Some(name.trim_end_matches('.').to_ascii_lowercase()));
+            }
+        }
+        return Some(None);
+    }
+    Some(None)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let taken = self.0.get(..n)?;
+        self.0 = &self.0[n..];
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.take(1)?[0] as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut v = (data.len() as u16).to_be_bytes().to_vec();
+        v.extend_from_slice(data);
+        v
+    }
+
+    /// A ClientHello asking for `name`, as records of at most `record_size` bytes.
+    fn client_hello(name: Option<&str>, record_size: usize) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[7; 32]);
+        body.extend_from_slice(&[0]);
+        body.extend_from_slice(&vec16(&[0x13, 0x01]));
+        body.extend_from_slice(&[1, 0]);
+        let mut extensions = Vec::new();
+        // supported_versions before server_name
+        extensions.extend_from_slice(&[0, 43, 0, 3, 2, 3, 4]);
+        if let Some(name) = name {
+            let mut entry = vec![NAME_TYPE_HOST_NAME];
+            entry.extend_from_slice(&vec16(name.as_bytes()));
+            extensions.extend_from_slice(&[0, 0]);
+            extensions.extend_from_slice(&vec16(&vec16(&entry)));
+        }
+        body.extend_from_slice(&vec16(&extensions));
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        handshake
+            .chunks(record_size)
+            .flat_map(|fragment| [vec![CONTENT_TYPE_HANDSHAKE, 3, 1], vec16(fragment)].concat())
+            .collect()
+    }
+
+    #[test]
+    fn test_server_name() {
+        let hello = client_hello(Some("Www.Example.com."), 1024);
+        assert_eq!(parse(&hello), ClientHello::Complete(Some("www.example.com".to_owned())));
+        // followed by early data
+        assert_eq!(parse(&[hello.as_slice(), &[23, 3, 3, 0, 1, 0]].concat()), ClientHello::Complete(Some("www.example.com".to_owned())));
+        assert_eq!(parse(&client_hello(None, 1024)), ClientHello::Complete(None));
+    }
+
+    #[test]
+    fn test_fragmented_and_partial() {
+        let hello = client_hello(Some("a.com"), 16);
+        assert_eq!(parse(&hello), ClientHello::Complete(Some("a.com".to_owned())));
+        for n in [0, 3, 5, 20, hello.len() - 1] {
+            assert_eq!(parse(&hello[..n]), ClientHello::Incomplete);
+        }
+    }
+
+    #[test]
+    fn test_not_tls() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n\r\n"), ClientHello::NotTls);
+        assert_eq!(parse(b"SSH-2.0-OpenSSH_9.6\r\n"), ClientHello::NotTls);
+        assert_eq!(parse(b"G"), ClientHello::NotTls);
+        // a ServerHello
+        assert_eq!(parse(&[22, 3, 3, 0, 4, 2, 0, 0, 0]), ClientHello::NotTls);
+        // a ClientHello whose server name overruns it
+        let mut hello = client_hello(Some("a.com"), 1024);
+        let len = hello.len();
+        hello[len - 7] = 0xff;
+        assert_eq!(parse(&hello), ClientHello::NotTls);
+    }
+}
@@ -1,8 +1,9 @@
-use socks5_protocol::{handshake::password_method, AsyncStreamOperation, AuthMethod, UserKey};
+use socks5_protocol::{handshake::{gssapi_method, hmac_method, password_method}, AsyncStreamOperation, AuthMethod, UserKey};
+use gssapi_method::{Mechanism, Message, MessageType, ProtectionLevel, SecurityContext, Step};
 use as_any::AsAny;
-use std::sync::Arc;
+use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 use async_trait::async_trait;
-use tokio::net::TcpStream;
+use socks5_http::ProxyStream;
 use password_method::{Request, Response, Status::*};
 use error::{Error, Result};
 
@@ -17,12 +18,12 @@ use error::{Error, Result};
 /// ```rust
 ///
 /// use socks5_protocol::AuthMethod;
-/// use socks5_server::auth::AuthExecutor;
-/// use tokio::net::TcpStream;
+/// use rg_proxy::socks5_server::auth::AuthExecutor;
+/// use socks5_http::ProxyStream;
 ///
 /// pub struct MyAuth;
 ///
-///
+/// #[async_trait::async_trait]
 /// impl AuthExecutor for MyAuth {
 ///     type Output = std::io::Result<usize>;
 ///
@@ -30,7 +31,7 @@ use error::{Error, Result};
 ///         AuthMethod::from(0x80)
 ///     }
 ///
-///     async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
+///     async fn execute(&self, stream: &mut ProxyStream) -> Self::Output {
 ///         // do something
 ///         Ok(1145141919810)
 ///     }
@@ -41,11 +42,74 @@ use error::{Error, Result};
 pub trait AuthExecutor {
     type Output: AsAny;
     fn auth_method(&self) -> AuthMethod;
-    async fn execute(&self, stream: &mut TcpStream) -> Self::Output;
+    async fn execute(&self, stream: &mut ProxyStream) -> Self::Output;
+
+    /// Picks the method to use among the ones offered by the client, `None` if none of them is acceptable.
+    ///
+    /// Executors serving a single method accept [`auth_method`](AuthExecutor::auth_method) only.
+    fn select_method(&self, offered: &[AuthMethod]) -> Option<AuthMethod> {
+        let method = self.auth_method();
+        offered.contains(&method).then_some(method)
+    }
+
+    /// Runs the sub-negotiation of a method returned by [`select_method`](AuthExecutor::select_method).
+    async fn execute_with(&self, _method: AuthMethod, stream: &mut ProxyStream) -> Self::Output {
+        self.execute(stream).await
+    }
 }
 
 pub type AuthAdaptor<O> = Arc<dyn AuthExecutor<Output = O> + Send + Sync>;
 
+/// Negotiates among several authentication methods.
+///
+/// The executors are kept in order of preference, the first one whose method the client offered
+/// runs the sub-negotiation.
+pub struct AuthChain<O> {
+    executors: Vec<AuthAdaptor<O>>,
+}
+
+impl<O: 'static> AuthChain<O> {
+    /// Creates a chain from executors in order of preference.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `executors` is empty.
+    pub fn new(executors: Vec<AuthAdaptor<O>>) -> Self {
+        assert!(!executors.is_empty(), "an auth chain needs at least one executor");
+        Self { executors }
+    }
+
+    /// The executor serving `method`, the most preferred one if none does.
+    fn find(&self, method: AuthMethod) -> &AuthAdaptor<O> {
+        self.executors
+            .iter()
+            .find(|executor| executor.auth_method() == method)
+            .unwrap_or(&self.executors[0])
+    }
+}
+
+#[async_trait]
+impl<O: AsAny + Send + 'static> AuthExecutor for AuthChain<O> {
+    type Output = O;
+
+    /// The most preferred method.
+    fn auth_method(&self) -> AuthMethod {
+        self.executors[0].auth_method()
+    }
+
+    async fn execute(&self, stream: &mut ProxyStream) -> Self::Output {
+        self.execute_with(self.auth_method(), stream).await
+    }
+
+    fn select_method(&self, offered: &[AuthMethod]) -> Option<AuthMethod> {
+        self.executors.iter().find_map(|executor| executor.select_method(offered))
+    }
+
+    async fn execute_with(&self, method: AuthMethod, stream: &mut ProxyStream) -> Self::Output {
+        self.find(method).execute_with(method, stream).await
+    }
+}
+
 /// No authentication as the socks5 handshake method.
 #[derive(Debug, Default)]
 pub struct NoAuth;
@@ -57,7 +121,7 @@ impl AuthExecutor for NoAuth {
         AuthMethod::NoAuth
     }
 
-    async fn execute(&self, _: &mut TcpStream) -> Self::Output {
+    async fn execute(&self, _: &mut ProxyStream) -> Self::Output {
 
     }
 }
@@ -82,7 +146,7 @@ impl AuthExecutor for UserKeyAuth {
         AuthMethod::UserPass
     }
 
-    async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
+    async fn execute(&self, stream: &mut ProxyStream) -> Self::Output {
         let req = Request::retrieve_from_async_stream(stream).await?;
         let is_equal = req.user_key == self.user_key;
         let resp = Response::new(if is_equal { Succeeded } else { Failed });
@@ -90,7 +154,258 @@ impl AuthExecutor for UserKeyAuth {
         if is_equal {
             Ok(true)
         } else {
-            Err(Error::from(std::io::Error::new(std::io::ErrorKind::Other, "username or password is incorrect")))
+            Err(Error::from(std::io::Error::other("username or password is incorrect")))
+        }
+    }
+}
+
+/// Looks up the secrets of [`HmacAuth`] users.
+#[async_trait]
+pub trait HmacSecrets {
+    type User;
+
+    /// The secrets `username` may sign with, each with the user it authenticates as.
+    async fn secrets(&self, username: &str) -> Vec<(String, Self::User)>;
+}
+
+/// Private HMAC token method, see [`hmac_method`].
+///
+/// The client proves knowledge of its secret without sending it, the output is the user the secret belongs to.
+pub struct HmacAuth<S> {
+    secrets: S,
+}
+
+impl<S> HmacAuth<S> {
+    pub fn new(secrets: S) -> Self {
+        Self { secrets }
+    }
+}
+
+#[async_trait]
+impl<S> AuthExecutor for HmacAuth<S>
+where
+    S: HmacSecrets + Send + Sync,
+    S::User: Send + 'static,
+{
+    type Output = Result<S::User>;
+
+    fn auth_method(&self) -> AuthMethod {
+        hmac_method::AUTH_METHOD
+    }
+
+    async fn execute(&self, stream: &mut ProxyStream) -> Self::Output {
+        let challenge = hmac_method::Challenge::new(rand::random());
+        challenge.write_to_async_stream(stream).await?;
+        let req = hmac_method::Request::retrieve_from_async_stream(stream).await?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let user = if now.abs_diff(req.timestamp) <= hmac_method::MAX_TIMESTAMP_SKEW_SECS {
+            self.secrets
+                .secrets(&req.username)
+                .await
+                .into_iter()
+                .find(|(secret, _)| hmac_method::verify(secret.as_bytes(), &challenge.nonce, &req))
+                .map(|(_, user)| user)
+        } else {
+            None
+        };
+
+        let resp = Response::new(if user.is_some() { Succeeded } else { Failed });
+        resp.write_to_async_stream(stream).await?;
+        user.ok_or_else(|| Error::AuthFailed(format!("hmac token of username: {}", req.username)))
+    }
+}
+
+/// GSS-API as the socks5 handshake method, RFC 1961.
+///
+/// The security mechanism is pluggable through [`Mechanism`]. The client's requested protection level is
+/// granted up to `max_level`, the traffic following the handshake has to be encapsulated accordingly
+/// with the context returned in the [`GssApiSession`].
+pub struct GssApiAuth<M> {
+    mechanism: M,
+    max_level: ProtectionLevel,
+}
+
+/// The outcome of a GSS-API sub-negotiation.
+pub struct GssApiSession<C> {
+    /// The authenticated name of the client.
+    pub principal: String,
+    pub level: ProtectionLevel,
+    pub context: C,
+}
+
+impl<M: Mechanism> GssApiAuth<M> {
+    pub fn new(mechanism: M, max_level: ProtectionLevel) -> Self {
+        Self { mechanism, max_level }
+    }
+
+    async fn negotiate(&self, stream: &mut ProxyStream) -> Result<GssApiSession<M::Context>> {
+        let mut context = self.mechanism.accept()?;
+        loop {
+            let msg = Message::retrieve_from_async_stream(stream).await?;
+            match msg.mtype {
+                MessageType::Authentication => {}
+                MessageType::Abort => return Err(Error::AuthFailed("gssapi context refused by client".to_owned())),
+                mtype => return Err(Error::from(format!("unexpected gssapi message {:?} during context establishment", mtype))),
+            }
+            match context.step(&msg.token)? {
+                Step::Continue(token) => {
+                    Message::new(MessageType::Authentication, token)?.write_to_async_stream(stream).await?;
+                }
+                Step::Complete(token) => {
+                    if let Some(token) = token {
+                        Message::new(MessageType::Authentication, token)?.write_to_async_stream(stream).await?;
+                    }
+                    break;
+                }
+            }
         }
+        let principal = context.source_name()?;
+
+        let msg = Message::retrieve_from_async_stream(stream).await?;
+        let requested = match msg.open(MessageType::ProtectionLevel, &mut context)?.as_slice() {
+            [level] => ProtectionLevel::try_from(*level)?,
+            _ => return Err(Error::from("gssapi protection level must be a single octet")),
+        };
+        let level = requested.min(self.max_level);
+        Message::seal(MessageType::ProtectionLevel, &mut context, false, &[level as u8])?
+            .write_to_async_stream(stream)
+            .await?;
+
+        Ok(GssApiSession { principal, level, context })
+    }
+}
+
+#[async_trait]
+impl<M> AuthExecutor for GssApiAuth<M>
+where
+    M: Mechanism + Send + Sync,
+    M::Context: Send + 'static,
+{
+    type Output = Result<GssApiSession<M::Context>>;
+
+    fn auth_method(&self) -> AuthMethod {
+        AuthMethod::GssApi
+    }
+
+    async fn execute(&self, stream: &mut ProxyStream) -> Self::Output {
+        let res = self.negotiate(stream).await;
+        if res.is_err() {
+            let _ = Message::abort().write_to_async_stream(stream).await;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    struct Method(AuthMethod);
+
+    #[async_trait]
+    impl AuthExecutor for Method {
+        type Output = AuthMethod;
+
+        fn auth_method(&self) -> AuthMethod {
+            self.0
+        }
+
+        async fn execute(&self, _: &mut ProxyStream) -> Self::Output {
+            self.0
+        }
+    }
+
+    /// Establishes its context in two round trips, wraps by xor-ing every octet.
+    struct MockMechanism;
+
+    struct MockContext {
+        round: u8,
+    }
+
+    impl Mechanism for MockMechanism {
+        type Context = MockContext;
+
+        fn accept(&self) -> Result<MockContext> {
+            Ok(MockContext { round: 0 })
+        }
+    }
+
+    impl SecurityContext for MockContext {
+        fn step(&mut self, token: &[u8]) -> Result<Step> {
+            self.round += 1;
+            match (self.round, token) {
+                (1, b"hello") => Ok(Step::Continue(b"challenge".to_vec())),
+                (2, b"answer") => Ok(Step::Complete(Some(b"welcome".to_vec()))),
+                _ => Err(Error::from("bad token")),
+            }
+        }
+
+        fn source_name(&self) -> Result<String> {
+            Ok("alice@EXAMPLE.COM".to_owned())
+        }
+
+        fn wrap(&mut self, _confidential: bool, data: &[u8]) -> Result<Vec<u8>> {
+            Ok(data.iter().map(|b| b ^ 0x5a).collect())
+        }
+
+        fn unwrap(&mut self, token: &[u8]) -> Result<Vec<u8>> {
+            self.wrap(false, token)
+        }
+    }
+
+    async fn gssapi_pair() -> (TcpStream, ProxyStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, ProxyStream::from(server))
+    }
+
+    #[tokio::test]
+    async fn test_gssapi_auth() {
+        let (mut client, mut server) = gssapi_pair().await;
+        let auth = GssApiAuth::new(MockMechanism, ProtectionLevel::Confidentiality);
+        let server = tokio::spawn(async move { auth.execute(&mut server).await });
+
+        let mut context = MockContext { round: 0 };
+        for (token, expected) in [(&b"hello"[..], &b"challenge"[..]), (b"answer", b"welcome")] {
+            Message::new(MessageType::Authentication, token.to_vec()).unwrap().write_to_async_stream(&mut client).await.unwrap();
+            let msg = Message::retrieve_from_async_stream(&mut client).await.unwrap();
+            assert_eq!((msg.mtype, msg.token.as_slice()), (MessageType::Authentication, expected));
+        }
+        let level = [ProtectionLevel::PerMessage as u8];
+        Message::seal(MessageType::ProtectionLevel, &mut context, false, &level).unwrap().write_to_async_stream(&mut client).await.unwrap();
+        let msg = Message::retrieve_from_async_stream(&mut client).await.unwrap();
+        // capped to the server's maximum
+        assert_eq!(msg.open(MessageType::ProtectionLevel, &mut context).unwrap(), [ProtectionLevel::Confidentiality as u8]);
+
+        let session = server.await.unwrap().unwrap();
+        assert_eq!(session.principal, "alice@EXAMPLE.COM");
+        assert_eq!(session.level, ProtectionLevel::Confidentiality);
+    }
+
+    #[tokio::test]
+    async fn test_gssapi_auth_abort() {
+        let (mut client, mut server) = gssapi_pair().await;
+        let auth = GssApiAuth::new(MockMechanism, ProtectionLevel::Integrity);
+        let server = tokio::spawn(async move { auth.execute(&mut server).await });
+
+        Message::new(MessageType::Authentication, b"forged".to_vec()).unwrap().write_to_async_stream(&mut client).await.unwrap();
+        assert_eq!(Message::retrieve_from_async_stream(&mut client).await.unwrap(), Message::abort());
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_auth_chain_select_method() {
+        let chain = AuthChain::new(vec![
+            Arc::new(Method(AuthMethod::NoAuth)) as AuthAdaptor<AuthMethod>,
+            Arc::new(Method(AuthMethod::UserPass)),
+        ]);
+        assert_eq!(chain.auth_method(), AuthMethod::NoAuth);
+        // server preference wins over the client's order
+        assert_eq!(chain.select_method(&[AuthMethod::UserPass, AuthMethod::NoAuth]), Some(AuthMethod::NoAuth));
+        assert_eq!(chain.select_method(&[AuthMethod::UserPass]), Some(AuthMethod::UserPass));
+        assert_eq!(chain.select_method(&[AuthMethod::from(0x80)]), None);
     }
 }
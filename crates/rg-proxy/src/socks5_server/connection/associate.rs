@@ -9,19 +9,20 @@ use std::{
 };
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
-    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    net::{ToSocketAddrs, UdpSocket},
 };
+use socks5_http::ProxyStream;
 
 /// Socks5 connection type `UdpAssociate`
 #[derive(Debug)]
 pub struct UdpAssociate<S> {
-    stream: TcpStream,
+    stream: ProxyStream,
     _state: S,
 }
 
 impl<S: Default> UdpAssociate<S> {
     #[inline]
-    pub(super) fn new(stream: TcpStream) -> Self {
+    pub(super) fn new(stream: ProxyStream) -> Self {
         Self {
             stream,
             _state: S::default(),
@@ -30,7 +31,7 @@ impl<S: Default> UdpAssociate<S> {
 
     /// Reply to the SOCKS5 client with the given reply and address.
     ///
-    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
+    /// If encountered an error while writing the reply, the error alongside the original `ProxyStream` is returned.
     pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<UdpAssociate<Ready>> {
         let resp = Response::new(reply, addr);
         resp.write_to_async_stream(&mut self.stream).await?;
@@ -60,7 +61,7 @@ impl<S: Default> UdpAssociate<S> {
     /// For more information about this option, see [`set_linger`](#method.set_linger).
     #[inline]
     pub fn linger(&self) -> std::io::Result<Option<Duration>> {
-        self.stream.linger()
+        self.stream.tcp().linger()
     }
 
     /// Sets the linger duration of this socket by setting the `SO_LINGER` option.
@@ -71,8 +72,9 @@ impl<S: Default> UdpAssociate<S> {
     /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
     /// that allows the process to continue as quickly as possible.
     #[inline]
+    #[allow(deprecated)]
     pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
-        self.stream.set_linger(dur)
+        self.stream.tcp().set_linger(dur)
     }
 
     /// Gets the value of the `TCP_NODELAY` option on this socket.
@@ -80,7 +82,7 @@ impl<S: Default> UdpAssociate<S> {
     /// For more information about this option, see [`set_nodelay`](#method.set_nodelay).
     #[inline]
     pub fn nodelay(&self) -> std::io::Result<bool> {
-        self.stream.nodelay()
+        self.stream.tcp().nodelay()
     }
 
     /// Sets the value of the `TCP_NODELAY` option on this socket.
@@ -89,21 +91,21 @@ impl<S: Default> UdpAssociate<S> {
     /// even if there is only a small amount of data. When not set, data is buffered until there is a sufficient amount to send out,
     /// thereby avoiding the frequent sending of small packets.
     pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
-        self.stream.set_nodelay(nodelay)
+        self.stream.tcp().set_nodelay(nodelay)
     }
 
     /// Gets the value of the `IP_TTL` option for this socket.
     ///
     /// For more information about this option, see [`set_ttl`](#method.set_ttl).
     pub fn ttl(&self) -> std::io::Result<u32> {
-        self.stream.ttl()
+        self.stream.tcp().ttl()
     }
 
     /// Sets the value for the `IP_TTL` option on this socket.
     ///
     /// This value sets the time-to-live field that is used in every packet sent from this socket.
     pub fn set_ttl(&self, ttl: u32) -> std::io::Result<()> {
-        self.stream.set_ttl(ttl)
+        self.stream.tcp().set_ttl(ttl)
     }
 }
 
@@ -130,7 +132,7 @@ impl UdpAssociate<Ready> {
 }
 
 impl std::ops::Deref for UdpAssociate<Ready> {
-    type Target = TcpStream;
+    type Target = ProxyStream;
 
     #[inline]
     fn deref(&self) -> &Self::Target {
@@ -169,7 +171,7 @@ impl AsyncWrite for UdpAssociate<Ready> {
     }
 }
 
-impl<S> From<UdpAssociate<S>> for TcpStream {
+impl<S> From<UdpAssociate<S>> for ProxyStream {
     #[inline]
     fn from(conn: UdpAssociate<S>) -> Self {
         conn.stream
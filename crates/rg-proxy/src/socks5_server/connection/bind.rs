@@ -6,13 +6,8 @@ use std::{
     task::{Context, Poll},
     time::Duration,
 };
-use tokio::{
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
-    net::{
-        tcp::{ReadHalf, WriteHalf},
-        TcpStream,
-    },
-};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use socks5_http::ProxyStream;
 
 /// Socks5 command type `Bind`
 ///
@@ -22,10 +17,10 @@ use tokio::{
 /// using [`reply()`](crate::server::connection::Bind::reply),
 /// you will get a `Bind<Ready>`, which can be used as a regular async TCP stream.
 ///
-/// A `Bind<S>` can be converted to a regular tokio [`TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) by using the `From` trait.
+/// A `Bind<S>` can be converted to a [`ProxyStream`](socks5_http::ProxyStream) by using the `From` trait.
 #[derive(Debug)]
 pub struct Bind<S> {
-    stream: TcpStream,
+    stream: ProxyStream,
     _state: PhantomData<S>,
 }
 
@@ -43,7 +38,7 @@ pub struct Ready;
 
 impl Bind<NeedFirstReply> {
     #[inline]
-    pub(super) fn new(stream: TcpStream) -> Self {
+    pub(super) fn new(stream: ProxyStream) -> Self {
         Self {
             stream,
             _state: PhantomData,
@@ -52,7 +47,7 @@ impl Bind<NeedFirstReply> {
 
     /// Reply to the SOCKS5 client with the given reply and address.
     ///
-    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
+    /// If encountered an error while writing the reply, the error alongside the original `ProxyStream` is returned.
     pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<Bind<NeedSecondReply>> {
         let resp = Response::new(reply, addr);
         resp.write_to_async_stream(&mut self.stream).await?;
@@ -82,7 +77,7 @@ impl Bind<NeedFirstReply> {
     /// For more information about this option, see [`set_linger`](crate::server::connection::Bind::set_linger).
     #[inline]
     pub fn linger(&self) -> std::io::Result<Option<Duration>> {
-        self.stream.linger()
+        self.stream.tcp().linger()
     }
 
     /// Sets the linger duration of this socket by setting the `SO_LINGER` option.
@@ -93,8 +88,9 @@ impl Bind<NeedFirstReply> {
     /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
     /// that allows the process to continue as quickly as possible.
     #[inline]
+    #[allow(deprecated)]
     pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
-        self.stream.set_linger(dur)
+        self.stream.tcp().set_linger(dur)
     }
 
     /// Gets the value of the `TCP_NODELAY` option on this socket.
@@ -102,7 +98,7 @@ impl Bind<NeedFirstReply> {
     /// For more information about this option, see [`set_nodelay`](crate::server::connection::Bind::set_nodelay).
     #[inline]
     pub fn nodelay(&self) -> std::io::Result<bool> {
-        self.stream.nodelay()
+        self.stream.tcp().nodelay()
     }
 
     /// Sets the value of the `TCP_NODELAY` option on this socket.
@@ -111,27 +107,27 @@ impl Bind<NeedFirstReply> {
     /// even if there is only a small amount of data. When not set, data is buffered until there is a sufficient amount to send out,
     /// thereby avoiding the frequent sending of small packets.
     pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
-        self.stream.set_nodelay(nodelay)
+        self.stream.tcp().set_nodelay(nodelay)
     }
 
     /// Gets the value of the `IP_TTL` option for this socket.
     ///
     /// For more information about this option, see [`set_ttl`](crate::server::connection::Bind::set_ttl).
     pub fn ttl(&self) -> std::io::Result<u32> {
-        self.stream.ttl()
+        self.stream.tcp().ttl()
     }
 
     /// Sets the value for the `IP_TTL` option on this socket.
     ///
     /// This value sets the time-to-live field that is used in every packet sent from this socket.
     pub fn set_ttl(&self, ttl: u32) -> std::io::Result<()> {
-        self.stream.set_ttl(ttl)
+        self.stream.tcp().set_ttl(ttl)
     }
 }
 
 impl Bind<NeedSecondReply> {
     #[inline]
-    fn new(stream: TcpStream) -> Self {
+    fn new(stream: ProxyStream) -> Self {
         Self {
             stream,
             _state: PhantomData,
@@ -140,8 +136,8 @@ impl Bind<NeedSecondReply> {
 
     /// Reply to the SOCKS5 client with the given reply and address.
     ///
-    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
-    pub async fn reply(mut self, reply: Reply, addr: Address) -> Result<Bind<Ready>, (error::Error, TcpStream)> {
+    /// If encountered an error while writing the reply, the error alongside the original `ProxyStream` is returned.
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> Result<Bind<Ready>, (error::Error, ProxyStream)> {
         let resp = Response::new(reply, addr);
 
         if let Err(err) = resp.write_to_async_stream(&mut self.stream).await {
@@ -174,7 +170,7 @@ impl Bind<NeedSecondReply> {
     /// For more information about this option, see [`set_linger`](crate::server::connection::Bind::set_linger).
     #[inline]
     pub fn linger(&self) -> std::io::Result<Option<Duration>> {
-        self.stream.linger()
+        self.stream.tcp().linger()
     }
 
     /// Sets the linger duration of this socket by setting the `SO_LINGER` option.
@@ -185,8 +181,9 @@ impl Bind<NeedSecondReply> {
     /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
     /// that allows the process to continue as quickly as possible.
     #[inline]
+    #[allow(deprecated)]
     pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
-        self.stream.set_linger(dur)
+        self.stream.tcp().set_linger(dur)
     }
 
     /// Gets the value of the `TCP_NODELAY` option on this socket.
@@ -195,7 +192,7 @@ impl Bind<NeedSecondReply> {
     /// [`set_nodelay`](crate::server::connection::Bind::set_nodelay).
     #[inline]
     pub fn nodelay(&self) -> std::io::Result<bool> {
-        self.stream.nodelay()
+        self.stream.tcp().nodelay()
     }
 
     /// Sets the value of the `TCP_NODELAY` option on this socket.
@@ -204,27 +201,27 @@ impl Bind<NeedSecondReply> {
     /// even if there is only a small amount of data. When not set, data is buffered until there is a sufficient amount to send out,
     /// thereby avoiding the frequent sending of small packets.
     pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
-        self.stream.set_nodelay(nodelay)
+        self.stream.tcp().set_nodelay(nodelay)
     }
 
     /// Gets the value of the `IP_TTL` option for this socket.
     ///
     /// For more information about this option, see [`set_ttl`](crate::server::connection::Bind::set_ttl).
     pub fn ttl(&self) -> std::io::Result<u32> {
-        self.stream.ttl()
+        self.stream.tcp().ttl()
     }
 
     /// Sets the value for the `IP_TTL` option on this socket.
     ///
     /// This value sets the time-to-live field that is used in every packet sent from this socket.
     pub fn set_ttl(&self, ttl: u32) -> std::io::Result<()> {
-        self.stream.set_ttl(ttl)
+        self.stream.tcp().set_ttl(ttl)
     }
 }
 
 impl Bind<Ready> {
     #[inline]
-    fn new(stream: TcpStream) -> Self {
+    fn new(stream: ProxyStream) -> Self {
         Self {
             stream,
             _state: PhantomData,
@@ -233,13 +230,13 @@ impl Bind<Ready> {
 
     /// Split the connection into a read and a write half.
     #[inline]
-    pub fn split(&mut self) -> (ReadHalf, WriteHalf) {
-        self.stream.split()
+    pub fn split(&mut self) -> (ReadHalf<&mut ProxyStream>, WriteHalf<&mut ProxyStream>) {
+        io::split(&mut self.stream)
     }
 }
 
 impl std::ops::Deref for Bind<Ready> {
-    type Target = TcpStream;
+    type Target = ProxyStream;
 
     #[inline]
     fn deref(&self) -> &Self::Target {
@@ -278,7 +275,7 @@ impl AsyncWrite for Bind<Ready> {
     }
 }
 
-impl<S> From<Bind<S>> for TcpStream {
+impl<S> From<Bind<S>> for ProxyStream {
     #[inline]
     fn from(conn: Bind<S>) -> Self {
         conn.stream
@@ -5,26 +5,21 @@ use std::{
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::{
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf},
-        TcpStream,
-    },
-};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use socks5_http::ProxyStream;
 
 /// Socks5 connection type `Connect`
 ///
 /// This connection can be used as a regular async TCP stream after replying the client.
 #[derive(Debug)]
 pub struct Connect<S> {
-    stream: TcpStream,
+    stream: ProxyStream,
     _state: S,
 }
 
 impl<S: Default> Connect<S> {
     #[inline]
-    pub(super) fn new(stream: TcpStream) -> Self {
+    pub(super) fn new(stream: ProxyStream) -> Self {
         Self {
             stream,
             _state: S::default(),
@@ -69,19 +64,19 @@ impl Connect<NeedReply> {
 impl Connect<Ready> {
     /// Returns the read/write half of the stream.
     #[inline]
-    pub fn split(&mut self) -> (ReadHalf, WriteHalf) {
-        self.stream.split()
+    pub fn split(&mut self) -> (ReadHalf<&mut ProxyStream>, WriteHalf<&mut ProxyStream>) {
+        io::split(&mut self.stream)
     }
 
     /// Returns the owned read/write half of the stream.
     #[inline]
-    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
-        self.stream.into_split()
+    pub fn into_split(self) -> (ReadHalf<ProxyStream>, WriteHalf<ProxyStream>) {
+        io::split(self.stream)
     }
 }
 
 impl std::ops::Deref for Connect<Ready> {
-    type Target = TcpStream;
+    type Target = ProxyStream;
 
     #[inline]
     fn deref(&self) -> &Self::Target {
@@ -130,7 +125,7 @@ impl AsyncWrite for Connect<Ready> {
     }
 }
 
-impl<S> From<Connect<S>> for TcpStream {
+impl<S> From<Connect<S>> for ProxyStream {
     #[inline]
     fn from(conn: Connect<S>) -> Self {
         conn.stream
@@ -1,24 +1,26 @@
-use self::{associate::UdpAssociate, bind::Bind, connect::Connect};
+use self::{associate::UdpAssociate, bind::Bind, connect::Connect, resolve::Resolve};
 use socks5_protocol::{self, handshake, Address, AsyncStreamOperation, AuthMethod, Command};
 use std::{net::SocketAddr, time::Duration};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::io::AsyncWriteExt;
+use socks5_http::ProxyStream;
 use error::Result;
 use crate::socks5_server::AuthAdaptor;
 
 pub mod associate;
 pub mod bind;
 pub mod connect;
+pub mod resolve;
 
 /// An incoming connection. This may not be a valid socks5 connection. You need to call [`authenticate()`](#method.authenticate)
 /// to perform the socks5 handshake. It will be converted to a proper socks5 connection after the handshake succeeds.
 pub struct IncomingConnection<O> {
-    stream: TcpStream,
+    stream: ProxyStream,
     auth: AuthAdaptor<O>,
 }
 
 impl<O: 'static> IncomingConnection<O> {
     #[inline]
-    pub fn new(stream: TcpStream, auth: AuthAdaptor<O>) -> Self {
+    pub fn new(stream: ProxyStream, auth: AuthAdaptor<O>) -> Self {
         IncomingConnection { stream, auth }
     }
 
@@ -45,7 +47,7 @@ impl<O: 'static> IncomingConnection<O> {
     /// For more information about this option, see [`set_linger`](crate::server::connection::IncomingConnection::set_linger).
     #[inline]
     pub fn linger(&self) -> std::io::Result<Option<Duration>> {
-        self.stream.linger()
+        self.stream.tcp().linger()
     }
 
     /// Sets the linger duration of this socket by setting the `SO_LINGER` option.
@@ -56,8 +58,9 @@ impl<O: 'static> IncomingConnection<O> {
     /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
     /// that allows the process to continue as quickly as possible.
     #[inline]
+    #[allow(deprecated)]
     pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
-        self.stream.set_linger(dur)
+        self.stream.tcp().set_linger(dur)
     }
 
     /// Gets the value of the `TCP_NODELAY` option on this socket.
@@ -66,7 +69,7 @@ impl<O: 'static> IncomingConnection<O> {
     /// [`set_nodelay`](#method.set_nodelay).
     #[inline]
     pub fn nodelay(&self) -> std::io::Result<bool> {
-        self.stream.nodelay()
+        self.stream.tcp().nodelay()
     }
 
     /// Sets the value of the `TCP_NODELAY` option on this socket.
@@ -75,7 +78,7 @@ impl<O: 'static> IncomingConnection<O> {
     /// even if there is only a small amount of data. When not set, data is buffered until there is a sufficient amount
     /// to send out, thereby avoiding the frequent sending of small packets.
     pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
-        self.stream.set_nodelay(nodelay)
+        self.stream.tcp().set_nodelay(nodelay)
     }
 
     /// Gets the value of the `IP_TTL` option for this socket.
@@ -83,14 +86,14 @@ impl<O: 'static> IncomingConnection<O> {
     /// For more information about this option, see
     /// [`set_ttl`](#method.set_ttl).
     pub fn ttl(&self) -> std::io::Result<u32> {
-        self.stream.ttl()
+        self.stream.tcp().ttl()
     }
 
     /// Sets the value for the `IP_TTL` option on this socket.
     ///
     /// This value sets the time-to-live field that is used in every packet sent from this socket.
     pub fn set_ttl(&self, ttl: u32) -> std::io::Result<()> {
-        self.stream.set_ttl(ttl)
+        self.stream.tcp().set_ttl(ttl)
     }
 
     /// Perform a SOCKS5 authentication handshake using the given
@@ -98,15 +101,15 @@ impl<O: 'static> IncomingConnection<O> {
     ///
     /// If the handshake succeeds, an [`Authenticated`]
     /// alongs with the output of the [`AuthExecutor`](crate::server::auth::AuthExecutor) adapter is returned.
-    /// Otherwise, the error and the original [`TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) is returned.
+    /// Otherwise, the error and the original [`ProxyStream`](socks5_http::ProxyStream) is returned.
     ///
     /// Note that this method will not implicitly close the connection even if the handshake failed.
     pub async fn authenticate(mut self) -> std::io::Result<(Authenticated, O)> {
         let request = handshake::Request::retrieve_from_async_stream(&mut self.stream).await?;
-        if let Some(method) = self.evaluate_request(&request) {
+        if let Some(method) = self.auth.select_method(request.methods()) {
             let response = handshake::Response::new(method);
             response.write_to_async_stream(&mut self.stream).await?;
-            let output = self.auth.execute(&mut self.stream).await;
+            let output = self.auth.execute_with(method, &mut self.stream).await;
             Ok((Authenticated::new(self.stream), output))
         } else {
             let response = handshake::Response::new(AuthMethod::NoAcceptableMethods);
@@ -115,15 +118,6 @@ impl<O: 'static> IncomingConnection<O> {
             Err(std::io::Error::new(std::io::ErrorKind::Unsupported, err))
         }
     }
-
-    fn evaluate_request(&self, req: &handshake::Request) -> Option<AuthMethod> {
-        let method = self.auth.auth_method();
-        if req.evaluate_method(method) {
-            Some(method)
-        } else {
-            None
-        }
-    }
 }
 
 impl<O> std::fmt::Debug for IncomingConnection<O> {
@@ -132,7 +126,7 @@ impl<O> std::fmt::Debug for IncomingConnection<O> {
     }
 }
 
-impl<O> From<IncomingConnection<O>> for TcpStream {
+impl<O> From<IncomingConnection<O>> for ProxyStream {
     #[inline]
     fn from(conn: IncomingConnection<O>) -> Self {
         conn.stream
@@ -144,12 +138,12 @@ impl<O> From<IncomingConnection<O>> for TcpStream {
 /// To get the command from the SOCKS5 client, use
 /// [`wait_request`](crate::server::connection::Authenticated::wait_request).
 ///
-/// It can also be converted back into a raw [`tokio::TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) with `From` trait.
-pub struct Authenticated(TcpStream);
+/// It can also be converted back into a [`ProxyStream`](socks5_http::ProxyStream) with `From` trait.
+pub struct Authenticated(ProxyStream);
 
 impl Authenticated {
     #[inline]
-    fn new(stream: TcpStream) -> Self {
+    fn new(stream: ProxyStream) -> Self {
         Self(stream)
     }
 
@@ -170,6 +164,8 @@ impl Authenticated {
             )),
             Command::Bind => Ok(ClientConnection::Bind(Bind::<bind::NeedFirstReply>::new(self.0), req.address)),
             Command::Connect => Ok(ClientConnection::Connect(Connect::<connect::NeedReply>::new(self.0), req.address)),
+            Command::Resolve => Ok(ClientConnection::Resolve(Resolve::new(self.0), req.address)),
+            Command::ResolvePtr => Ok(ClientConnection::ResolvePtr(Resolve::new(self.0), req.address)),
         }
     }
 
@@ -197,7 +193,7 @@ impl Authenticated {
     /// [`set_linger`](crate::server::connection::Authenticated::set_linger).
     #[inline]
     pub fn linger(&self) -> std::io::Result<Option<Duration>> {
-        self.0.linger()
+        self.0.tcp().linger()
     }
 
     /// Sets the linger duration of this socket by setting the `SO_LINGER` option.
@@ -208,8 +204,9 @@ impl Authenticated {
     /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
     /// that allows the process to continue as quickly as possible.
     #[inline]
+    #[allow(deprecated)]
     pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
-        self.0.set_linger(dur)
+        self.0.tcp().set_linger(dur)
     }
 
     /// Gets the value of the `TCP_NODELAY` option on this socket.
@@ -218,7 +215,7 @@ impl Authenticated {
     /// [`set_nodelay`](crate::server::connection::Authenticated::set_nodelay).
     #[inline]
     pub fn nodelay(&self) -> std::io::Result<bool> {
-        self.0.nodelay()
+        self.0.tcp().nodelay()
     }
 
     /// Sets the value of the `TCP_NODELAY` option on this socket.
@@ -227,7 +224,7 @@ impl Authenticated {
     /// even if there is only a small amount of data. When not set, data is buffered until there is a sufficient amount to send out,
     /// thereby avoiding the frequent sending of small packets.
     pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
-        self.0.set_nodelay(nodelay)
+        self.0.tcp().set_nodelay(nodelay)
     }
 
     /// Gets the value of the `IP_TTL` option for this socket.
@@ -235,18 +232,18 @@ impl Authenticated {
     /// For more information about this option, see
     /// [`set_ttl`](crate::server::connection::Authenticated::set_ttl).
     pub fn ttl(&self) -> std::io::Result<u32> {
-        self.0.ttl()
+        self.0.tcp().ttl()
     }
 
     /// Sets the value for the `IP_TTL` option on this socket.
     ///
     /// This value sets the time-to-live field that is used in every packet sent from this socket.
     pub fn set_ttl(&self, ttl: u32) -> std::io::Result<()> {
-        self.0.set_ttl(ttl)
+        self.0.tcp().set_ttl(ttl)
     }
 }
 
-impl From<Authenticated> for TcpStream {
+impl From<Authenticated> for ProxyStream {
     #[inline]
     fn from(conn: Authenticated) -> Self {
         conn.0
@@ -258,9 +255,12 @@ impl From<Authenticated> for TcpStream {
 /// - Associate
 /// - Bind
 /// - Connect
+/// - Resolve / ResolvePtr (Tor extensions)
 #[derive(Debug)]
 pub enum ClientConnection {
     UdpAssociate(UdpAssociate<associate::NeedReply>, Address),
     Bind(Bind<bind::NeedFirstReply>, Address),
     Connect(Connect<connect::NeedReply>, Address),
+    Resolve(Resolve, Address),
+    ResolvePtr(Resolve, Address),
 }
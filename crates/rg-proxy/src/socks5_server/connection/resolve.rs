@@ -0,0 +1,46 @@
+use socks5_protocol::{Address, AsyncStreamOperation, Reply, Response};
+use std::net::SocketAddr;
+use tokio::io::AsyncWriteExt;
+use socks5_http::ProxyStream;
+
+/// Socks5 connection type `Resolve`, serving the Tor extension commands `RESOLVE` and `RESOLVE_PTR`.
+///
+/// No stream is opened, the answer is carried in the address of the only reply,
+/// and the connection is closed afterwards.
+#[derive(Debug)]
+pub struct Resolve {
+    stream: ProxyStream,
+}
+
+impl Resolve {
+    #[inline]
+    pub(super) fn new(stream: ProxyStream) -> Self {
+        Self { stream }
+    }
+
+    /// Reply to the client with the given reply and resolved address, then close the connection.
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<()> {
+        let resp = Response::new(reply, addr);
+        resp.write_to_async_stream(&mut self.stream).await?;
+        self.stream.shutdown().await
+    }
+
+    /// Returns the local address that this stream is bound to.
+    #[inline]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    /// Returns the remote address that this stream is connected to.
+    #[inline]
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+}
+
+impl From<Resolve> for ProxyStream {
+    #[inline]
+    fn from(conn: Resolve) -> Self {
+        conn.stream
+    }
+}
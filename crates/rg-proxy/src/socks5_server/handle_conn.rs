@@ -1,59 +1,41 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use as_any::AsAny;
-use tokio::io;
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::Mutex;
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
 use error::Error;
-use socks5_protocol::{Address, Reply, UdpHeader};
-use crate::socks5_server::{AssociatedUdpSocket, AuthAdaptor, ClientConnection, IncomingConnection, UdpAssociate};
+use rg_common::user_auth::UserInfo;
+use rg_stat::RequestType;
+use socks5_protocol::udp::{split_fragments, MAX_UDP_DATAGRAM_SIZE, UDP_REASSEMBLY_TIMEOUT};
+use socks5_protocol::{Address, Reply, StreamOperation, UdpHeader, UdpReassembler};
+use crate::backend::{address_host, CommonBackend, ConnInfo};
+use crate::resolver::resolve_host;
+use crate::socks5_server::{AssociatedUdpSocket, UdpAssociate};
 use crate::socks5_server::connection::associate;
 
 pub(crate) static MAX_UDP_RELAY_PACKET_SIZE: usize = 1500;
-pub async fn handle<S>(auth: AuthAdaptor<S>,stream: TcpStream) -> error::Result<()>
-where
-    S: Send + Sync + 'static,
-{
-    let conn = IncomingConnection::new(stream, auth);
-    let (conn, res) = conn.authenticate().await?;
-
-    if let Some(res) = res.as_any().downcast_ref::<std::io::Result<bool>>() {
-        let res = *res.as_ref().map_err(|err| err.to_string())?;
-        if !res {
-            tracing::info!("authentication failed");
-            return Ok(());
-        }
-    }
-
-    match conn.wait_request().await? {
-        ClientConnection::UdpAssociate(associate, _) => {
-            handle_s5_upd_associate(associate).await?;
-        }
-        ClientConnection::Bind(bind, _) => {
-            let mut conn = bind.reply(Reply::CommandNotSupported, Address::unspecified()).await?;
-            conn.shutdown().await?;
-        }
-        ClientConnection::Connect(connect, addr) => {
-            let target = match addr {
-                Address::DomainAddress(domain, port) => TcpStream::connect((domain, port)).await,
-                Address::SocketAddress(addr) => TcpStream::connect(addr).await,
-            };
-
-            if let Ok(mut target) = target {
-                let mut conn = connect.reply(Reply::Succeeded, Address::unspecified()).await?;
-                tracing::trace!("{} -> {}", conn.peer_addr()?, target.peer_addr()?);
-                io::copy_bidirectional(&mut target, &mut conn).await?;
-            } else {
-                let mut conn = connect.reply(Reply::HostUnreachable, Address::unspecified()).await?;
-                conn.shutdown().await?;
-            }
-        }
-    }
+/// Client sources one association may map to outbound sockets.
+static MAX_UDP_NAT_MAPPINGS: usize = 64;
+/// Bounds the resolved destinations and remote peers an association remembers.
+static MAX_UDP_NAT_CACHE_SIZE: usize = 1024;
+static UDP_REPLY_QUEUE_SIZE: usize = 256;
 
-    Ok(())
-}
-
-pub(crate) async fn handle_s5_upd_associate(associate: UdpAssociate<associate::NeedReply>) -> error::Result<()> {
+/// Relays the datagrams of an UDP association until the control connection closes or the user gets killed.
+///
+/// Every datagram is checked against the acl for its destination and accounted to `user_info`.
+/// Only datagrams from the client source given in the request (or the control connection's peer) are relayed,
+/// replies only from remote peers the client sent to, and the association expires after the configured idle period.
+pub(crate) async fn handle_s5_upd_associate(
+    backend: &CommonBackend,
+    associate: UdpAssociate<associate::NeedReply>,
+    client_addr: Address,
+    user_info: &UserInfo,
+    conn_info: &ConnInfo,
+) -> error::Result<()> {
     // listen on a random port
     let listen_ip = associate.local_addr()?.ip();
     let udp_listener = UdpSocket::bind(SocketAddr::from((listen_ip, 0))).await;
@@ -70,59 +52,339 @@ pub(crate) async fn handle_s5_upd_associate(associate: UdpAssociate<associate::N
             let s5_listen_addr = Address::from(listen_addr);
             let mut reply_listener = associate.reply(Reply::Succeeded, s5_listen_addr).await?;
 
-            let buf_size = MAX_UDP_RELAY_PACKET_SIZE - UdpHeader::max_serialized_len();
-            let listen_udp = Arc::new(AssociatedUdpSocket::from((listen_udp, buf_size)));
+            let listen_udp = AssociatedUdpSocket::from((listen_udp, MAX_UDP_RELAY_PACKET_SIZE));
 
-            let zero_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+            let mut reassembler = UdpReassembler::new(UDP_REASSEMBLY_TIMEOUT, MAX_UDP_DATAGRAM_SIZE);
+            // a client that does not implement fragmentation drops fragments, replies are only split for
+            // clients that sent fragments themselves
+            let mut fragmenting_client = false;
+            let (reply_tx, mut reply_rx) = mpsc::channel(UDP_REPLY_QUEUE_SIZE);
+            let mut nat = UdpNat::new(conn_info.local_addr, reply_tx);
 
-            let incoming_addr = Arc::new(Mutex::new(zero_addr));
+            let udp_config = &backend.config.udp;
+            let mut source = SourcePin::new(&client_addr, conn_info.remote_addr, udp_config.pin_source_port);
+            let idle_timeout = Duration::from_secs(udp_config.idle_timeout_secs);
+            let mut last_active = Instant::now();
 
-            let dispatch_socket = UdpSocket::bind(zero_addr).await?;
+            backend.request_stat(RequestType::Socks5);
+            let (shutdown_tx, mut shutdown_rx) = tokio::sync::broadcast::channel::<()>(10);
+            let id = &shutdown_tx as *const _ as usize;
+            backend.conn_set.add(user_info.user_id, id, shutdown_tx.clone());
 
             let res = loop {
                 tokio::select! {
-                    res = async {
-                        let buf_size = MAX_UDP_RELAY_PACKET_SIZE - UdpHeader::max_serialized_len();
-                        listen_udp.set_max_packet_size(buf_size);
-
-                        let (pkt, frag, dst_addr, src_addr) = listen_udp.recv_from().await?;
-                        if frag != 0 {
-                            return Err("[UDP] packet fragment is not supported".into());
+                    res = listen_udp.recv_from() => {
+                        let (pkt, frag, dst_addr, src_addr) = match res {
+                            Ok(res) => res,
+                            Err(e) => break Err(e.into()),
+                        };
+                        if !source.accept(src_addr) {
+                            tracing::debug!("[UDP] drop packet from unexpected source: {src_addr}");
+                            continue;
                         }
-
-                        *incoming_addr.lock().await = src_addr;
+                        last_active = Instant::now();
+                        fragmenting_client |= frag != 0;
+                        let Some((dst_addr, pkt)) = reassembler.push(frag, dst_addr, pkt) else {
+                            tracing::trace!("[UDP] {src_addr} queued fragment {frag:#x}");
+                            continue;
+                        };
 
                         tracing::trace!("[UDP] {src_addr} -> {dst_addr} incoming packet size {}", pkt.len());
-                        let dst_addr = dst_addr.to_socket_addrs()?.next().ok_or("Invalid address")?;
-                        dispatch_socket.send_to(&pkt, dst_addr).await?;
-                        Ok::<_, Error>(())
-                    } => {
-                        if res.is_err() {
-                            break res;
+                        let (host, hostname, _) = address_host(&dst_addr);
+                        if !backend.acl.read().await.check(user_info, &host, &conn_info.local_ip) {
+                            tracing::debug!("[UDP] drop forbidden packet from user: {}, host: {}", user_info.user_id, host);
+                            continue;
+                        }
+                        match nat.send_to(src_addr, &pkt, dst_addr, hostname).await {
+                            Ok(hostname) => backend.traffic_stat(user_info, &hostname, conn_info, pkt.len() as u64, true),
+                            Err(e) => tracing::debug!("[UDP] {src_addr} drop packet to {host}: {e}"),
                         }
                     },
-                    res = async {
-                        let mut buf = vec![0u8; MAX_UDP_RELAY_PACKET_SIZE];
-                        let (len, remote_addr) = dispatch_socket.recv_from(&mut buf).await?;
-                        let incoming_addr = *incoming_addr.lock().await;
+                    Some((incoming_addr, remote_addr, pkt)) = reply_rx.recv() => {
+                        // the peer passed the acl when the client sent to it, anyone else is not relayed nor accounted
+                        let Some(hostname) = nat.peer(remote_addr) else {
+                            tracing::debug!("[UDP] drop unsolicited packet from {remote_addr} to {incoming_addr}");
+                            continue;
+                        };
                         tracing::trace!("[UDP] {incoming_addr} <- {remote_addr} feedback to incoming");
-                        listen_udp.send_to(&buf[..len], 0, remote_addr.into(), incoming_addr).await?;
-                        Ok::<_, Error>(())
-                    } => {
-                        if res.is_err() {
-                            break res;
+                        last_active = Instant::now();
+                        if let Err(e) = send_to_incoming(&listen_udp, &pkt, remote_addr.into(), incoming_addr, fragmenting_client).await {
+                            break Err(e);
                         }
+                        backend.traffic_stat(user_info, &hostname, conn_info, pkt.len() as u64, false);
+                    },
+                    _ = tokio::time::sleep_until(last_active + idle_timeout), if !idle_timeout.is_zero() => {
+                        tracing::info!("[UDP] {} idle for {:?}, release the association...", listen_addr, idle_timeout);
+                        break Ok::<_, Error>(());
                     },
                     _ = reply_listener.wait_until_closed() => {
                         tracing::trace!("[UDP] {} listener closed", listen_addr);
                         break Ok::<_, Error>(());
                     },
+                    _ = shutdown_rx.recv() => {
+                        tracing::info!("[UDP] get shutdown signal, release the association...");
+                        break Ok::<_, Error>(());
+                    },
                 };
             };
+            let _ = shutdown_tx.send(());
+            backend.conn_set.remove(user_info.user_id, id);
 
             reply_listener.shutdown().await?;
 
             res
         }
     }
-}
\ No newline at end of file
+}
+
+/// The client source an association accepts datagrams from.
+///
+/// The ip comes from the ASSOCIATE request, or from the control connection's peer if the request leaves it unspecified.
+/// With port pinning the port comes from the request, or is taken from the first accepted datagram.
+struct SourcePin {
+    ip: IpAddr,
+    port: Option<u16>,
+    pin_port: bool,
+}
+
+impl SourcePin {
+    fn new(client_addr: &Address, control_peer: SocketAddr, pin_port: bool) -> Self {
+        let (ip, port) = match client_addr {
+            Address::SocketAddress(addr) if !addr.ip().is_unspecified() => (addr.ip(), addr.port()),
+            Address::SocketAddress(addr) => (control_peer.ip(), addr.port()),
+            Address::DomainAddress(_, port) => (control_peer.ip(), *port),
+        };
+        let port = if pin_port && port != 0 { Some(port) } else { None };
+        Self { ip, port, pin_port }
+    }
+
+    fn accept(&mut self, src_addr: SocketAddr) -> bool {
+        if src_addr.ip().to_canonical() != self.ip.to_canonical() {
+            return false;
+        }
+        if !self.pin_port {
+            return true;
+        }
+        *self.port.get_or_insert(src_addr.port()) == src_addr.port()
+    }
+}
+
+/// Port-restricted cone NAT of an association.
+///
+/// Every client source gets its own outbound socket bound to the egress ip, datagrams a remote peer the client
+/// sent to sends to that socket are relayed back to the client source it belongs to.
+struct UdpNat {
+    egress_ip: IpAddr,
+    mappings: HashMap<SocketAddr, Arc<UdpSocket>>,
+    reply_tx: mpsc::Sender<(SocketAddr, SocketAddr, Bytes)>,
+    tasks: JoinSet<()>,
+    // domain destinations resolved through the async resolver
+    resolved: HashMap<(String, u16), SocketAddr>,
+    // remote peers the client sent to -> hostname reported in traffic stats
+    peers: HashMap<SocketAddr, String>,
+}
+
+impl UdpNat {
+    fn new(egress_ip: IpAddr, reply_tx: mpsc::Sender<(SocketAddr, SocketAddr, Bytes)>) -> Self {
+        Self {
+            egress_ip,
+            mappings: HashMap::new(),
+            reply_tx,
+            tasks: JoinSet::new(),
+            resolved: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Sends a datagram from `client_addr` to `dst_addr` through its mapping, returns the hostname it was accounted to.
+    async fn send_to(&mut self, client_addr: SocketAddr, pkt: &[u8], dst_addr: Address, hostname: String) -> error::Result<String> {
+        let dst_addr = self.resolve(dst_addr).await?;
+        let socket = self.outbound(client_addr).await?;
+        socket.send_to(pkt, dst_addr).await?;
+
+        // a forgotten peer is learned again by the next datagram the client sends to it
+        if self.peers.len() >= MAX_UDP_NAT_CACHE_SIZE && !self.peers.contains_key(&dst_addr) {
+            self.peers.clear();
+        }
+        self.peers.insert(dst_addr, hostname.clone());
+        Ok(hostname)
+    }
+
+    /// The hostname of `remote_addr` if the client sent to it, datagrams from other peers are dropped.
+    fn peer(&self, remote_addr: SocketAddr) -> Option<String> {
+        self.peers.get(&remote_addr).cloned()
+    }
+
+    async fn resolve(&mut self, addr: Address) -> error::Result<SocketAddr> {
+        match addr {
+            Address::SocketAddress(addr) => Ok(addr),
+            Address::DomainAddress(domain, port) => {
+                let key = (domain, port);
+                if let Some(addr) = self.resolved.get(&key) {
+                    return Ok(*addr);
+                }
+                let addr = resolve_host(&key.0, port).await?;
+                if self.resolved.len() >= MAX_UDP_NAT_CACHE_SIZE {
+                    self.resolved.clear();
+                }
+                self.resolved.insert(key, addr);
+                Ok(addr)
+            }
+        }
+    }
+
+    async fn outbound(&mut self, client_addr: SocketAddr) -> error::Result<Arc<UdpSocket>> {
+        if let Some(socket) = self.mappings.get(&client_addr) {
+            return Ok(socket.clone());
+        }
+        if self.mappings.len() >= MAX_UDP_NAT_MAPPINGS {
+            return Err(Error::from(format!("too many udp mappings, drop source {}", client_addr)));
+        }
+
+        let socket = Arc::new(UdpSocket::bind(SocketAddr::new(self.egress_ip, 0)).await?);
+        tracing::info!("[UDP] map {} to {}", client_addr, socket.local_addr()?);
+        let reply_tx = self.reply_tx.clone();
+        let recv_socket = socket.clone();
+        self.tasks.spawn(async move {
+            let mut buf = vec![0u8; MAX_UDP_DATAGRAM_SIZE];
+            loop {
+                let (len, remote_addr) = match recv_socket.recv_from(&mut buf).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        tracing::debug!("[UDP] mapping of {} closed: {}", client_addr, e);
+                        break;
+                    }
+                };
+                let pkt = Bytes::copy_from_slice(&buf[..len]);
+                if reply_tx.send((client_addr, remote_addr, pkt)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        self.mappings.insert(client_addr, socket.clone());
+        Ok(socket)
+    }
+}
+
+/// Sends a datagram back to the client.
+///
+/// With `fragment` a datagram that does not fit into one relay packet is split into fragments, otherwise it is
+/// sent whole.
+pub(crate) async fn send_to_incoming(
+    listen_udp: &AssociatedUdpSocket,
+    pkt: &[u8],
+    from_addr: Address,
+    incoming_addr: SocketAddr,
+    fragment: bool,
+) -> error::Result<()> {
+    if !fragment {
+        listen_udp.send_to(pkt, 0, from_addr, incoming_addr).await?;
+        return Ok(());
+    }
+    let max_payload = MAX_UDP_RELAY_PACKET_SIZE - UdpHeader::new(0, from_addr.clone()).len();
+    for (frag, chunk) in split_fragments(pkt, max_payload)? {
+        listen_udp.send_to(chunk, frag, from_addr.clone(), incoming_addr).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_source_pin() {
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+
+        let mut pin = SourcePin::new(&Address::unspecified(), peer, false);
+        assert!(pin.accept("10.0.0.1:6000".parse().unwrap()));
+        assert!(pin.accept("10.0.0.1:6001".parse().unwrap()));
+        assert!(!pin.accept("10.0.0.2:6000".parse().unwrap()));
+
+        let mut pin = SourcePin::new(&Address::unspecified(), peer, true);
+        assert!(pin.accept("10.0.0.1:6000".parse().unwrap()));
+        assert!(!pin.accept("10.0.0.1:6001".parse().unwrap()));
+
+        let requested = Address::from("10.0.0.3:7000".parse::<SocketAddr>().unwrap());
+        let mut pin = SourcePin::new(&requested, peer, true);
+        assert!(!pin.accept("10.0.0.1:7000".parse().unwrap()));
+        assert!(!pin.accept("10.0.0.3:7001".parse().unwrap()));
+        assert!(pin.accept("10.0.0.3:7000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_send_to_incoming() {
+        let relay = AssociatedUdpSocket::from((UdpSocket::bind("127.0.0.1:0").await.unwrap(), MAX_UDP_RELAY_PACKET_SIZE));
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client_socket.local_addr().unwrap();
+        let client = AssociatedUdpSocket::from((client_socket, MAX_UDP_DATAGRAM_SIZE));
+        let remote = Address::from("10.0.0.1:53".parse::<SocketAddr>().unwrap());
+        let pkt = (0..4000).map(|i| i as u8).collect::<Vec<_>>();
+
+        // a client that never sent fragments gets the datagram whole
+        send_to_incoming(&relay, &pkt, remote.clone(), client_addr, false).await.unwrap();
+        let (data, frag, from, _) = client.recv_from().await.unwrap();
+        assert_eq!((frag, from), (0, remote.clone()));
+        assert_eq!(data.as_ref(), pkt.as_slice());
+
+        send_to_incoming(&relay, &pkt, remote.clone(), client_addr, true).await.unwrap();
+        let mut reassembler = UdpReassembler::new(UDP_REASSEMBLY_TIMEOUT, MAX_UDP_DATAGRAM_SIZE);
+        let mut frags = Vec::new();
+        let (from, data) = loop {
+            let (data, frag, from, _) = client.recv_from().await.unwrap();
+            assert!(data.len() <= MAX_UDP_RELAY_PACKET_SIZE);
+            frags.push(frag);
+            if let Some(datagram) = reassembler.push(frag, from, data) {
+                break datagram;
+            }
+        };
+        assert_eq!(frags, [1, 2, 0x83]);
+        assert_eq!(from, remote);
+        assert_eq!(data.as_ref(), pkt.as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_udp_nat() {
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let (reply_tx, mut reply_rx) = mpsc::channel(UDP_REPLY_QUEUE_SIZE);
+        let mut nat = UdpNat::new("127.0.0.1".parse().unwrap(), reply_tx);
+        let client: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+
+        // one client source keeps its outbound socket, whatever the destination form
+        let hostname = nat.send_to(client, b"a", remote_addr.into(), "remote.example".to_owned()).await.unwrap();
+        assert_eq!(hostname, "remote.example");
+        let mut buf = [0; 16];
+        let (_, first) = remote.recv_from(&mut buf).await.unwrap();
+        let domain = Address::DomainAddress("localhost".to_owned(), remote_addr.port());
+        nat.send_to(client, b"b", domain, "localhost".to_owned()).await.unwrap();
+        let (_, second) = remote.recv_from(&mut buf).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(nat.mappings.len(), 1);
+        assert_eq!(nat.resolved.len(), 1);
+
+        // replies to the outbound socket go back to the client source it belongs to
+        remote.send_to(b"reply", first).await.unwrap();
+        let (to, from, pkt) = reply_rx.recv().await.unwrap();
+        assert_eq!((to, from, pkt.as_ref()), (client, remote_addr, &b"reply"[..]));
+        assert_eq!(nat.peer(remote_addr).as_deref(), Some("localhost"));
+
+        // a peer the client never sent to reaches the outbound socket, but is no peer of the association
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        stranger.send_to(b"unsolicited", first).await.unwrap();
+        let (_, from, _) = reply_rx.recv().await.unwrap();
+        assert_eq!(from, stranger.local_addr().unwrap());
+        assert_eq!(nat.peer(from), None);
+
+        // a new source beyond the cap is dropped, mapped ones still relay
+        for port in 1..MAX_UDP_NAT_MAPPINGS as u16 {
+            let source = SocketAddr::from(([10, 0, 0, 2], port));
+            nat.send_to(source, b"c", remote_addr.into(), String::new()).await.unwrap();
+        }
+        assert_eq!(nat.mappings.len(), MAX_UDP_NAT_MAPPINGS);
+        let source = SocketAddr::from(([10, 0, 0, 3], 1));
+        assert!(nat.send_to(source, b"d", remote_addr.into(), String::new()).await.is_err());
+        nat.send_to(client, b"e", remote_addr.into(), String::new()).await.unwrap();
+        assert_eq!(nat.mappings.len(), MAX_UDP_NAT_MAPPINGS);
+    }
+}
@@ -9,11 +9,12 @@ pub mod connection;
 pub mod server_auth;
 pub mod handle_conn;
 
-pub use crate::socks5_server::{auth::{AuthAdaptor, AuthExecutor},
+pub use crate::socks5_server::{auth::{AuthAdaptor, AuthChain, AuthExecutor, GssApiAuth, GssApiSession, HmacAuth, HmacSecrets},
                 connection::{
         associate::{AssociatedUdpSocket, UdpAssociate},
         bind::Bind,
         connect::Connect,
+        resolve::Resolve,
         ClientConnection, IncomingConnection,
     },
 };
@@ -57,7 +58,7 @@ impl<O: 'static> Server<O> {
     #[inline]
     pub async fn accept(&self) -> std::io::Result<(IncomingConnection<O>, SocketAddr)> {
         let (stream, addr) = self.listener.accept().await?;
-        Ok((IncomingConnection::new(stream, self.auth.clone()), addr))
+        Ok((IncomingConnection::new(stream.into(), self.auth.clone()), addr))
     }
 
     /// Polls to accept an [`IncomingConnection<O>`](crate::server::connection::IncomingConnection).
@@ -73,7 +74,7 @@ impl<O: 'static> Server<O> {
     pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(IncomingConnection<O>, SocketAddr)>> {
         self.listener
             .poll_accept(cx)
-            .map_ok(|(stream, addr)| (IncomingConnection::new(stream, self.auth.clone()), addr))
+            .map_ok(|(stream, addr)| (IncomingConnection::new(stream.into(), self.auth.clone()), addr))
     }
 
     /// Get the the local socket address binded to this socks5_server
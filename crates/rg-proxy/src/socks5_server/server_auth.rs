@@ -1,58 +1,195 @@
 use async_trait::async_trait;
-use http::Uri;
-use tokio::net::TcpStream;
-use socks5_protocol::{AsyncStreamOperation, AuthMethod, UserKey};
+use rg_acl::AuthCenter;
+use rg_common::user_auth::UserInfo;
+use socks5_http::ProxyStream;
+use socks5_protocol::{AsyncStreamOperation, AuthMethod};
 use socks5_protocol::password_method::{Request, Response};
 use socks5_protocol::password_method::Status::{Failed, Succeeded};
-use crate::socks5_server::AuthExecutor;
+use std::sync::Arc;
+use crate::socks5_server::{AuthAdaptor, AuthChain, AuthExecutor, HmacAuth, HmacSecrets};
 use error::{Error, Result};
-use crate::backend::check_user_auth;
-use crate::backend::dc_server::DC_SERVER_BACKEND;
+use crate::backend::{check_user_auth, hmac_user_secrets};
 
-#[derive(Debug, Default)]
+/// Authenticates socks clients against the backend's [`AuthCenter`].
+///
+/// White listed clients may skip credentials, all others have to pass username and password.
+/// The output is the [`UserInfo`] the connection is accounted to.
+#[derive(Clone)]
 pub struct ServerAuth {
+    auth: AuthCenter,
     is_white: bool,
     local_ip: String,
-    host: Uri,
     remote_ip: String,
 }
 
 impl ServerAuth {
-    pub fn new(is_white: bool, local_ip: String, remote_ip: String) -> Self {
-        Self { is_white, local_ip, host: Uri::default(), remote_ip }
+    pub fn new(auth: AuthCenter, is_white: bool, local_ip: String, remote_ip: String) -> Self {
+        Self { auth, is_white, local_ip, remote_ip }
     }
-}
 
-#[async_trait]
-impl AuthExecutor for ServerAuth {
-    type Output = Result<bool>;
+    /// The socks5 methods this client may negotiate, in order of preference.
+    ///
+    /// White listed clients prefer `NoAuth`, then come the HMAC token method and `UserPass`.
+    pub fn into_chain(self) -> AuthChain<Result<UserInfo>> {
+        let auth = Arc::new(self);
+        let mut executors: Vec<AuthAdaptor<Result<UserInfo>>> = Vec::new();
+        if auth.is_white {
+            executors.push(Arc::new(WhiteListAuth(auth.clone())));
+        }
+        executors.push(Arc::new(HmacAuth::new(auth.clone())));
+        executors.push(Arc::new(PasswordAuth(auth)));
+        AuthChain::new(executors)
+    }
 
-    fn auth_method(&self) -> AuthMethod {
+    /// Authenticates the USERID of a SOCKS4 request.
+    ///
+    /// SOCKS4 has no password field, clients pass `username:password` as USERID instead.
+    /// White listed clients are accepted whatever the USERID is.
+    pub async fn check_socks4(&self, user_id: &str) -> Result<UserInfo> {
         if self.is_white {
-            AuthMethod::NoAuth
+            return self.check("", "").await;
+        }
+        let (username, password) = user_id.split_once(':').unwrap_or((user_id, ""));
+        self.check(username, password).await
+    }
+
+    async fn check(&self, username: &str, password: &str) -> Result<UserInfo> {
+        let (valid, user_info) = check_user_auth(&self.auth, &self.local_ip, &self.remote_ip, self.is_white, username, password).await?;
+        if valid {
+            Ok(user_info)
         } else {
-            AuthMethod::UserPass
+            Err(Error::AuthFailed(format!("ip: {}, username: {}", self.local_ip, username)))
         }
     }
+}
+
+/// `NoAuth` for white listed clients.
+struct WhiteListAuth(Arc<ServerAuth>);
+
+#[async_trait]
+impl AuthExecutor for WhiteListAuth {
+    type Output = Result<UserInfo>;
+
+    fn auth_method(&self) -> AuthMethod {
+        AuthMethod::NoAuth
+    }
+
+    async fn execute(&self, _: &mut ProxyStream) -> Self::Output {
+        self.0.check("", "").await
+    }
+}
+
+/// `UserPass`, white listed clients may use it as well.
+struct PasswordAuth(Arc<ServerAuth>);
+
+#[async_trait]
+impl AuthExecutor for PasswordAuth {
+    type Output = Result<UserInfo>;
+
+    fn auth_method(&self) -> AuthMethod {
+        AuthMethod::UserPass
+    }
+
+    async fn execute(&self, stream: &mut ProxyStream) -> Self::Output {
+        let req = Request::retrieve_from_async_stream(stream).await?;
+        let res = self.0.check(&req.user_key.username, &req.user_key.password).await;
+        let resp = Response::new(if res.is_ok() { Succeeded } else { Failed });
+        resp.write_to_async_stream(stream).await?;
+        res
+    }
+}
+
+/// HMAC tokens are signed with the password, so they resolve to the same users as `UserPass`.
+#[async_trait]
+impl HmacSecrets for Arc<ServerAuth> {
+    type User = UserInfo;
+
+    async fn secrets(&self, username: &str) -> Vec<(String, UserInfo)> {
+        hmac_user_secrets(&self.auth, &self.local_ip, username).await
+    }
+}
 
-    async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
-        match self.auth_method() {
-            AuthMethod::NoAuth => {
-                Ok(true)
-            }
-            AuthMethod::UserPass => {
-                let req = Request::retrieve_from_async_stream(stream).await?;
-                let auth = DC_SERVER_BACKEND.auth.clone();
-                let (valid, user) = check_user_auth(&auth, &self.host.host().unwrap_or_default(), &self.local_ip, self.is_white, &req.user_key.username, &req.user_key.password).await?;
-                let resp = Response::new(if valid { Succeeded } else { Failed });
-                resp.write_to_async_stream(stream).await?;
-                if valid {
-                    Ok(true)
-                } else {
-                    Err(Error::from(std::io::Error::new(std::io::ErrorKind::Other, "username or password is incorrect")))
-                }
-            }
-            _ => Ok(false)
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::socks5_server::connection::{ClientConnection, IncomingConnection};
+    use rg_acl::auth::{dc_auth::{DcAuthenticator, PASSWORD}, Authenticator};
+    use rg_common::user_auth::WhiteListData;
+    use socks5_client::Auth;
+    use socks5_protocol::{handshake::{self, hmac_method}, Address, Reply, UserKey};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::{io::BufStream, net::{TcpListener, TcpStream}, sync::RwLock};
+
+    const IP: &str = "10.0.0.1";
+
+    fn auth_center() -> AuthCenter {
+        let mut auth = DcAuthenticator::default();
+        auth.update_all(vec![
+            UserInfo::new(7, 70, "alice", "secret", "", PASSWORD, vec![IP.to_string()]),
+            UserInfo::new(8, 80, "bob", "unused", "", PASSWORD, vec![]),
+        ]);
+        auth.update_white_list(vec![WhiteListData::new_with_id(IP, "bob", "hunter2", 8)]);
+        Arc::new(RwLock::new(auth))
+    }
+
+    /// Runs the server side of one socks5 handshake, answering the CONNECT after a successful one.
+    async fn serve_one(listener: TcpListener) -> Result<UserInfo> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let chain = ServerAuth::new(auth_center(), false, IP.to_string(), "127.0.0.1".to_string()).into_chain();
+        let (conn, output) = IncomingConnection::new(ProxyStream::from(stream), Arc::new(chain)).authenticate().await.unwrap();
+        if output.is_ok() {
+            let Ok(ClientConnection::Connect(connect, _)) = conn.wait_request().await else { panic!("expected a CONNECT request") };
+            connect.reply(Reply::Succeeded, Address::unspecified()).await.unwrap();
         }
+        output
+    }
+
+    async fn hmac_connect(username: &str, password: &str) -> (error::Result<Address>, Result<UserInfo>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_one(listener));
+        let mut stream = BufStream::new(TcpStream::connect(addr).await.unwrap());
+        let auth = Auth::HmacToken(UserKey::new(username, password));
+        let client = socks5_client::connect_with_auth(&mut stream, ("example.com", 80), Some(auth)).await;
+        (client, server.await.unwrap())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_hmac_round_trip() {
+        let (client, server) = hmac_connect("alice", "secret").await;
+        assert!(client.is_ok());
+        let user = server.unwrap();
+        assert_eq!((user.user_id, user.user_plan_id), (7, 70));
+
+        // white listed ip-username-password
+        let (client, server) = hmac_connect("bob", "hunter2").await;
+        assert!(client.is_ok());
+        assert_eq!(server.unwrap().user_id, 8);
+
+        // bad MAC
+        let (client, server) = hmac_connect("alice", "wrong").await;
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hmac_timestamp_skew() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_one(listener));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        handshake::Request::new(vec![hmac_method::AUTH_METHOD]).write_to_async_stream(&mut stream).await.unwrap();
+        let selected = handshake::Response::retrieve_from_async_stream(&mut stream).await.unwrap();
+        assert_eq!(selected.method, hmac_method::AUTH_METHOD);
+
+        // signed with the right secret, but too old
+        let challenge = hmac_method::Challenge::retrieve_from_async_stream(&mut stream).await.unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let timestamp = now - hmac_method::MAX_TIMESTAMP_SKEW_SECS - 5;
+        let mac = hmac_method::sign(b"secret", &challenge.nonce, timestamp, "alice");
+        hmac_method::Request::new("alice", timestamp, mac).write_to_async_stream(&mut stream).await.unwrap();
+        let resp = hmac_method::Response::retrieve_from_async_stream(&mut stream).await.unwrap();
+        assert_eq!(resp.status, hmac_method::Status::Failed);
+        assert!(server.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use config::TlsConfig;
+use error::{Error, Result};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::ServerConfig;
+use tracing::{error, info};
+
+pub use tokio_rustls::TlsAcceptor;
+
+/// Terminates TLS on inbound connections with the certificate and key files of a [`TlsConfig`].
+///
+/// The files are re-read when they change, connections accepted afterwards get the new certificate while
+/// established ones keep theirs.
+pub struct TlsTerminator {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    http2: bool,
+    loaded: RwLock<Loaded>,
+}
+
+struct Loaded {
+    acceptor: TlsAcceptor,
+    /// modification times of the certificate and the key files the acceptor was built from
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl TlsTerminator {
+    /// Loads the certificate and key, failing if they are missing or invalid.
+    pub fn load(config: &TlsConfig) -> Result<Self> {
+        let modified = (modified(&config.cert_file), modified(&config.key_file));
+        let acceptor = acceptor(&config.cert_file, &config.key_file, config.http2)?;
+        Ok(Self {
+            cert_file: config.cert_file.clone(),
+            key_file: config.key_file.clone(),
+            http2: config.http2,
+            loaded: RwLock::new(Loaded { acceptor, modified }),
+        })
+    }
+
+    /// The acceptor serving the current certificate.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.loaded.read().unwrap_or_else(|e| e.into_inner()).acceptor.clone()
+    }
+
+    /// Reloads the certificate and key if either file changed since they were last loaded.
+    ///
+    /// Returns whether a new certificate is served. If the new files are invalid, the previous certificate is kept.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = (modified(&self.cert_file), modified(&self.key_file));
+        if self.loaded.read().unwrap_or_else(|e| e.into_inner()).modified == modified {
+            return Ok(false);
+        }
+        let acceptor = acceptor(&self.cert_file, &self.key_file, self.http2)?;
+        *self.loaded.write().unwrap_or_else(|e| e.into_inner()) = Loaded { acceptor, modified };
+        Ok(true)
+    }
+
+    /// Checks the files for changes every `interval`.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let tls = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match tls.reload_if_changed() {
+                    Ok(true) => info!("reloaded tls certificate {:?}", tls.cert_file),
+                    Ok(false) => {}
+                    Err(e) => error!("reload tls certificate {:?} failed, keep the previous one: {}", tls.cert_file, e),
+                }
+            }
+        })
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn acceptor(cert_file: &Path, key_file: &Path, http2: bool) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file)?)).collect::<std::io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(Error::from(format!("no certificate found in {:?}", cert_file)));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_file)?))?
+        .ok_or_else(|| Error::from(format!("no private key found in {:?}", key_file)))?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::from(format!("invalid tls certificate or key: {}", e)))?;
+    if http2 {
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    /// Writes a self signed certificate for `name`, with both files modified at `mtime` seconds.
+    fn write_cert(config: &TlsConfig, name: &str, mtime: u64) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+        std::fs::write(&config.cert_file, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&config.key_file, cert.serialize_private_key_pem()).unwrap();
+        touch(&config.cert_file, mtime);
+        touch(&config.key_file, mtime);
+    }
+
+    fn touch(path: &Path, mtime: u64) {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime)).unwrap();
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let dir = std::env::temp_dir().join(format!("rg-proxy-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert_file: dir.join("cert.pem"),
+            key_file: dir.join("key.pem"),
+            ..Default::default()
+        };
+
+        write_cert(&config, "a.example", 1000);
+        let tls = TlsTerminator::load(&config).unwrap();
+        assert!(!tls.reload_if_changed().unwrap());
+
+        // a broken certificate is reported and the previous one stays in use
+        std::fs::write(&config.cert_file, "not a certificate").unwrap();
+        touch(&config.cert_file, 2000);
+        assert!(tls.reload_if_changed().is_err());
+
+        write_cert(&config, "b.example", 3000);
+        assert!(tls.reload_if_changed().unwrap());
+        assert!(!tls.reload_if_changed().unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
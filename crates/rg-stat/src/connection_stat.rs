@@ -1,4 +1,4 @@
-use std::sync::atomic::AtomicI64;
+use std::sync::atomic::{AtomicI64, AtomicU64};
 
 use rg_common::stat::ConnectionStatSnapshot;
 
@@ -7,6 +7,7 @@ use crate::StatCollectable;
 pub struct ConnectionStat {
     pub alive_in_connection: AtomicI64,
     // pub alive_out_connection: AtomicI64,
+    pub handshake_timeout: AtomicU64,
 }
 
 impl StatCollectable for ConnectionStat {
@@ -22,6 +23,9 @@ impl StatCollectable for ConnectionStat {
             // alive_out_connection: self
             //     .alive_out_connection
             //     .fetch_and(0, std::sync::atomic::Ordering::Relaxed),
+            handshake_timeout: self
+                .handshake_timeout
+                .fetch_and(0, std::sync::atomic::Ordering::Relaxed),
         };
         serde_json::to_string(&snap).unwrap_or_default()
     }
@@ -32,6 +36,7 @@ impl ConnectionStat {
         Self {
             alive_in_connection: AtomicI64::new(0),
             // alive_out_connection: AtomicI64::new(0),
+            handshake_timeout: AtomicU64::new(0),
         }
     }
 
@@ -41,4 +46,9 @@ impl ConnectionStat {
         // self.alive_out_connection
         //     .fetch_add(out_conn, std::sync::atomic::Ordering::Relaxed);
     }
+
+    pub fn add_handshake_timeout(&self) {
+        self.handshake_timeout
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
 }
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use chrono::Utc;
+use error::FailureReason;
 use tracing::{error, info};
 use rg_common::{
     stat::{StatData, StatType},
@@ -92,6 +93,12 @@ impl StatisticManager {
                             StatEvent::Connection(in_cnt) => {
                                 self.connection_stat.add(in_cnt);
                             }
+                            StatEvent::HandshakeTimeout => {
+                                self.connection_stat.add_handshake_timeout();
+                            }
+                            StatEvent::Failure(reason) => {
+                                self.request_stat.failures.add(reason);
+                            }
                         }
                     }
                 }
@@ -118,4 +125,8 @@ pub enum StatEvent {
     Traffic(TrafficInfo),
     Request(RequestType),
     Connection(i64),
+    /// a client did not finish its handshake in time
+    HandshakeTimeout,
+    /// a request could not be served
+    Failure(FailureReason),
 }
@@ -1,6 +1,7 @@
 use std::sync::atomic::AtomicU64;
 
-use rg_common::stat::RequestStatSnapshot;
+use error::FailureReason;
+use rg_common::stat::{FailureStatSnapshot, RequestStatSnapshot};
 
 use crate::StatCollectable;
 
@@ -8,6 +9,7 @@ use crate::StatCollectable;
 pub enum RequestType {
     Http,
     Https,
+    Socks4,
     Socks5,
     // for total request
     None,
@@ -17,7 +19,21 @@ pub struct RequestStat {
     pub total_request: AtomicU64,
     pub http_request: AtomicU64,
     pub https_request: AtomicU64,
+    pub socks4_request: AtomicU64,
     pub socks5_request: AtomicU64,
+    pub failures: FailureStat,
+}
+
+#[derive(Default)]
+pub struct FailureStat {
+    pub dns_not_found: AtomicU64,
+    pub connection_refused: AtomicU64,
+    pub timeout: AtomicU64,
+    pub network_unreachable: AtomicU64,
+    pub acl_denied: AtomicU64,
+    pub auth_failed: AtomicU64,
+    pub user_disabled: AtomicU64,
+    pub other: AtomicU64,
 }
 
 impl StatCollectable for RequestStat {
@@ -36,9 +52,13 @@ impl StatCollectable for RequestStat {
             https_request: self
                 .https_request
                 .fetch_and(0, std::sync::atomic::Ordering::Relaxed),
+            socks4_request: self
+                .socks4_request
+                .fetch_and(0, std::sync::atomic::Ordering::Relaxed),
             socks5_request: self
                 .socks5_request
                 .fetch_and(0, std::sync::atomic::Ordering::Relaxed),
+            failures: self.failures.take(),
         };
         serde_json::to_string(&snap).unwrap_or_default()
     }
@@ -50,7 +70,9 @@ impl RequestStat {
             total_request: AtomicU64::new(0),
             http_request: AtomicU64::new(0),
             https_request: AtomicU64::new(0),
+            socks4_request: AtomicU64::new(0),
             socks5_request: AtomicU64::new(0),
+            failures: FailureStat::default(),
         }
     }
 
@@ -59,8 +81,40 @@ impl RequestStat {
             RequestType::None => &self.total_request,
             RequestType::Http => &self.http_request,
             RequestType::Https => &self.https_request,
+            RequestType::Socks4 => &self.socks4_request,
             RequestType::Socks5 => &self.socks5_request,
         }
         .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     }
 }
+
+impl FailureStat {
+    pub fn add(&self, reason: FailureReason) {
+        match reason {
+            FailureReason::DnsNotFound => &self.dns_not_found,
+            FailureReason::ConnectionRefused => &self.connection_refused,
+            FailureReason::Timeout => &self.timeout,
+            FailureReason::NetworkUnreachable => &self.network_unreachable,
+            FailureReason::AclDenied => &self.acl_denied,
+            FailureReason::AuthFailed => &self.auth_failed,
+            FailureReason::UserDisabled => &self.user_disabled,
+            FailureReason::Other => &self.other,
+        }
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// The counts since the last call, resetting them.
+    fn take(&self) -> FailureStatSnapshot {
+        let take = |count: &AtomicU64| count.swap(0, std::sync::atomic::Ordering::Relaxed);
+        FailureStatSnapshot {
+            dns_not_found: take(&self.dns_not_found),
+            connection_refused: take(&self.connection_refused),
+            timeout: take(&self.timeout),
+            network_unreachable: take(&self.network_unreachable),
+            acl_denied: take(&self.acl_denied),
+            auth_failed: take(&self.auth_failed),
+            user_disabled: take(&self.user_disabled),
+            other: take(&self.other),
+        }
+    }
+}
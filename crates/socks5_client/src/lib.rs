@@ -1,14 +1,18 @@
 use {
     error::{Error, Result},
-    socks5_protocol::{Address, AddressType, AsyncStreamOperation, AuthMethod, Command, Reply, StreamOperation, UserKey, Version},
+    socks5_protocol::{handshake::hmac_method, Address, AddressType, AsyncStreamOperation, AuthMethod, Command, Reply, StreamOperation, UserKey, Version},
 };
 
+use bytes::Bytes;
+use socks5_protocol::udp::{split_fragments, MAX_UDP_DATAGRAM_SIZE, UDP_REASSEMBLY_TIMEOUT};
+use socks5_protocol::UdpReassembler;
 use std::future::Future;
 use std::{
     fmt::Debug,
     io::Cursor,
-    net::{SocketAddr, ToSocketAddrs},
-    time::Duration,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufStream},
@@ -34,6 +38,7 @@ where
             let value = AuthMethod::from(self.read_u8().await?);
             match value {
                 AuthMethod::NoAuth | AuthMethod::UserPass => Ok(value),
+                method if method == hmac_method::AUTH_METHOD => Ok(value),
                 _ => Err(Error::InvalidAuthMethod(value.to_string())),
             }
         }
@@ -257,7 +262,43 @@ where
     stream.read_auth_status().await
 }
 
-async fn init<S, A>(stream: &mut S, command: Command, addr: A, auth: Option<UserKey>) -> Result<Address>
+async fn hmac_token_auth<S>(stream: &mut S, auth: &UserKey) -> Result<()>
+where
+    S: Socks5Writer + Socks5Reader + Send,
+{
+    if auth.username.len() > 255 {
+        return Err("Too long string".into());
+    }
+    let challenge = hmac_method::Challenge::retrieve_from_async_stream(stream).await?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?.as_secs();
+    let mac = hmac_method::sign(auth.password.as_bytes(), &challenge.nonce, timestamp, &auth.username);
+    hmac_method::Request::new(&auth.username, timestamp, mac).write_to_async_stream(stream).await?;
+    stream.flush().await?;
+
+    match hmac_method::Response::retrieve_from_async_stream(stream).await?.status {
+        hmac_method::Status::Succeeded => Ok(()),
+        status => Err(Error::InvalidAuthStatus(status.into())),
+    }
+}
+
+/// Credentials to authenticate to the proxy with.
+#[derive(Clone, Debug)]
+pub enum Auth {
+    /// RFC 1929 username and password, the password is sent in plaintext.
+    UserPass(UserKey),
+    /// The private HMAC token method, the client only proves it knows the password.
+    ///
+    /// `UserPass` is not offered, so the password is never sent to a proxy without support for the method.
+    HmacToken(UserKey),
+}
+
+impl From<UserKey> for Auth {
+    fn from(user_key: UserKey) -> Self {
+        Auth::UserPass(user_key)
+    }
+}
+
+async fn init<S, A>(stream: &mut S, command: Command, addr: A, auth: Option<Auth>) -> Result<Address>
 where
     S: Socks5Writer + Socks5Reader + Send,
     A: Into<Address>,
@@ -266,17 +307,22 @@ where
 
     let mut methods = Vec::with_capacity(2);
     methods.push(AuthMethod::NoAuth);
-    if auth.is_some() {
-        methods.push(AuthMethod::UserPass);
+    match auth {
+        Some(Auth::UserPass(_)) => methods.push(AuthMethod::UserPass),
+        Some(Auth::HmacToken(_)) => methods.push(hmac_method::AUTH_METHOD),
+        None => {}
     }
     stream.write_selection_msg(&methods).await?;
     stream.flush().await?;
 
     let method: AuthMethod = stream.read_selection_msg().await?;
-    match method {
-        AuthMethod::NoAuth => {}
-        AuthMethod::UserPass if auth.is_some() => {
-            username_password_auth(stream, auth.as_ref().unwrap()).await?;
+    match (method, &auth) {
+        (AuthMethod::NoAuth, _) => {}
+        (AuthMethod::UserPass, Some(Auth::UserPass(user_key))) => {
+            username_password_auth(stream, user_key).await?;
+        }
+        (method, Some(Auth::HmacToken(user_key))) if method == hmac_method::AUTH_METHOD => {
+            hmac_token_auth(stream, user_key).await?;
         }
         _ => return Err(Error::InvalidAuthMethod(method.to_string())),
     }
@@ -304,6 +350,15 @@ where
 /// # }
 /// ```
 pub async fn connect<S, A>(socket: &mut S, addr: A, auth: Option<UserKey>) -> Result<Address>
+where
+    S: AsyncWriteExt + AsyncReadExt + Send + Unpin,
+    A: Into<Address>,
+{
+    connect_with_auth(socket, addr, auth.map(Auth::from)).await
+}
+
+/// Like [`connect`], authenticating with any [`Auth`] method.
+pub async fn connect_with_auth<S, A>(socket: &mut S, addr: A, auth: Option<Auth>) -> Result<Address>
 where
     S: AsyncWriteExt + AsyncReadExt + Send + Unpin,
     A: Into<Address>,
@@ -311,6 +366,49 @@ where
     init(socket, Command::Connect, addr, auth).await
 }
 
+/// Resolves a hostname through the proxy. Performs the Tor [`RESOLVE`] extension command under the hood.
+///
+/// [`RESOLVE`]: https://spec.torproject.org/socks-extensions.html
+pub async fn resolve<S>(socket: &mut S, host: &str, auth: Option<UserKey>) -> Result<IpAddr>
+where
+    S: AsyncWriteExt + AsyncReadExt + Send + Unpin,
+{
+    resolve_with_auth(socket, host, auth.map(Auth::from)).await
+}
+
+/// Like [`resolve`], authenticating with any [`Auth`] method.
+pub async fn resolve_with_auth<S>(socket: &mut S, host: &str, auth: Option<Auth>) -> Result<IpAddr>
+where
+    S: AsyncWriteExt + AsyncReadExt + Send + Unpin,
+{
+    match init(socket, Command::Resolve, Address::DomainAddress(host.to_owned(), 0), auth).await? {
+        Address::SocketAddress(addr) => Ok(addr.ip()),
+        Address::DomainAddress(name, _) => Err(format!("RESOLVE answered with a hostname: {}", name).into()),
+    }
+}
+
+/// Looks up the hostname of an ip address through the proxy. Performs the Tor [`RESOLVE_PTR`] extension command
+/// under the hood.
+///
+/// [`RESOLVE_PTR`]: https://spec.torproject.org/socks-extensions.html
+pub async fn resolve_ptr<S>(socket: &mut S, ip: IpAddr, auth: Option<UserKey>) -> Result<String>
+where
+    S: AsyncWriteExt + AsyncReadExt + Send + Unpin,
+{
+    resolve_ptr_with_auth(socket, ip, auth.map(Auth::from)).await
+}
+
+/// Like [`resolve_ptr`], authenticating with any [`Auth`] method.
+pub async fn resolve_ptr_with_auth<S>(socket: &mut S, ip: IpAddr, auth: Option<Auth>) -> Result<String>
+where
+    S: AsyncWriteExt + AsyncReadExt + Send + Unpin,
+{
+    match init(socket, Command::ResolvePtr, SocketAddr::new(ip, 0), auth).await? {
+        Address::DomainAddress(name, _) => Ok(name),
+        Address::SocketAddress(addr) => Err(format!("RESOLVE_PTR answered with an address: {}", addr).into()),
+    }
+}
+
 /// A listener that accepts TCP connections through a proxy.
 ///
 /// ```no_run
@@ -343,7 +441,15 @@ where
     /// Creates `SocksListener`. Performs the [`BIND`] command under the hood.
     ///
     /// [`BIND`]: https://tools.ietf.org/html/rfc1928#page-6
-    pub async fn bind<A>(mut stream: S, addr: A, auth: Option<UserKey>) -> Result<Self>
+    pub async fn bind<A>(stream: S, addr: A, auth: Option<UserKey>) -> Result<Self>
+    where
+        A: Into<Address>,
+    {
+        Self::bind_with_auth(stream, addr, auth.map(Auth::from)).await
+    }
+
+    /// Like [`bind`](Self::bind), authenticating with any [`Auth`] method.
+    pub async fn bind_with_auth<A>(mut stream: S, addr: A, auth: Option<Auth>) -> Result<Self>
     where
         A: Into<Address>,
     {
@@ -367,6 +473,8 @@ pub struct SocksDatagram<S> {
     socket: UdpSocket,
     proxy_addr: Address,
     stream: S,
+    fragment_size: Option<usize>,
+    reassembler: Mutex<UdpReassembler>,
 }
 
 impl<S> SocksDatagram<S>
@@ -376,7 +484,12 @@ where
     /// Creates `SocksDatagram`. Performs [`UDP ASSOCIATE`] under the hood.
     ///
     /// [`UDP ASSOCIATE`]: https://tools.ietf.org/html/rfc1928#page-7
-    pub async fn udp_associate(mut stream: S, socket: UdpSocket, auth: Option<UserKey>) -> Result<Self> {
+    pub async fn udp_associate(stream: S, socket: UdpSocket, auth: Option<UserKey>) -> Result<Self> {
+        Self::udp_associate_with_auth(stream, socket, auth.map(Auth::from)).await
+    }
+
+    /// Like [`udp_associate`](Self::udp_associate), authenticating with any [`Auth`] method.
+    pub async fn udp_associate_with_auth(mut stream: S, socket: UdpSocket, auth: Option<Auth>) -> Result<Self> {
         let addr = if socket.local_addr()?.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
         let addr = addr.parse::<SocketAddr>()?;
         let proxy_addr = init(&mut stream, Command::UdpAssociate, addr, auth).await?;
@@ -386,9 +499,19 @@ where
             socket,
             proxy_addr,
             stream,
+            fragment_size: None,
+            reassembler: Mutex::new(UdpReassembler::new(UDP_REASSEMBLY_TIMEOUT, MAX_UDP_DATAGRAM_SIZE)),
         })
     }
 
+    /// Enables sending fragmented datagrams, payloads larger than `fragment_size` bytes are split
+    /// into several datagrams which the proxy reassembles. `None` disables fragmentation.
+    ///
+    /// Keep each fragment with its header within the proxy's relay packet size.
+    pub fn set_fragment_size(&mut self, fragment_size: Option<usize>) {
+        self.fragment_size = fragment_size;
+    }
+
     /// Returns the address of the associated udp address.
     pub fn proxy_addr(&self) -> &Address {
         &self.proxy_addr
@@ -419,13 +542,18 @@ where
     //  https://tools.ietf.org/html/rfc1928#page-8
     //
     pub async fn build_socks5_udp_datagram(buf: &[u8], addr: &Address) -> Result<Vec<u8>> {
+        Self::build_socks5_udp_fragment(buf, 0x00, addr).await
+    }
+
+    /// Builds a udp-based client request packet carrying the given `FRAG` value.
+    pub async fn build_socks5_udp_fragment(buf: &[u8], frag: u8, addr: &Address) -> Result<Vec<u8>> {
         let bytes_size = Self::get_buf_size(addr.len(), buf.len());
         let bytes = Vec::with_capacity(bytes_size);
 
         let mut cursor = Cursor::new(bytes);
         cursor.write_reserved().await?;
         cursor.write_reserved().await?;
-        cursor.write_fragment_id(0x00).await?;
+        cursor.write_fragment_id(frag).await?;
         cursor.write_address(addr).await?;
         cursor.write_all(buf).await?;
 
@@ -439,34 +567,49 @@ where
         A: Into<Address>,
     {
         let addr: Address = addr.into();
-        let bytes = Self::build_socks5_udp_datagram(buf, &addr).await?;
-        Ok(self.socket.send(&bytes).await?)
+        let fragments = match self.fragment_size {
+            Some(size) => split_fragments(buf, size)?,
+            None => vec![(0x00, buf)],
+        };
+        let mut sent = 0;
+        for (frag, chunk) in fragments {
+            let bytes = Self::build_socks5_udp_fragment(chunk, frag, &addr).await?;
+            sent += self.socket.send(&bytes).await?;
+        }
+        Ok(sent)
     }
 
     /// Parses the udp-based socks5_server response packet, the format is same as the client request packet.
-    async fn parse_socks5_udp_response(bytes: &mut [u8], buf: &mut Vec<u8>) -> Result<(usize, Address)> {
-        let len = bytes.len();
+    async fn parse_socks5_udp_response(bytes: &mut [u8]) -> Result<(u8, Address, usize)> {
         let mut cursor = Cursor::new(bytes);
         cursor.read_reserved().await?;
         cursor.read_reserved().await?;
-        cursor.read_fragment_id().await?;
+        let frag = cursor.read_u8().await?;
         let addr = cursor.read_address().await?;
-        let header_len = cursor.position() as usize;
-        buf.resize(len - header_len, 0);
-        _ = cursor.read_exact(buf).await?;
-        Ok((len - header_len, addr))
+        Ok((frag, addr, cursor.position() as usize))
     }
 
     /// Receives data from the udp socket and returns the number of bytes read and the origin of the data.
+    ///
+    /// Fragmented datagrams are reassembled before they are returned.
     pub async fn recv_from(&self, timeout: Duration, buf: &mut Vec<u8>) -> Result<(usize, Address)> {
         const UDP_MTU: usize = 1500;
-        // let bytes_size = Self::get_buf_size(Address::max_serialized_len(), buf.len());
-        let bytes_size = UDP_MTU;
-        let mut bytes = vec![0; bytes_size];
-        let len = tokio::time::timeout(timeout, self.socket.recv(&mut bytes)).await??;
-        bytes.truncate(len);
-        let (read, addr) = Self::parse_socks5_udp_response(&mut bytes, buf).await?;
-        Ok((read, addr))
+        tokio::time::timeout(timeout, async {
+            loop {
+                let mut bytes = vec![0; UDP_MTU];
+                let len = self.socket.recv(&mut bytes).await?;
+                bytes.truncate(len);
+                let (frag, addr, header_len) = Self::parse_socks5_udp_response(&mut bytes).await?;
+                let data = Bytes::from(bytes).slice(header_len..);
+                let datagram = self.reassembler.lock().map_err(|e| Error::from(e.to_string()))?.push(frag, addr, data);
+                if let Some((addr, data)) = datagram {
+                    buf.clear();
+                    buf.extend_from_slice(&data);
+                    return Ok((data.len(), addr));
+                }
+            }
+        })
+        .await?
     }
 
     fn get_buf_size(addr_size: usize, buf_len: usize) -> usize {
@@ -500,12 +643,17 @@ impl UdpClientTrait for SocksUdpClient {
 }
 
 pub async fn create_udp_client<A: Into<SocketAddr>>(proxy_addr: A, auth: Option<UserKey>) -> Result<SocksUdpClient> {
+    create_udp_client_with_auth(proxy_addr, auth.map(Auth::from)).await
+}
+
+/// Like [`create_udp_client`], authenticating with any [`Auth`] method.
+pub async fn create_udp_client_with_auth<A: Into<SocketAddr>>(proxy_addr: A, auth: Option<Auth>) -> Result<SocksUdpClient> {
     let proxy_addr = proxy_addr.into();
     let client_addr = if proxy_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
     let proxy = TcpStream::connect(proxy_addr).await?;
     let proxy = BufStream::new(proxy);
     let client = UdpSocket::bind(client_addr).await?;
-    SocksDatagram::udp_associate(proxy, client, auth).await
+    SocksDatagram::udp_associate_with_auth(proxy, client, auth).await
 }
 
 pub struct UdpClientImpl<C> {
@@ -529,7 +677,16 @@ impl UdpClientImpl<SocksUdpClient> {
         A1: Into<SocketAddr>,
         A2: Into<Address>,
     {
-        let client = create_udp_client(proxy_addr, auth).await?;
+        Self::datagram_with_auth(proxy_addr, udp_server_addr, auth.map(Auth::from)).await
+    }
+
+    /// Like [`datagram`](Self::datagram), authenticating with any [`Auth`] method.
+    pub async fn datagram_with_auth<A1, A2>(proxy_addr: A1, udp_server_addr: A2, auth: Option<Auth>) -> Result<Self>
+    where
+        A1: Into<SocketAddr>,
+        A2: Into<Address>,
+    {
+        let client = create_udp_client_with_auth(proxy_addr, auth).await?;
 
         let server_addr = udp_server_addr.into();
 
@@ -543,10 +700,8 @@ mod tests {
         crate::{SocksListener, SocksUdpClient, UdpClientTrait},
         error::Error,
         socks5_protocol::{Address, UserKey},
-        Result,
     };
 
-    use crate::UdpClientTrait;
     use std::{
         net::{SocketAddr, ToSocketAddrs},
         sync::Arc,
@@ -586,6 +741,19 @@ mod tests {
         connect(PROXY_AUTH_ADDR, None).await;
     }
 
+    #[ignore]
+    #[tokio::test]
+    async fn resolve() {
+        let socket = TcpStream::connect(PROXY_ADDR).await.unwrap();
+        let mut socket = BufStream::new(socket);
+        let ip = crate::resolve(&mut socket, "localhost", None).await.unwrap();
+        assert!(ip.is_loopback());
+
+        let socket = TcpStream::connect(PROXY_ADDR).await.unwrap();
+        let mut socket = BufStream::new(socket);
+        crate::resolve_ptr(&mut socket, ip, None).await.unwrap();
+    }
+
     #[ignore]
     #[tokio::test]
     async fn bind() {
@@ -1,33 +1,47 @@
-use error::Result;
+mod stream;
+
+use error::{Error, Result};
 use hyper_util::rt::TokioIo;
 use socks5_protocol::Version;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+
+pub use stream::ProxyStream;
 
 pub struct Sock5Http {
-    pub stream: TokioIo<TcpStream>,
+    pub stream: TokioIo<ProxyStream>,
 }
 
 pub enum Sock5OrHttp {
+    Sock4,
     Sock5,
     Http,
+    /// HTTP/2 agreed on by ALPN
+    Http2,
 }
 
 impl Sock5Http {
-    pub fn new(sock5_or_http: TcpStream) -> Self {
+    pub fn new(sock5_or_http: ProxyStream) -> Self {
         Self {
             stream: TokioIo::new(sock5_or_http),
         }
     }
 
+    /// Detects the protocol from the first byte of the stream.
+    ///
+    /// The byte is only peeked, so the following SOCKS handshake or HTTP request parsing
+    /// still sees the whole message. A TLS client that agreed on HTTP/2 by ALPN is not peeked at.
     pub async fn socks5_or_http(&mut self) -> Result<Sock5OrHttp> {
+        if self.stream.inner().alpn_protocol() == Some(b"h2") {
+            return Ok(Sock5OrHttp::Http2);
+        }
         let mut ver = [0u8; 1];
-        self.stream.inner_mut().read_exact(&mut ver).await?;
-        let version = Version::try_from(ver[0])?;
-        if version == Version::V5 {
-            Ok(Sock5OrHttp::Sock5)
-        } else {
-            Ok(Sock5OrHttp::Http)
+        let n = self.stream.inner_mut().peek(&mut ver).await?;
+        if n == 0 {
+            return Err(Error::EmptyRequest);
+        }
+        match Version::try_from(ver[0]) {
+            Ok(Version::V4) => Ok(Sock5OrHttp::Sock4),
+            Ok(Version::V5) => Ok(Sock5OrHttp::Sock5),
+            Err(_) => Ok(Sock5OrHttp::Http),
         }
     }
 }
@@ -0,0 +1,163 @@
+use std::{
+    io::IoSlice,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::server::TlsStream;
+
+/// Largest plaintext of a TLS record, a peek reads up to this much so that the following read returns
+/// as much as it would have without the peek.
+const MAX_TLS_PLAINTEXT: usize = 16 * 1024;
+
+/// An accepted client connection, either plain TCP or with TLS terminated by the proxy.
+///
+/// Socket level details, such as addresses and options, are the ones of the underlying [`TcpStream`].
+#[derive(Debug)]
+pub struct ProxyStream {
+    inner: Inner,
+    /// plaintext read ahead by [`peek`](ProxyStream::peek) on a TLS stream, returned before reading further
+    peeked: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Inner {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl ProxyStream {
+    /// The underlying TCP connection.
+    pub fn tcp(&self) -> &TcpStream {
+        match &self.inner {
+            Inner::Tcp(stream) => stream,
+            Inner::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.inner, Inner::Tls(_))
+    }
+
+    /// The server name the client asked for during the TLS handshake, if any.
+    pub fn tls_server_name(&self) -> Option<&str> {
+        match &self.inner {
+            Inner::Tcp(_) => None,
+            Inner::Tls(stream) => stream.get_ref().1.server_name(),
+        }
+    }
+
+    /// The application protocol agreed on by ALPN during the TLS handshake, if any.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match &self.inner {
+            Inner::Tcp(_) => None,
+            Inner::Tls(stream) => stream.get_ref().1.alpn_protocol(),
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    /// Receives data without removing it from the stream, like [`TcpStream::peek`].
+    ///
+    /// On a TLS stream the data is decrypted and kept until it is read.
+    pub async fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.inner {
+            Inner::Tcp(stream) => stream.peek(buf).await,
+            Inner::Tls(stream) => {
+                if self.peeked.is_empty() {
+                    let mut ahead = vec![0; buf.len().max(MAX_TLS_PLAINTEXT)];
+                    let n = stream.read(&mut ahead).await?;
+                    ahead.truncate(n);
+                    self.peeked = ahead;
+                }
+                let n = buf.len().min(self.peeked.len());
+                buf[..n].copy_from_slice(&self.peeked[..n]);
+                Ok(n)
+            }
+        }
+    }
+
+    pub fn set_zero_linger(&self) -> std::io::Result<()> {
+        self.tcp().set_zero_linger()
+    }
+}
+
+impl From<TcpStream> for ProxyStream {
+    fn from(stream: TcpStream) -> Self {
+        Self {
+            inner: Inner::Tcp(stream),
+            peeked: Vec::new(),
+        }
+    }
+}
+
+impl From<TlsStream<TcpStream>> for ProxyStream {
+    fn from(stream: TlsStream<TcpStream>) -> Self {
+        Self {
+            inner: Inner::Tls(Box::new(stream)),
+            peeked: Vec::new(),
+        }
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if !self.peeked.is_empty() {
+            let n = buf.remaining().min(self.peeked.len());
+            buf.put_slice(&self.peeked[..n]);
+            self.peeked.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        match &mut self.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Inner::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match &mut self.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Inner::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<std::io::Result<usize>> {
+        match &mut self.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Inner::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.inner {
+            Inner::Tcp(stream) => stream.is_write_vectored(),
+            Inner::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Inner::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Inner::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
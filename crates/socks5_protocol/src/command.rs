@@ -3,6 +3,10 @@ pub enum Command {
     Connect = 0x01,
     Bind = 0x02,
     UdpAssociate = 0x03,
+    /// Tor extension, resolves a hostname through the proxy without opening a stream.
+    Resolve = 0xf0,
+    /// Tor extension, looks up the hostname of an ip address through the proxy.
+    ResolvePtr = 0xf1,
 }
 
 impl TryFrom<u8> for Command {
@@ -14,6 +18,8 @@ impl TryFrom<u8> for Command {
             0x01 => Ok(Command::Connect),
             0x02 => Ok(Command::Bind),
             0x03 => Ok(Command::UdpAssociate),
+            0xf0 => Ok(Command::Resolve),
+            0xf1 => Ok(Command::ResolvePtr),
             _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err)),
         }
     }
@@ -25,6 +31,23 @@ impl From<Command> for u8 {
             Command::Connect => 0x01,
             Command::Bind => 0x02,
             Command::UdpAssociate => 0x03,
+            Command::Resolve => 0xf0,
+            Command::ResolvePtr => 0xf1,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_try_from() {
+        for command in [Command::Connect, Command::Bind, Command::UdpAssociate, Command::Resolve, Command::ResolvePtr] {
+            assert_eq!(Command::try_from(u8::from(command)).unwrap(), command);
+        }
+        assert_eq!(u8::from(Command::Resolve), 0xf0);
+        assert_eq!(u8::from(Command::ResolvePtr), 0xf1);
+        assert!(Command::try_from(0x04).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use super::{SecurityContext, MAX_TOKEN_LEN};
+use crate::AsyncStreamOperation;
+use crate::StreamOperation;
+use error::{Error, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The `MTYP` of a GSS-API sub-negotiation message.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MessageType {
+    /// Context establishment token.
+    Authentication = 0x01,
+    /// Protection level negotiation.
+    ProtectionLevel = 0x02,
+    /// Per-message encapsulation of the traffic following the sub-negotiation.
+    Encapsulation = 0x03,
+    /// Sent by either side refusing the context, carries no `LEN` nor `TOKEN`.
+    Abort = 0xff,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x01 => Ok(MessageType::Authentication),
+            0x02 => Ok(MessageType::ProtectionLevel),
+            0x03 => Ok(MessageType::Encapsulation),
+            0xff => Ok(MessageType::Abort),
+            _ => {
+                let err = format!("Invalid GSS-API message type {0:#x}", value);
+                Err(Error::from(std::io::Error::new(std::io::ErrorKind::InvalidData, err)))
+            }
+        }
+    }
+}
+
+/// SOCKS5 GSS-API sub-negotiation message, RFC 1961
+///
+/// ```plain
+/// +------+------+------+.......................+
+/// + VER  | MTYP | LEN  |       TOKEN           |
+/// +------+------+------+.......................+
+/// + 0x01 |  1   |  2   | up to 2^16 - 1 octets |
+/// +------+------+------+.......................+
+/// ```
+///
+/// An [`Abort`](MessageType::Abort) message is `VER` and `MTYP` only.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Message {
+    pub mtype: MessageType,
+    pub token: Vec<u8>,
+}
+
+impl Message {
+    /// Fails if `token` does not fit into `LEN`.
+    pub fn new(mtype: MessageType, token: Vec<u8>) -> Result<Self> {
+        if token.len() > MAX_TOKEN_LEN {
+            return Err(Error::from(format!("GSS-API token of {} bytes, at most {} allowed", token.len(), MAX_TOKEN_LEN)));
+        }
+        Ok(Self { mtype, token })
+    }
+
+    pub fn abort() -> Self {
+        Self {
+            mtype: MessageType::Abort,
+            token: Vec::new(),
+        }
+    }
+
+    /// Wraps `data` with the security context into a message of type `mtype`.
+    pub fn seal<C: SecurityContext + ?Sized>(mtype: MessageType, context: &mut C, confidential: bool, data: &[u8]) -> Result<Self> {
+        Self::new(mtype, context.wrap(confidential, data)?)
+    }
+
+    /// Unwraps the token of a [`seal`](Message::seal)ed message, checking it is of type `mtype`.
+    pub fn open<C: SecurityContext + ?Sized>(&self, mtype: MessageType, context: &mut C) -> Result<Vec<u8>> {
+        if self.mtype != mtype {
+            return Err(Error::from(format!("GSS-API message of type {:?} when {:?} is expected", self.mtype, mtype)));
+        }
+        context.unwrap(&self.token)
+    }
+}
+
+impl StreamOperation for Message {
+    fn retrieve_from_stream<R: std::io::Read>(r: &mut R) -> Result<Self> {
+        let mut buf = [0; 2];
+        r.read_exact(&mut buf)?;
+
+        if buf[0] != super::SUBNEGOTIATION_VERSION {
+            return Err(Error::InvalidAuthSubnegotiation(buf[0]));
+        }
+
+        let mtype = MessageType::try_from(buf[1])?;
+        if mtype == MessageType::Abort {
+            return Ok(Self::abort());
+        }
+
+        let mut len = [0; 2];
+        r.read_exact(&mut len)?;
+        let mut token = vec![0; u16::from_be_bytes(len) as usize];
+        r.read_exact(&mut token)?;
+        Ok(Self { mtype, token })
+    }
+
+    fn write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) {
+        buf.put_u8(super::SUBNEGOTIATION_VERSION);
+        buf.put_u8(self.mtype as u8);
+        if self.mtype != MessageType::Abort {
+            buf.put_u16(self.token.len() as u16);
+            buf.put_slice(&self.token);
+        }
+    }
+
+    fn len(&self) -> usize {
+        if self.mtype == MessageType::Abort { 2 } else { 4 + self.token.len() }
+    }
+}
+
+impl AsyncStreamOperation for Message {
+    async fn retrieve_from_async_stream<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + ?Sized,
+    {
+        let ver = r.read_u8().await?;
+
+        if ver != super::SUBNEGOTIATION_VERSION {
+            return Err(Error::InvalidAuthSubnegotiation(ver));
+        }
+
+        let mtype = MessageType::try_from(r.read_u8().await?)?;
+        if mtype == MessageType::Abort {
+            return Ok(Self::abort());
+        }
+
+        let len = r.read_u16().await?;
+        let mut token = vec![0; len as usize];
+        r.read_exact(&mut token).await?;
+        Ok(Self { mtype, token })
+    }
+}
@@ -0,0 +1,139 @@
+//! GSS-API method, RFC 1961.
+//!
+//! The sub-negotiation runs in three stages, all framed as [`Message`]s:
+//!
+//! 1. context establishment, the client and the server exchange [`Authentication`](MessageType::Authentication)
+//!    tokens until the security context is established,
+//! 2. protection level negotiation, the client sends the level it requires and the server answers with the level
+//!    it selected, each as a single octet wrapped with integrity only,
+//! 3. per-message encapsulation, the rest of the SOCKS traffic is wrapped into
+//!    [`Encapsulation`](MessageType::Encapsulation) messages.
+//!
+//! The security mechanism itself, e.g. Kerberos V5, is provided by implementing [`Mechanism`].
+mod message;
+
+use error::{Error, Result};
+
+pub use self::message::{Message, MessageType};
+
+pub const SUBNEGOTIATION_VERSION: u8 = 0x01;
+
+/// Largest token `LEN` can hold.
+pub const MAX_TOKEN_LEN: usize = u16::MAX as usize;
+
+/// The protection applied to the encapsulated traffic.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ProtectionLevel {
+    /// Integrity protection only.
+    Integrity = 0x01,
+    /// Integrity and confidentiality protection.
+    Confidentiality = 0x02,
+    /// The protection is chosen for each message.
+    PerMessage = 0x03,
+}
+
+impl ProtectionLevel {
+    /// Whether encapsulated messages are encrypted, `PerMessage` defaults to encryption.
+    pub fn confidential(&self) -> bool {
+        *self != ProtectionLevel::Integrity
+    }
+}
+
+impl TryFrom<u8> for ProtectionLevel {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x01 => Ok(ProtectionLevel::Integrity),
+            0x02 => Ok(ProtectionLevel::Confidentiality),
+            0x03 => Ok(ProtectionLevel::PerMessage),
+            _ => Err(Error::from(format!("Invalid GSS-API protection level {0:#x}", value))),
+        }
+    }
+}
+
+/// Outcome of feeding a peer token to a [`SecurityContext`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    /// The context needs more tokens, the output token is sent to the peer.
+    Continue(Vec<u8>),
+    /// The context is established, with a last output token if the mechanism produced one.
+    Complete(Option<Vec<u8>>),
+}
+
+/// A GSS-API security mechanism, such as Kerberos V5.
+pub trait Mechanism {
+    type Context: SecurityContext;
+
+    /// Creates the acceptor side of a new security context.
+    fn accept(&self) -> Result<Self::Context>;
+}
+
+/// A security context, the mechanism specific state of one sub-negotiation.
+pub trait SecurityContext {
+    /// Processes a context establishment token from the peer, like `GSS_Accept_sec_context`.
+    fn step(&mut self, token: &[u8]) -> Result<Step>;
+
+    /// The authenticated name of the peer, available once the context is established.
+    fn source_name(&self) -> Result<String>;
+
+    /// Protects `data`, encrypting it if `confidential`, like `GSS_Wrap`.
+    fn wrap(&mut self, confidential: bool, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Verifies and decrypts a token produced by the peer's `wrap`, like `GSS_Unwrap`.
+    fn unwrap(&mut self, token: &[u8]) -> Result<Vec<u8>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamOperation;
+
+    struct Xor;
+
+    impl SecurityContext for Xor {
+        fn step(&mut self, token: &[u8]) -> Result<Step> {
+            Ok(Step::Complete(Some(token.to_vec())))
+        }
+
+        fn source_name(&self) -> Result<String> {
+            Ok("xor".to_owned())
+        }
+
+        fn wrap(&mut self, _confidential: bool, data: &[u8]) -> Result<Vec<u8>> {
+            Ok(data.iter().map(|b| b ^ 0x5a).collect())
+        }
+
+        fn unwrap(&mut self, token: &[u8]) -> Result<Vec<u8>> {
+            self.wrap(false, token)
+        }
+    }
+
+    #[test]
+    fn test_message_framing() {
+        let msg = Message::new(MessageType::Authentication, vec![1, 2, 3]).unwrap();
+        let mut buf = Vec::new();
+        msg.write_to_buf(&mut buf);
+        assert_eq!(buf, [0x01, 0x01, 0x00, 0x03, 1, 2, 3]);
+        assert_eq!(buf.len(), msg.len());
+        assert_eq!(Message::retrieve_from_stream(&mut buf.as_slice()).unwrap(), msg);
+
+        let mut buf = Vec::new();
+        Message::abort().write_to_buf(&mut buf);
+        assert_eq!(buf, [0x01, 0xff]);
+        assert_eq!(Message::retrieve_from_stream(&mut buf.as_slice()).unwrap(), Message::abort());
+
+        assert!(Message::new(MessageType::Encapsulation, vec![0; MAX_TOKEN_LEN + 1]).is_err());
+        assert!(Message::retrieve_from_stream(&mut [0x01, 0x04, 0x00, 0x00].as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let level = [ProtectionLevel::Confidentiality as u8];
+        let msg = Message::seal(MessageType::ProtectionLevel, &mut Xor, false, &level).unwrap();
+        assert_ne!(msg.token, level);
+        assert_eq!(msg.open(MessageType::ProtectionLevel, &mut Xor).unwrap(), level);
+        assert!(msg.open(MessageType::Encapsulation, &mut Xor).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use super::NONCE_LEN;
+use crate::AsyncStreamOperation;
+use crate::StreamOperation;
+use error::{Error, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// SOCKS5 HMAC handshake challenge, sent by the server
+///
+/// ```plain
+/// +-----+-------+
+/// | VER | NONCE |
+/// +-----+-------+
+/// |  1  |  16   |
+/// +-----+-------+
+/// ```
+#[derive(Clone, Debug)]
+pub struct Challenge {
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl Challenge {
+    pub fn new(nonce: [u8; NONCE_LEN]) -> Self {
+        Self { nonce }
+    }
+}
+
+impl StreamOperation for Challenge {
+    fn retrieve_from_stream<R: std::io::Read>(r: &mut R) -> Result<Self> {
+        let mut ver = [0; 1];
+        r.read_exact(&mut ver)?;
+        let ver = ver[0];
+
+        if ver != super::SUBNEGOTIATION_VERSION {
+            let err = format!("Unsupported sub-negotiation version {0:#x}", ver);
+            return Err(Error::from(std::io::Error::new(std::io::ErrorKind::Unsupported, err)));
+        }
+
+        let mut nonce = [0; NONCE_LEN];
+        r.read_exact(&mut nonce)?;
+        Ok(Self { nonce })
+    }
+
+    fn write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) {
+        buf.put_u8(super::SUBNEGOTIATION_VERSION);
+        buf.put_slice(&self.nonce);
+    }
+
+    fn len(&self) -> usize {
+        1 + NONCE_LEN
+    }
+}
+
+impl AsyncStreamOperation for Challenge {
+    async fn retrieve_from_async_stream<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + ?Sized,
+    {
+        let ver = r.read_u8().await?;
+
+        if ver != super::SUBNEGOTIATION_VERSION {
+            let err = format!("Unsupported sub-negotiation version {0:#x}", ver);
+            return Err(Error::from(std::io::Error::new(std::io::ErrorKind::Unsupported, err)));
+        }
+
+        let mut nonce = [0; NONCE_LEN];
+        r.read_exact(&mut nonce).await?;
+        Ok(Self { nonce })
+    }
+}
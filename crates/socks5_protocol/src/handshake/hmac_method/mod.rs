@@ -0,0 +1,71 @@
+//! Private HMAC token method.
+//!
+//! Instead of sending its password like RFC 1929, the client proves knowledge of it:
+//!
+//! 1. the server sends a [`Challenge`] with a random nonce,
+//! 2. the client answers with a [`Request`] carrying its username, the current unix time and
+//!    `HMAC-SHA256(password, nonce || timestamp || username)`,
+//! 3. the server replies with a [`Response`], the same message as for password authentication.
+mod challenge;
+mod request;
+
+use crate::AuthMethod;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub use self::{challenge::Challenge, request::Request};
+pub use super::password_method::{Response, Status};
+
+pub const SUBNEGOTIATION_VERSION: u8 = 0x01;
+
+/// The method code, taken from the private range.
+pub const AUTH_METHOD: AuthMethod = AuthMethod::Private(0x80);
+
+pub const NONCE_LEN: usize = 16;
+
+pub const MAC_LEN: usize = 32;
+
+/// Largest difference between the client timestamp and the server clock that is accepted.
+pub const MAX_TIMESTAMP_SKEW_SECS: u64 = 60;
+
+fn mac(secret: &[u8], nonce: &[u8; NONCE_LEN], timestamp: u64, username: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac.update(&timestamp.to_be_bytes());
+    mac.update(username.as_bytes());
+    mac
+}
+
+/// Signs a challenge, see the [module documentation](self).
+pub fn sign(secret: &[u8], nonce: &[u8; NONCE_LEN], timestamp: u64, username: &str) -> [u8; MAC_LEN] {
+    mac(secret, nonce, timestamp, username).finalize().into_bytes().into()
+}
+
+/// Checks the signature of a request in constant time.
+pub fn verify(secret: &[u8], nonce: &[u8; NONCE_LEN], req: &Request) -> bool {
+    mac(secret, nonce, req.timestamp, &req.username).verify_slice(&req.mac).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamOperation;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let nonce = [7u8; NONCE_LEN];
+        let req = Request::new("crawler", 1_700_000_000, sign(b"secret", &nonce, 1_700_000_000, "crawler"));
+
+        let mut buf = Vec::new();
+        req.write_to_buf(&mut buf);
+        assert_eq!(buf.len(), req.len());
+        let req = Request::retrieve_from_stream(&mut buf.as_slice()).unwrap();
+
+        assert!(verify(b"secret", &nonce, &req));
+        assert!(!verify(b"other", &nonce, &req));
+        assert!(!verify(b"secret", &[8u8; NONCE_LEN], &req));
+
+        let replayed = Request::new("crawler", req.timestamp + 1, req.mac);
+        assert!(!verify(b"secret", &nonce, &replayed));
+    }
+}
@@ -0,0 +1,102 @@
+use super::MAC_LEN;
+use crate::AsyncStreamOperation;
+use crate::StreamOperation;
+use error::{Error, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// SOCKS5 HMAC handshake request, the client's answer to a [`Challenge`](super::Challenge)
+///
+/// ```plain
+/// +-----+------+----------+-----------+-----+
+/// | VER | ULEN |  UNAME   | TIMESTAMP | MAC |
+/// +-----+------+----------+-----------+-----+
+/// |  1  |  1   | 1 to 255 |     8     | 32  |
+/// +-----+------+----------+-----------+-----+
+/// ```
+///
+/// `TIMESTAMP` is the client's unix time in seconds.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub username: String,
+    pub timestamp: u64,
+    pub mac: [u8; MAC_LEN],
+}
+
+impl Request {
+    pub fn new(username: &str, timestamp: u64, mac: [u8; MAC_LEN]) -> Self {
+        Self {
+            username: username.to_owned(),
+            timestamp,
+            mac,
+        }
+    }
+}
+
+impl StreamOperation for Request {
+    fn retrieve_from_stream<R: std::io::Read>(r: &mut R) -> Result<Self> {
+        let mut ver = [0; 1];
+        r.read_exact(&mut ver)?;
+        let ver = ver[0];
+
+        if ver != super::SUBNEGOTIATION_VERSION {
+            let err = format!("Unsupported sub-negotiation version {0:#x}", ver);
+            return Err(Error::from(std::io::Error::new(std::io::ErrorKind::Unsupported, err)));
+        }
+
+        let mut ulen = [0; 1];
+        r.read_exact(&mut ulen)?;
+        let mut username = vec![0; ulen[0] as usize];
+        r.read_exact(&mut username)?;
+        let username = String::from_utf8(username).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let mut timestamp = [0; 8];
+        r.read_exact(&mut timestamp)?;
+        let mut mac = [0; MAC_LEN];
+        r.read_exact(&mut mac)?;
+
+        Ok(Self {
+            username,
+            timestamp: u64::from_be_bytes(timestamp),
+            mac,
+        })
+    }
+
+    fn write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) {
+        buf.put_u8(super::SUBNEGOTIATION_VERSION);
+
+        let username = self.username.as_bytes();
+        buf.put_u8(username.len() as u8);
+        buf.put_slice(username);
+        buf.put_u64(self.timestamp);
+        buf.put_slice(&self.mac);
+    }
+
+    fn len(&self) -> usize {
+        2 + self.username.len() + 8 + MAC_LEN
+    }
+}
+
+impl AsyncStreamOperation for Request {
+    async fn retrieve_from_async_stream<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + ?Sized,
+    {
+        let ver = r.read_u8().await?;
+
+        if ver != super::SUBNEGOTIATION_VERSION {
+            let err = format!("Unsupported sub-negotiation version {0:#x}", ver);
+            return Err(Error::from(std::io::Error::new(std::io::ErrorKind::Unsupported, err)));
+        }
+
+        let ulen = r.read_u8().await?;
+        let mut username = vec![0; ulen as usize];
+        r.read_exact(&mut username).await?;
+        let username = String::from_utf8(username).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let timestamp = r.read_u64().await?;
+        let mut mac = [0; MAC_LEN];
+        r.read_exact(&mut mac).await?;
+
+        Ok(Self { username, timestamp, mac })
+    }
+}
@@ -1,4 +1,6 @@
 mod auth_method;
+pub mod gssapi_method;
+pub mod hmac_method;
 pub mod password_method;
 mod request;
 mod response;
@@ -23,7 +23,12 @@ impl Request {
     }
 
     pub fn evaluate_method(&self, server_method: AuthMethod) -> bool {
-        self.methods.iter().any(|&m| m == server_method)
+        self.methods.contains(&server_method)
+    }
+
+    /// Methods offered by the client, in the order it sent them.
+    pub fn methods(&self) -> &[AuthMethod] {
+        &self.methods
     }
 }
 
@@ -4,6 +4,7 @@ pub mod handshake;
 pub mod reply;
 pub mod request;
 pub mod response;
+pub mod socks4;
 pub mod udp;
 
 pub use self::{
@@ -16,7 +17,7 @@ pub use self::{
     reply::Reply,
     request::Request,
     response::Response,
-    udp::UdpHeader,
+    udp::{UdpHeader, UdpReassembler},
 };
 pub use bytes::BufMut;
 use error::Result;
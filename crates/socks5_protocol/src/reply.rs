@@ -1,3 +1,5 @@
+use error::FailureReason;
+
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Default)]
 pub enum Reply {
@@ -49,6 +51,21 @@ impl From<Reply> for u8 {
     }
 }
 
+impl From<FailureReason> for Reply {
+    fn from(reason: FailureReason) -> Self {
+        match reason {
+            FailureReason::DnsNotFound => Reply::HostUnreachable,
+            FailureReason::ConnectionRefused => Reply::ConnectionRefused,
+            FailureReason::Timeout => Reply::TtlExpired,
+            FailureReason::NetworkUnreachable => Reply::NetworkUnreachable,
+            FailureReason::AclDenied | FailureReason::AuthFailed | FailureReason::UserDisabled => {
+                Reply::ConnectionNotAllowed
+            }
+            FailureReason::Other => Reply::GeneralFailure,
+        }
+    }
+}
+
 impl std::fmt::Display for Reply {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
@@ -96,4 +113,15 @@ mod tests {
         assert_eq!(u8::from(Reply::CommandNotSupported), 0x07);
         assert_eq!(u8::from(Reply::AddressTypeNotSupported), 0x08);
     }
+
+    #[test]
+    fn reply_from_failure_reason() {
+        assert_eq!(Reply::from(FailureReason::DnsNotFound), Reply::HostUnreachable);
+        assert_eq!(Reply::from(FailureReason::ConnectionRefused), Reply::ConnectionRefused);
+        assert_eq!(Reply::from(FailureReason::Timeout), Reply::TtlExpired);
+        assert_eq!(Reply::from(FailureReason::NetworkUnreachable), Reply::NetworkUnreachable);
+        assert_eq!(Reply::from(FailureReason::AclDenied), Reply::ConnectionNotAllowed);
+        assert_eq!(Reply::from(FailureReason::UserDisabled), Reply::ConnectionNotAllowed);
+        assert_eq!(Reply::from(FailureReason::Other), Reply::GeneralFailure);
+    }
 }
@@ -0,0 +1,350 @@
+use crate::{Address, AsyncStreamOperation, StreamOperation, Version};
+use error::{Error, Result};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest USERID or SOCKS4a hostname accepted, without the terminating NUL.
+pub const MAX_FIELD_LEN: usize = 255;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Command {
+    Connect = 0x01,
+    Bind = 0x02,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = std::io::Error;
+
+    fn try_from(code: u8) -> std::result::Result<Self, Self::Error> {
+        let err = format!("Unsupported SOCKS4 command code {0:#x}", code);
+        match code {
+            0x01 => Ok(Command::Connect),
+            0x02 => Ok(Command::Bind),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err)),
+        }
+    }
+}
+
+impl From<Command> for u8 {
+    fn from(cmd: Command) -> Self {
+        match cmd {
+            Command::Connect => 0x01,
+            Command::Bind => 0x02,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Default)]
+pub enum Reply {
+    #[default]
+    Granted = 0x5a,
+    Rejected = 0x5b,
+    IdentdUnreachable = 0x5c,
+    IdentdMismatch = 0x5d,
+}
+
+impl TryFrom<u8> for Reply {
+    type Error = std::io::Error;
+
+    fn try_from(code: u8) -> std::result::Result<Self, Self::Error> {
+        let err = format!("Unsupported SOCKS4 reply code {0:#x}", code);
+        match code {
+            0x5a => Ok(Reply::Granted),
+            0x5b => Ok(Reply::Rejected),
+            0x5c => Ok(Reply::IdentdUnreachable),
+            0x5d => Ok(Reply::IdentdMismatch),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err)),
+        }
+    }
+}
+
+impl From<Reply> for u8 {
+    fn from(reply: Reply) -> Self {
+        reply as u8
+    }
+}
+
+/// SOCKS4 and SOCKS4a request
+///
+/// ```plain
+/// +----+----+---------+--------+----------+------+-------------+------+
+/// | VN | CD | DSTPORT | DSTIP  |  USERID  | NULL |  HOSTNAME   | NULL |
+/// +----+----+---------+--------+----------+------+-------------+------+
+/// | 1  | 1  |    2    |   4    | Variable |  1   | (Variable)  | (1)  |
+/// +----+----+---------+--------+----------+------+-------------+------+
+/// ```
+///
+/// SOCKS4a marks a hostname with a `DSTIP` of `0.0.0.x` (x non-zero), the hostname then follows the USERID.
+/// Addresses that do not fit into `DSTIP`, like IPv6 ones, are written as SOCKS4a hostnames.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub command: Command,
+    pub address: Address,
+    pub user_id: String,
+}
+
+impl Request {
+    pub fn new(command: Command, address: Address, user_id: String) -> Self {
+        Self { command, address, user_id }
+    }
+
+    fn hostname(&self) -> Option<String> {
+        match &self.address {
+            Address::SocketAddress(SocketAddr::V4(_)) => None,
+            Address::SocketAddress(SocketAddr::V6(addr)) => Some(addr.ip().to_string()),
+            Address::DomainAddress(domain, _) => Some(domain.clone()),
+        }
+    }
+}
+
+/// `DSTIP` is `0.0.0.x` with a non-zero `x`
+fn is_socks4a(ip: [u8; 4]) -> bool {
+    ip[..3] == [0, 0, 0] && ip[3] != 0
+}
+
+fn parse_field(field: Vec<u8>) -> Result<String> {
+    String::from_utf8(field).map_err(|e| Error::from(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+}
+
+fn read_field<R: std::io::Read>(stream: &mut R) -> Result<String> {
+    let mut field = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        stream.read_exact(&mut byte)?;
+        if byte[0] == 0 {
+            return parse_field(field);
+        }
+        if field.len() == MAX_FIELD_LEN {
+            return Err(Error::from(std::io::Error::new(std::io::ErrorKind::InvalidData, "SOCKS4 field too long")));
+        }
+        field.push(byte[0]);
+    }
+}
+
+async fn read_field_async<R: AsyncRead + Unpin + Send + ?Sized>(r: &mut R) -> Result<String> {
+    let mut field = Vec::new();
+    loop {
+        let byte = r.read_u8().await?;
+        if byte == 0 {
+            return parse_field(field);
+        }
+        if field.len() == MAX_FIELD_LEN {
+            return Err(Error::from(std::io::Error::new(std::io::ErrorKind::InvalidData, "SOCKS4 field too long")));
+        }
+        field.push(byte);
+    }
+}
+
+fn check_version(ver: u8) -> Result<()> {
+    let ver = Version::try_from(ver)?;
+    if ver != Version::V4 {
+        let err = format!("Unsupported SOCKS version {0:#x}", u8::from(ver));
+        return Err(Error::from(std::io::Error::new(std::io::ErrorKind::Unsupported, err)));
+    }
+    Ok(())
+}
+
+impl StreamOperation for Request {
+    fn retrieve_from_stream<R: std::io::Read>(stream: &mut R) -> Result<Self> {
+        let mut buf = [0u8; 8];
+        stream.read_exact(&mut buf)?;
+        check_version(buf[0])?;
+
+        let command = Command::try_from(buf[1])?;
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = [buf[4], buf[5], buf[6], buf[7]];
+        let user_id = read_field(stream)?;
+        let address = if is_socks4a(ip) {
+            Address::DomainAddress(read_field(stream)?, port)
+        } else {
+            Address::from((Ipv4Addr::from(ip), port))
+        };
+
+        Ok(Self { command, address, user_id })
+    }
+
+    fn write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) {
+        buf.put_u8(Version::V4.into());
+        buf.put_u8(u8::from(self.command));
+        buf.put_u16(self.address.port());
+        match &self.address {
+            Address::SocketAddress(SocketAddr::V4(addr)) => buf.put_slice(&addr.ip().octets()),
+            _ => buf.put_slice(&[0, 0, 0, 1]),
+        }
+        buf.put_slice(self.user_id.as_bytes());
+        buf.put_u8(0x00);
+        if let Some(hostname) = self.hostname() {
+            buf.put_slice(hostname.as_bytes());
+            buf.put_u8(0x00);
+        }
+    }
+
+    fn len(&self) -> usize {
+        let hostname_len = self.hostname().map(|h| h.len() + 1).unwrap_or_default();
+        8 + self.user_id.len() + 1 + hostname_len
+    }
+}
+
+impl AsyncStreamOperation for Request {
+    async fn retrieve_from_async_stream<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + ?Sized,
+    {
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf).await?;
+        check_version(buf[0])?;
+
+        let command = Command::try_from(buf[1])?;
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = [buf[4], buf[5], buf[6], buf[7]];
+        let user_id = read_field_async(r).await?;
+        let address = if is_socks4a(ip) {
+            Address::DomainAddress(read_field_async(r).await?, port)
+        } else {
+            Address::from((Ipv4Addr::from(ip), port))
+        };
+
+        Ok(Self { command, address, user_id })
+    }
+}
+
+/// SOCKS4 response
+///
+/// ```plain
+/// +----+----+---------+-------+
+/// | VN | CD | DSTPORT | DSTIP |
+/// +----+----+---------+-------+
+/// | 1  | 1  |    2    |   4   |
+/// +----+----+---------+-------+
+/// ```
+///
+/// `VN` is always 0.
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub reply: Reply,
+    pub address: SocketAddrV4,
+}
+
+impl Response {
+    pub fn new(reply: Reply, address: SocketAddrV4) -> Self {
+        Self { reply, address }
+    }
+
+    /// Builds a response for a bound address, IPv6 addresses that do not map to IPv4 are reported as `0.0.0.0`.
+    pub fn from_socket_addr(reply: Reply, address: SocketAddr) -> Self {
+        let address = match address {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(addr) => {
+                SocketAddrV4::new(addr.ip().to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED), addr.port())
+            }
+        };
+        Self { reply, address }
+    }
+
+    pub fn rejected() -> Self {
+        Self::new(Reply::Rejected, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+    }
+}
+
+impl StreamOperation for Response {
+    fn retrieve_from_stream<R: std::io::Read>(stream: &mut R) -> Result<Self> {
+        let mut buf = [0u8; 8];
+        stream.read_exact(&mut buf)?;
+        Self::parse(buf)
+    }
+
+    fn write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) {
+        buf.put_u8(0x00);
+        buf.put_u8(u8::from(self.reply));
+        buf.put_u16(self.address.port());
+        buf.put_slice(&self.address.ip().octets());
+    }
+
+    fn len(&self) -> usize {
+        8
+    }
+}
+
+impl AsyncStreamOperation for Response {
+    async fn retrieve_from_async_stream<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + ?Sized,
+    {
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf).await?;
+        Self::parse(buf)
+    }
+}
+
+impl Response {
+    fn parse(buf: [u8; 8]) -> Result<Self> {
+        if buf[0] != 0x00 {
+            let err = format!("Unsupported SOCKS4 reply version {0:#x}", buf[0]);
+            return Err(Error::from(std::io::Error::new(std::io::ErrorKind::Unsupported, err)));
+        }
+        let reply = Reply::try_from(buf[1])?;
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+        Ok(Self { reply, address: SocketAddrV4::new(ip, port) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(req: &Request) -> Request {
+        let mut buf = Vec::new();
+        req.write_to_buf(&mut buf);
+        assert_eq!(buf.len(), req.len());
+        Request::retrieve_from_stream(&mut buf.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_socks4_request() {
+        let raw = [4, 1, 0, 80, 93, 184, 216, 34, b'b', b'o', b'b', 0];
+        let req = Request::retrieve_from_stream(&mut &raw[..]).unwrap();
+        assert_eq!(req.command, Command::Connect);
+        assert_eq!(req.address, Address::from((Ipv4Addr::new(93, 184, 216, 34), 80)));
+        assert_eq!(req.user_id, "bob");
+        assert_eq!(round_trip(&req).address, req.address);
+    }
+
+    #[tokio::test]
+    async fn test_socks4a_request() {
+        let mut raw = vec![4, 1, 1, 187, 0, 0, 0, 1, 0];
+        raw.extend_from_slice(b"example.com\0");
+        let req = Request::retrieve_from_async_stream(&mut raw.as_slice()).await.unwrap();
+        assert_eq!(req.address, Address::DomainAddress("example.com".to_owned(), 443));
+        assert!(req.user_id.is_empty());
+
+        let ipv6 = Address::from(("::1".parse::<std::net::Ipv6Addr>().unwrap(), 443));
+        let req = round_trip(&Request::new(Command::Bind, ipv6, "alice".to_owned()));
+        assert_eq!(req.address, Address::DomainAddress("::1".to_owned(), 443));
+        assert_eq!(req.user_id, "alice");
+    }
+
+    #[test]
+    fn test_socks4_request_limits() {
+        let mut raw = vec![4, 1, 0, 80, 1, 2, 3, 4];
+        raw.extend(std::iter::repeat_n(b'a', MAX_FIELD_LEN + 1));
+        raw.push(0);
+        assert!(Request::retrieve_from_stream(&mut raw.as_slice()).is_err());
+
+        let raw = [5, 1, 0, 80, 1, 2, 3, 4, 0];
+        assert!(Request::retrieve_from_stream(&mut &raw[..]).is_err());
+    }
+
+    #[test]
+    fn test_socks4_response() {
+        let resp = Response::from_socket_addr(Reply::Granted, "10.0.0.1:1080".parse().unwrap());
+        let mut buf = Vec::new();
+        resp.write_to_buf(&mut buf);
+        assert_eq!(buf, [0, 0x5a, 0x04, 0x38, 10, 0, 0, 1]);
+
+        let resp = Response::retrieve_from_stream(&mut buf.as_slice()).unwrap();
+        assert_eq!(resp.reply, Reply::Granted);
+        assert_eq!(resp.address, "10.0.0.1:1080".parse().unwrap());
+    }
+}
@@ -1,8 +1,22 @@
 use crate::StreamOperation;
 use crate::{Address, AsyncStreamOperation};
-use error::Result;
+use bytes::{Bytes, BytesMut};
+use error::{Error, Result};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+/// The high-order bit of `FRAG` marks the last fragment of a sequence.
+pub const FRAG_END_OF_SEQUENCE: u8 = 0x80;
+
+/// Fragment positions are kept in the low 7 bits of `FRAG`, starting from 1.
+pub const MAX_FRAG_POSITION: u8 = 0x7f;
+
+/// Largest payload a single UDP datagram can carry, bounds the reassembly queue.
+pub const MAX_UDP_DATAGRAM_SIZE: usize = 65507;
+
+/// RFC 1928 asks for a reassembly timer of no less than 5 seconds.
+pub const UDP_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// SOCKS5 UDP packet header
 ///
 /// ```plain
@@ -64,3 +78,166 @@ impl AsyncStreamOperation for UdpHeader {
         Ok(Self { frag, address })
     }
 }
+
+/// Splits a payload into fragments of at most `max_payload` bytes, paired with their `FRAG` values.
+///
+/// A payload that fits into one datagram is returned as a single standalone datagram (`FRAG = 0`).
+pub fn split_fragments(data: &[u8], max_payload: usize) -> Result<Vec<(u8, &[u8])>> {
+    if max_payload == 0 {
+        return Err(Error::from("fragment payload size must not be zero"));
+    }
+    if data.len() <= max_payload {
+        return Ok(vec![(0, data)]);
+    }
+    let count = data.len().div_ceil(max_payload);
+    if count > MAX_FRAG_POSITION as usize {
+        return Err(Error::from(format!("datagram needs {} fragments, at most {} allowed", count, MAX_FRAG_POSITION)));
+    }
+    Ok(data
+        .chunks(max_payload)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let position = i as u8 + 1;
+            let frag = if i + 1 == count { position | FRAG_END_OF_SEQUENCE } else { position };
+            (frag, chunk)
+        })
+        .collect())
+}
+
+/// Reassembly queue for fragmented SOCKS5 UDP datagrams, as described in RFC 1928 section 7.
+///
+/// Fragments must arrive in order. The queue is reinitialized when the timer expires, when a fragment with a
+/// position not following the last one arrives, or when the destination changes. Sequences that grow past
+/// `max_size` bytes are dropped.
+#[derive(Debug)]
+pub struct UdpReassembler {
+    timeout: Duration,
+    max_size: usize,
+    pending: Option<PendingDatagram>,
+}
+
+#[derive(Debug)]
+struct PendingDatagram {
+    address: Address,
+    position: u8,
+    data: BytesMut,
+    started: Instant,
+}
+
+impl UdpReassembler {
+    pub fn new(timeout: Duration, max_size: usize) -> Self {
+        Self {
+            timeout,
+            max_size,
+            pending: None,
+        }
+    }
+
+    /// Feeds a received datagram into the queue.
+    ///
+    /// Returns the complete datagram once it is available, standalone datagrams are returned immediately.
+    pub fn push(&mut self, frag: u8, address: Address, data: Bytes) -> Option<(Address, Bytes)> {
+        if frag == 0 {
+            return Some((address, data));
+        }
+
+        if self.pending.as_ref().is_some_and(|p| p.started.elapsed() > self.timeout) {
+            self.pending = None;
+        }
+
+        let position = frag & MAX_FRAG_POSITION;
+        let is_next = self
+            .pending
+            .as_ref()
+            .is_some_and(|p| p.address == address && p.position.checked_add(1) == Some(position));
+        if !is_next {
+            self.pending = None;
+            if position != 1 {
+                return None;
+            }
+            self.pending = Some(PendingDatagram {
+                address,
+                position: 0,
+                data: BytesMut::new(),
+                started: Instant::now(),
+            });
+        }
+
+        let pending = self.pending.as_mut()?;
+        if pending.data.len() + data.len() > self.max_size {
+            self.pending = None;
+            return None;
+        }
+        pending.position = position;
+        pending.data.extend_from_slice(&data);
+
+        if frag & FRAG_END_OF_SEQUENCE != 0 {
+            let pending = self.pending.take()?;
+            return Some((pending.address, pending.data.freeze()));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_fragments() {
+        let data = [1u8; 10];
+        assert_eq!(split_fragments(&data, 10).unwrap(), vec![(0, &data[..])]);
+
+        let frags = split_fragments(&data, 4).unwrap();
+        assert_eq!(frags, vec![(1, &data[..4]), (2, &data[4..8]), (3 | FRAG_END_OF_SEQUENCE, &data[8..])]);
+
+        assert!(split_fragments(&[0u8; 128], 1).is_err());
+        assert!(split_fragments(&data, 0).is_err());
+    }
+
+    #[test]
+    fn test_reassemble() {
+        let addr = Address::from(("example.com".to_owned(), 53));
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut reassembler = UdpReassembler::new(Duration::from_secs(5), 1024);
+
+        let frags = split_fragments(&data, 30).unwrap();
+        let (last, frags) = frags.split_last().unwrap();
+        for (frag, chunk) in frags {
+            assert!(reassembler.push(*frag, addr.clone(), Bytes::copy_from_slice(chunk)).is_none());
+        }
+        let (a, d) = reassembler.push(last.0, addr.clone(), Bytes::copy_from_slice(last.1)).unwrap();
+        assert_eq!(a, addr);
+        assert_eq!(&d[..], &data[..]);
+
+        // standalone datagrams pass through
+        let (_, d) = reassembler.push(0, addr.clone(), Bytes::from_static(b"dns")).unwrap();
+        assert_eq!(&d[..], b"dns");
+    }
+
+    #[test]
+    fn test_reassemble_restart_and_limits() {
+        let addr = Address::from(("example.com".to_owned(), 53));
+        let mut reassembler = UdpReassembler::new(Duration::from_secs(5), 8);
+
+        // a gap drops the sequence
+        assert!(reassembler.push(1, addr.clone(), Bytes::from_static(b"ab")).is_none());
+        assert!(reassembler.push(3 | FRAG_END_OF_SEQUENCE, addr.clone(), Bytes::from_static(b"cd")).is_none());
+
+        // a lower position restarts the sequence
+        assert!(reassembler.push(1, addr.clone(), Bytes::from_static(b"ab")).is_none());
+        assert!(reassembler.push(1, addr.clone(), Bytes::from_static(b"xy")).is_none());
+        let (_, d) = reassembler.push(2 | FRAG_END_OF_SEQUENCE, addr.clone(), Bytes::from_static(b"z")).unwrap();
+        assert_eq!(&d[..], b"xyz");
+
+        // sequences larger than the queue are dropped
+        assert!(reassembler.push(1, addr.clone(), Bytes::from_static(b"12345")).is_none());
+        assert!(reassembler.push(2 | FRAG_END_OF_SEQUENCE, addr.clone(), Bytes::from_static(b"6789")).is_none());
+
+        // expired sequences are dropped
+        let mut reassembler = UdpReassembler::new(Duration::ZERO, 8);
+        assert!(reassembler.push(1, addr.clone(), Bytes::from_static(b"ab")).is_none());
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(reassembler.push(2 | FRAG_END_OF_SEQUENCE, addr, Bytes::from_static(b"cd")).is_none());
+    }
+}
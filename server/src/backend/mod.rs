@@ -1,8 +1,6 @@
 pub mod std_client;
 pub mod ws_client;
 
-use std::sync::atomic::AtomicBool;
-
 use async_channel::Receiver;
 use async_trait::async_trait;
 use rg_common::{Result, stat::StatData};
@@ -10,9 +8,7 @@ use rg_server_common::message::{ClientMessage, ServerIpInfo, ServerMessage};
 
 use crate::utils::get_config;
 
-/// backend status, if backend is running or not
-/// used for reconnect if backend is down
-pub static BACKEND_STATUS: AtomicBool = AtomicBool::new(false);
+pub use rg_common::backend::BACKEND_STATUS;
 
 #[async_trait]
 pub trait ClientBackend {
@@ -6,9 +6,9 @@ use futures::{
     stream::{SplitSink, SplitStream},
 };
 use tracing::{error, info};
+use error::Error;
 use rg_common::{
     Result, TrafficInfo,
-    error::RgError,
     stat::{StatData, StatType},
 };
 use rg_server_common::message::{ClientMessage, ServerMessage, UserTrafficInfo};
@@ -46,7 +46,7 @@ impl WebSocketClient {
 
         let (ws_stream, _) = connect_async(server_addr).await.map_err(|e| {
             error!("connect to server error: {}", e);
-            RgError::ConnectServerError
+            Error::ConnectServerError
         })?;
 
         let (sender, receiver) = ws_stream.split();
@@ -79,7 +79,7 @@ impl ClientBackend for WebSocketClient {
         self.sender
             .send(data)
             .await
-            .map_err(|_| RgError::WebsocketSendError)?;
+            .map_err(|_| Error::WebsocketSendError)?;
         Ok(())
     }
 
@@ -87,11 +87,11 @@ impl ClientBackend for WebSocketClient {
         self.sender
             .send(Message::Text(serde_json::to_string(&msg)?))
             .await
-            .map_err(|_| RgError::WebsocketSendError)?;
+            .map_err(|_| Error::WebsocketSendError)?;
         self.sender
             .flush()
             .await
-            .map_err(|_| RgError::WebsocketSendError)?;
+            .map_err(|_| Error::WebsocketSendError)?;
         Ok(())
     }
 
@@ -3,34 +3,24 @@ mod server_client;
 mod emit_client;
 mod backend;
 
-use {
-    socks5_protocol::{Address, Reply, UdpHeader},
-    rg_proxy::socks5_server::{auth, connection::associate, AssociatedUdpSocket, ClientConnection, IncomingConnection, UdpAssociate},
-    error::{Error, Result},
-};
-use std::{
-    net::{SocketAddr, ToSocketAddrs},
-    sync::{atomic::AtomicBool, Arc},
-};
+use config::ProxyConfig;
+use error::Result;
+use std::sync::Arc;
 use std::process::exit;
-use tokio::{
-    io,
-    net::{TcpStream, UdpSocket},
-    sync::Mutex,
-};
 use rg_acl::{acl::DefaultAclRule, auth::dc_auth::DcAuthenticator};
-use as_any::AsAny;
 use tokio::sync::RwLock;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 use rg_common::stat::StatType;
 use crate::utils::get_local_ip_port;
 use tracing::{info, error};
-use rg_proxy::backend::{CommonBackend, ServerBackend};
-use rg_proxy::backend::dc_server::{init, DcServerBackend, DC_SERVER_BACKEND};
+use rg_proxy::backend::ServerBackend;
+use rg_proxy::backend::dc_server::{init, DC_SERVER_BACKEND};
 use strum::IntoEnumIterator;
 use rg_proxy::proxy_server::ProxyServer;
+use rg_proxy::tls::TlsTerminator;
 use rg_proxy::Server;
+use std::time::Duration;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
@@ -59,13 +49,19 @@ async fn main() -> Result<()> {
         let t = stat_manager.subscribe(stat_type);
         client.add_subscribe(t).await;
     }
-    init(stat_sender).await;
-    // create proxy server
-    // let dc_backend = DcServerBackend::new(CommonBackend::new(
-    //     auth_center.clone(),
-    //     acl_center.clone(),
-    //     stat_sender,
-    // ));
+    // create proxy server, sharing the auth and acl centers updated by the client
+    let proxy_config = ProxyConfig::load();
+    let tls = if proxy_config.tls.ports.is_empty() {
+        None
+    } else {
+        let tls = Arc::new(TlsTerminator::load(&proxy_config.tls)?);
+        if proxy_config.tls.reload_interval_secs > 0 {
+            tls.watch(Duration::from_secs(proxy_config.tls.reload_interval_secs));
+        }
+        Some(tls)
+    };
+    let tls_ports = proxy_config.tls.ports.clone();
+    init(stat_sender, auth_center.clone(), acl_center.clone(), proxy_config).await;
 
     let kill_user_sender = DC_SERVER_BACKEND.init_kill_user_connection().await;
     // start listening
@@ -80,7 +76,13 @@ async fn main() -> Result<()> {
                 continue;
             }
         };
-        servers.push(ProxyServer::new(listener, DC_SERVER_BACKEND.clone()).await);
+        let serve_tls = listener.local_addr().is_ok_and(|addr| tls_ports.contains(&addr.port()));
+        let mut server = ProxyServer::new(listener, DC_SERVER_BACKEND.clone()).await;
+        if let Some(tls) = tls.as_ref().filter(|_| serve_tls) {
+            info!("serve tls on {}", ip);
+            server = server.with_tls(tls.clone());
+        }
+        servers.push(server);
     }
 
     info!("start stat manager");
@@ -112,10 +112,8 @@ impl ServerClient {
                         }
                     }
                     stat = self.stat_reciever.recv() => {
-                        if let Ok(stat) = stat {
-                            if let Err(e) = self.backend.emit_stat(stat).await {
-                                error!("emit stat error: {}", e);
-                            }
+                        if let Ok(stat) = stat && let Err(e) = self.backend.emit_stat(stat).await {
+                            error!("emit stat error: {}", e);
                         }
                     }
                 }
@@ -1,11 +1,11 @@
 use anyhow::anyhow;
 use args::ENV_ARG;
 use regex::Regex;
-use rg_common::{Result, error::RgError};
+use error::Error;
+use rg_common::Result;
 use std::fmt::Display;
 use std::sync::LazyLock;
 use tokio::sync::OnceCell;
-use error::Error;
 
 pub static LOCAL_IP_CONFIG_FILE: LazyLock<&str> = LazyLock::new(|| match ENV_ARG.as_str() {
     "dev" => "/etc/dev_gre_tunnel_config",
@@ -25,7 +25,9 @@ pub struct Config {
     pub offset: u32,
     pub ip_range: Vec<IpRange>,
     pub extra_ips: Vec<String>,
+    #[allow(unused)]
     pub server_start: Option<String>,
+    #[allow(unused)]
     pub server_end: Option<String>,
 }
 
@@ -87,15 +89,14 @@ pub async fn get_local_ip_port() -> &'static [String] {
             let port_range = config.port_end.abs_diff(config.port_start) + 1;
             println!("port range: {}", port_range);
             let mut ind = 0;
-            let ips = ips
+            ips
                 .iter()
                 .map(|x| {
                     let ip = format!("{}:{}", x, config.port_start + ind);
                     ind = (ind + 1) % port_range;
                     ip
                 })
-                .collect::<Vec<_>>();
-            ips
+                .collect::<Vec<_>>()
         })
         .await
 }
@@ -161,7 +162,7 @@ pub fn get_field_ip(data: &str, field: &str) -> Result<String> {
             .map(|c| c.as_str().to_string())
             .ok_or(anyhow!(format!("No field {} ip found", field)).into())
     } else {
-        Err(RgError::from(anyhow!(format!(
+        Err(Error::from(anyhow!(format!(
             "No field {} ip found",
             field
         ))))